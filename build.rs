@@ -1,3 +1,4 @@
+#[cfg(feature = "esp")]
 #[toml_cfg::toml_config]
 pub struct Config {
     #[default("NOT SET")]
@@ -6,19 +7,48 @@ pub struct Config {
     wifi_psk: &'static str,
 }
 
+/// Web UI files, served gzipped from the firmware
+const WEB_ASSETS: &[&str] = &["index.html"];
+
 fn main() {
     println!("cargo:rerun-if-changed=cfg.toml");
 
-    // Check if the `cfg.toml` file exists and has been filled out.
-    if !std::path::Path::new("cfg.toml").exists() {
-        panic!("You need to create a `cfg.toml` file with your Wi-Fi credentials! Use `cfg.toml.example` as a template.");
-    }
+    compress_web_assets();
+
+    // Host builds do not connect to the Wi-Fi, nor link against ESP-IDF
+    #[cfg(feature = "esp")]
+    {
+        // Check if the `cfg.toml` file exists and has been filled out.
+        if !std::path::Path::new("cfg.toml").exists() {
+            panic!("You need to create a `cfg.toml` file with your Wi-Fi credentials! Use `cfg.toml.example` as a template.");
+        }
+
+        // The constant `CONFIG` is auto-generated by `toml_config`.
+        let app_config = CONFIG;
+        if app_config.wifi_ssid == "NOT SET" || app_config.wifi_psk == "NOT SET" {
+            panic!("You need to set the Wi-Fi credentials in `cfg.toml`!");
+        }
 
-    // The constant `CONFIG` is auto-generated by `toml_config`.
-    let app_config = CONFIG;
-    if app_config.wifi_ssid == "NOT SET" || app_config.wifi_psk == "NOT SET" {
-        panic!("You need to set the Wi-Fi credentials in `cfg.toml`!");
+        embuild::espidf::sysenv::output();
     }
+}
+
+/// Gzips the `web/` files into OUT_DIR, the firmware includes them as they are
+fn compress_web_assets() {
+    use std::io::Write;
 
-    embuild::espidf::sysenv::output();
+    let out_dir = std::path::PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    for asset in WEB_ASSETS {
+        let path = format!("web/{asset}");
+        println!("cargo:rerun-if-changed={path}");
+
+        let content = std::fs::read(&path).unwrap_or_else(|e| panic!("Cannot read {path}: {e}"));
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+        encoder.write_all(&content).unwrap();
+        std::fs::write(
+            out_dir.join(format!("{asset}.gz")),
+            encoder.finish().unwrap(),
+        )
+        .unwrap();
+    }
 }
@@ -7,3 +7,7 @@ cargo fmt --all
 # echo "Audit"
 # cargo audit
 
+
+echo "Host tests"
+cargo +stable host-clippy
+cargo +stable host-test
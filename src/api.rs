@@ -0,0 +1,851 @@
+//! HTTP API of the controller, agnostic to the HTTP server serving it
+
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt::Display,
+    sync::mpsc::{channel, Receiver, Sender},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context};
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime, TimeDelta};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    auth::Auth,
+    clock::{ClockError, ClockServiceChannel, ClockServiceMessage, ClockStatus, DateTimeSetting},
+    events::{self, Event, EventsServiceChannel},
+    history::HistoryQuery,
+    schedule::ScheduleRule,
+    sections::{SectionDuration, SectionId, SectionsConfig, SectionsError},
+    watering::{
+        Program, WateringError, WateringServiceChannel, WateringServiceMessage, WateringStatus,
+    },
+};
+
+// Max payload length, enough for a pretty printed program with a dozen or so sections and their rules
+pub const MAX_LEN: usize = 4096;
+/// How long a handler waits for the service to answer
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Server-Sent Events stream, not in the ROUTES - the server keeps the connection open
+/// and writes the events to it as they come, see `Api::subscribe_events`
+pub const EVENTS_PATH: &str = "/events";
+/// Written as is to the socket, the body follows until the client goes away
+pub const EVENTS_RESPONSE_HEAD: &str = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n";
+/// SSE comment sent when nothing happens for EVENTS_KEEP_ALIVE_PERIOD,
+/// keeps the client from giving up and lets the server notice the client is gone
+pub const EVENTS_KEEP_ALIVE: &str = ": keep-alive\n\n";
+pub const EVENTS_KEEP_ALIVE_PERIOD: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Method {
+    Get,
+    Post,
+    Put,
+}
+
+/// Single page UI, gzipped by the build script
+const INDEX_HTML_GZ: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/index.html.gz"));
+
+/// Everything a request can fail with, each one has its HTTP status and the code in the error envelope:
+/// `{"ok":false,"error":{"code":"SECTION_BUSY","message":"..."}}`
+#[derive(Debug, Clone, PartialEq)]
+pub enum ApiError {
+    /// Body or query string is not what the endpoint expects
+    BadRequest(String),
+    RequestTooBig,
+    Unauthorized,
+    /// There is no such endpoint
+    NotFound(String),
+    MethodNotAllowed,
+    Sections(SectionsError),
+    Watering(WateringError),
+    Clock(ClockError),
+    /// Service did not take the request or did not answer it in time, trying again later might help
+    Unavailable(String),
+    Internal(String),
+}
+
+impl ApiError {
+    pub fn status(&self) -> u16 {
+        match self {
+            ApiError::BadRequest(_) | ApiError::RequestTooBig => 400,
+            ApiError::Unauthorized => 401,
+            ApiError::NotFound(_) | ApiError::Sections(_) => 404,
+            ApiError::MethodNotAllowed => 405,
+            ApiError::Watering(
+                WateringError::SectionBusy(_) | WateringError::ProgramOverlaps(_),
+            ) => 409,
+            ApiError::Watering(WateringError::Sections(_) | WateringError::UnknownProgram(_)) => {
+                404
+            }
+            ApiError::Clock(_) | ApiError::Unavailable(_) => 503,
+            ApiError::Internal(_) => 500,
+        }
+    }
+
+    /// Machine readable, stays the same when the message changes
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) => "BAD_REQUEST",
+            ApiError::RequestTooBig => "REQUEST_TOO_BIG",
+            ApiError::Unauthorized => "UNAUTHORIZED",
+            ApiError::NotFound(_) => "NOT_FOUND",
+            ApiError::MethodNotAllowed => "METHOD_NOT_ALLOWED",
+            ApiError::Sections(err) => err.code(),
+            ApiError::Watering(err) => err.code(),
+            ApiError::Clock(err) => err.code(),
+            ApiError::Unavailable(_) => "SERVICE_UNAVAILABLE",
+            ApiError::Internal(_) => "INTERNAL_ERROR",
+        }
+    }
+}
+
+impl Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiError::BadRequest(reason)
+            | ApiError::NotFound(reason)
+            | ApiError::Unavailable(reason)
+            | ApiError::Internal(reason) => f.write_str(reason),
+            ApiError::RequestTooBig => write!(f, "Request is bigger than {MAX_LEN} bytes"),
+            ApiError::Unauthorized => f.write_str("Token is missing or invalid"),
+            ApiError::MethodNotAllowed => f.write_str("Method not allowed"),
+            ApiError::Sections(err) => err.fmt(f),
+            ApiError::Watering(err) => err.fmt(f),
+            ApiError::Clock(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl From<SectionsError> for ApiError {
+    fn from(err: SectionsError) -> Self {
+        ApiError::Sections(err)
+    }
+}
+
+impl From<WateringError> for ApiError {
+    fn from(err: WateringError) -> Self {
+        ApiError::Watering(err)
+    }
+}
+
+impl From<ClockError> for ApiError {
+    fn from(err: ClockError) -> Self {
+        ApiError::Clock(err)
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorEnvelope<'a> {
+    ok: bool,
+    error: ErrorBody<'a>,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    code: &'a str,
+    message: String,
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    /// On top of the ones the server sets on its own
+    pub headers: Vec<(&'static str, &'static str)>,
+    pub body: Cow<'static, [u8]>,
+}
+
+impl Response {
+    pub fn json(body: &impl Serialize) -> Result<Self, ApiError> {
+        let body = serde_json::to_vec_pretty(body)
+            .map_err(|err| ApiError::Internal(format!("Cannot serialize the response: {err}")))?;
+        Ok(Self {
+            status: 200,
+            headers: vec![("Content-Type", "application/json")],
+            body: Cow::Owned(body),
+        })
+    }
+
+    /// Request got carried out, there is nothing more to say
+    pub fn done() -> Self {
+        Self {
+            status: 200,
+            headers: vec![("Content-Type", "application/json")],
+            body: Cow::Borrowed(b"{\"ok\":true}"),
+        }
+    }
+
+    /// Static file compressed at the build time
+    pub fn gzipped(content_type: &'static str, body: &'static [u8]) -> Self {
+        Self {
+            status: 200,
+            headers: vec![("Content-Type", content_type), ("Content-Encoding", "gzip")],
+            body: Cow::Borrowed(body),
+        }
+    }
+}
+
+impl From<ApiError> for Response {
+    fn from(err: ApiError) -> Self {
+        let envelope = ErrorEnvelope {
+            ok: false,
+            error: ErrorBody {
+                code: err.code(),
+                message: err.to_string(),
+            },
+        };
+
+        let mut headers = vec![("Content-Type", "application/json")];
+        if err == ApiError::Unauthorized {
+            // Browser asks for the user name and password, the token goes as the password
+            headers.push(("WWW-Authenticate", "Basic realm=\"water my garden\""));
+        }
+
+        Self {
+            status: err.status(),
+            headers,
+            body: Cow::Owned(serde_json::to_vec(&envelope).expect("Envelope is always valid JSON")),
+        }
+    }
+}
+
+/// Parts of the HTTP request the handlers need
+pub struct Request<'a> {
+    /// Query string, without the leading `?`
+    pub query: &'a str,
+    pub body: &'a [u8],
+}
+
+pub type Handler = fn(&Api, &Request) -> Result<Response, ApiError>;
+
+/// All the endpoints, server registers a handler for each of them
+pub const ROUTES: &[(Method, &str, Handler)] = &[
+    (Method::Get, "/", Api::index),
+    (Method::Get, "/status", Api::status),
+    (Method::Get, "/sections", Api::sections),
+    (Method::Get, "/history", Api::history),
+    (Method::Post, "/start_watering_at", Api::start_watering_at),
+    (Method::Post, "/disable_watering", Api::disable_watering),
+    (Method::Post, "/rain_delay", Api::rain_delay),
+    (
+        Method::Post,
+        "/set_section_duration",
+        Api::set_section_duration,
+    ),
+    (Method::Post, "/close_all_valves", Api::close_all_valves),
+    (Method::Post, "/enable_section_for", Api::enable_section_for),
+    (Method::Post, "/set_program", Api::set_program),
+    (Method::Post, "/remove_program", Api::remove_program),
+    (Method::Put, "/clock", Api::set_clock),
+];
+
+#[derive(Deserialize)]
+struct StartWateringAtReq {
+    time: NaiveTime,
+}
+
+#[derive(Deserialize)]
+struct RainDelayReq {
+    /// 0 lifts the delay
+    days: u16,
+}
+
+#[derive(Deserialize)]
+struct SetSectionDurationReq {
+    section: SectionId,
+    duration: SectionDuration,
+}
+
+#[derive(Deserialize)]
+struct EnableSectionForReq {
+    section: SectionId,
+    duration: SectionDuration,
+}
+
+#[derive(Deserialize)]
+struct SetProgramReq {
+    name: String,
+    time: NaiveTime,
+    sections: HashMap<SectionId, SectionDuration>,
+    #[serde(default)]
+    rule: ScheduleRule,
+    #[serde(default)]
+    section_rules: HashMap<SectionId, ScheduleRule>,
+}
+
+#[derive(Deserialize)]
+struct RemoveProgramReq {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct SetClockReq {
+    /// ISO-8601, local time if there is no offset
+    datetime: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SystemStatus {
+    watering: WateringStatus,
+    clock: ClockStatus,
+    last_reset_cause: String,
+}
+
+/// Translates requests into the messages to the services
+#[derive(Clone)]
+pub struct Api {
+    clock_tx: ClockServiceChannel,
+    watering_tx: WateringServiceChannel,
+    events_tx: EventsServiceChannel,
+    sections: SectionsConfig,
+    last_reset_cause: String,
+    auth: Auth,
+}
+
+impl Api {
+    /// `last_reset_cause` is reported in the status, e.g. the panic that caused the reset
+    pub fn new(
+        clock_tx: ClockServiceChannel,
+        watering_tx: WateringServiceChannel,
+        events_tx: EventsServiceChannel,
+        sections: SectionsConfig,
+        last_reset_cause: String,
+    ) -> Self {
+        Self {
+            clock_tx,
+            watering_tx,
+            events_tx,
+            sections,
+            last_reset_cause,
+            auth: Auth::disabled(),
+        }
+    }
+
+    pub fn with_auth(mut self, auth: Auth) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    /// `authorization` is the value of the header
+    pub fn authorize(&self, method: Method, authorization: Option<&str>) -> Result<(), ApiError> {
+        if self.auth.is_required(method == Method::Get) && !self.auth.is_authorized(authorization) {
+            Err(ApiError::Unauthorized)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Dispatches the request to the handler registered for given method and path,
+    /// `uri` is the path with an optional query string, `authorization` is the value of the header
+    pub fn handle(
+        &self,
+        method: Method,
+        uri: &str,
+        authorization: Option<&str>,
+        body: &[u8],
+    ) -> Response {
+        let (path, query) = uri.split_once('?').unwrap_or((uri, ""));
+        // Unknown route is told apart before the token is checked
+        let response = match ROUTES
+            .iter()
+            .find(|(route_method, route_path, _)| *route_method == method && *route_path == path)
+        {
+            Some((_, _, handler)) => self
+                .authorize(method, authorization)
+                .and_then(|()| handler(self, &Request { query, body })),
+            None => Err(no_route(method, path)),
+        };
+
+        response.unwrap_or_else(Response::from)
+    }
+
+    /// Events for the `/events` stream, None if the events service is gone
+    pub fn subscribe_events(&self) -> Option<Receiver<Event>> {
+        events::subscribe(&self.events_tx)
+    }
+
+    fn index(&self, _req: &Request) -> Result<Response, ApiError> {
+        Ok(Response::gzipped("text/html; charset=utf-8", INDEX_HTML_GZ))
+    }
+
+    fn status(&self, _req: &Request) -> Result<Response, ApiError> {
+        Response::json(&self.get_system_status()?)
+    }
+
+    fn sections(&self, _req: &Request) -> Result<Response, ApiError> {
+        Response::json(&self.sections)
+    }
+
+    fn history(&self, req: &Request) -> Result<Response, ApiError> {
+        let query = parse_history_query(req.query)
+            .map_err(|err| ApiError::BadRequest(format!("{err:#}")))?;
+        self.check_sections(query.section.iter())?;
+
+        let history = ask(&self.watering_tx, WATERING, |tx| {
+            WateringServiceMessage::GetHistory(query, tx)
+        })?;
+        Response::json(&history)
+    }
+
+    /// Fails if any of the sections is not configured
+    fn check_sections<'a>(
+        &self,
+        mut sections: impl Iterator<Item = &'a SectionId>,
+    ) -> Result<(), SectionsError> {
+        sections.try_for_each(|section| self.sections.check(section))
+    }
+
+    fn get_system_status(&self) -> Result<SystemStatus, ApiError> {
+        let watering_status = ask(
+            &self.watering_tx,
+            WATERING,
+            WateringServiceMessage::GetStatus,
+        )?;
+        let clock_status = ask(&self.clock_tx, CLOCK, ClockServiceMessage::GetStatus)?;
+
+        Ok(SystemStatus {
+            watering: watering_status,
+            clock: clock_status,
+            last_reset_cause: self.last_reset_cause.clone(),
+        })
+    }
+
+    fn start_watering_at(&self, req: &Request) -> Result<Response, ApiError> {
+        let body = get_body::<StartWateringAtReq>(req.body)?;
+        ask(&self.watering_tx, WATERING, |tx| {
+            WateringServiceMessage::StartWateringAt(body.time, tx)
+        })??;
+        Ok(Response::done())
+    }
+
+    fn set_section_duration(&self, req: &Request) -> Result<Response, ApiError> {
+        let body = get_body::<SetSectionDurationReq>(req.body)?;
+        self.sections.check(&body.section)?;
+
+        ask(&self.watering_tx, WATERING, |tx| {
+            WateringServiceMessage::SetSectionDuration(body.section, body.duration, tx)
+        })??;
+        Ok(Response::done())
+    }
+
+    fn disable_watering(&self, _req: &Request) -> Result<Response, ApiError> {
+        tell(
+            &self.watering_tx,
+            WATERING,
+            WateringServiceMessage::DisableWatering,
+        )?;
+        Ok(Response::done())
+    }
+
+    fn rain_delay(&self, req: &Request) -> Result<Response, ApiError> {
+        let body = get_body::<RainDelayReq>(req.body)?;
+        tell(
+            &self.watering_tx,
+            WATERING,
+            WateringServiceMessage::SetRainDelay(TimeDelta::days(body.days.into())),
+        )?;
+        Ok(Response::done())
+    }
+
+    fn close_all_valves(&self, _req: &Request) -> Result<Response, ApiError> {
+        tell(
+            &self.watering_tx,
+            WATERING,
+            WateringServiceMessage::CloseAllValves,
+        )?;
+        Ok(Response::done())
+    }
+
+    fn enable_section_for(&self, req: &Request) -> Result<Response, ApiError> {
+        let body = get_body::<EnableSectionForReq>(req.body)?;
+        self.sections.check(&body.section)?;
+
+        ask(&self.watering_tx, WATERING, |tx| {
+            WateringServiceMessage::EnableSectionFor(body.section, body.duration, tx)
+        })??;
+        Ok(Response::done())
+    }
+
+    fn set_program(&self, req: &Request) -> Result<Response, ApiError> {
+        let body = get_body::<SetProgramReq>(req.body)?;
+        if body.name.is_empty() {
+            return Err(ApiError::BadRequest("Program name is empty".to_string()));
+        }
+        self.check_sections(body.sections.keys().chain(body.section_rules.keys()))?;
+
+        ask(&self.watering_tx, WATERING, |tx| {
+            WateringServiceMessage::SetProgram(
+                body.name,
+                Program {
+                    start_at: Some(body.time),
+                    section_durations: body.sections,
+                    rule: body.rule,
+                    section_rules: body.section_rules,
+                },
+                tx,
+            )
+        })??;
+        Ok(Response::done())
+    }
+
+    fn remove_program(&self, req: &Request) -> Result<Response, ApiError> {
+        let body = get_body::<RemoveProgramReq>(req.body)?;
+
+        ask(&self.watering_tx, WATERING, |tx| {
+            WateringServiceMessage::RemoveProgram(body.name, tx)
+        })??;
+        Ok(Response::done())
+    }
+
+    fn set_clock(&self, req: &Request) -> Result<Response, ApiError> {
+        let body = get_body::<SetClockReq>(req.body)?;
+        let setting = body
+            .datetime
+            .parse::<DateTimeSetting>()
+            .map_err(|err| ApiError::BadRequest(format!("{err:#}")))?;
+
+        ask(&self.clock_tx, CLOCK, |tx| {
+            ClockServiceMessage::SetDateTime(setting, tx)
+        })??;
+        Ok(Response::done())
+    }
+}
+
+/// Known path asked with another method is told apart from the unknown one
+fn no_route(method: Method, path: &str) -> ApiError {
+    let known = path == EVENTS_PATH || ROUTES.iter().any(|(_, route_path, _)| *route_path == path);
+    match known {
+        true => ApiError::MethodNotAllowed,
+        false => ApiError::NotFound(format!("No route for {method:?} {path}")),
+    }
+}
+
+/// Names of the services in the errors
+const WATERING: &str = "Watering";
+const CLOCK: &str = "Clock";
+
+/// Sends the message that does not need the answer
+fn tell<M>(service_tx: &Sender<M>, service: &str, msg: M) -> Result<(), ApiError> {
+    service_tx
+        .send(msg)
+        .map_err(|_| ApiError::Unavailable(format!("{service} service is not running")))
+}
+
+/// Sends the message carrying the reply channel, and waits for the reply
+fn ask<M, T>(
+    service_tx: &Sender<M>,
+    service: &str,
+    msg: impl FnOnce(Sender<T>) -> M,
+) -> Result<T, ApiError> {
+    let (tx, rx) = channel();
+    tell(service_tx, service, msg(tx))?;
+    rx.recv_timeout(REPLY_TIMEOUT)
+        .map_err(|_| ApiError::Unavailable(format!("{service} service does not respond")))
+}
+
+/// `since` is the local date and time, or the date alone for the whole day
+fn parse_history_query(query: &str) -> anyhow::Result<HistoryQuery> {
+    let mut history_query = HistoryQuery::default();
+
+    for (key, value) in query_params(query)? {
+        match key.as_str() {
+            "since" => {
+                let since = value
+                    .parse::<NaiveDateTime>()
+                    .or_else(|_| {
+                        value
+                            .parse::<NaiveDate>()
+                            .map(|date| date.and_time(NaiveTime::MIN))
+                    })
+                    .with_context(|| format!("{value:?} is not ISO-8601 date and time"))?;
+                history_query.since = Some(since);
+            }
+            "section" => history_query.section = Some(SectionId::from(value.as_str())),
+            _ => return Err(anyhow!("Unknown query parameter {key}")),
+        }
+    }
+
+    Ok(history_query)
+}
+
+/// Decoded `key=value` pairs of the query string
+fn query_params(query: &str) -> anyhow::Result<Vec<(String, String)>> {
+    query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            Ok((percent_decode(key)?, percent_decode(value)?))
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> anyhow::Result<String> {
+    let mut bytes = s.bytes();
+    let mut decoded = vec![];
+
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let hex = [bytes.next(), bytes.next()];
+                let hex = hex
+                    .iter()
+                    .flatten()
+                    .map(|digit| *digit as char)
+                    .collect::<String>();
+                let byte = u8::from_str_radix(&hex, 16)
+                    .ok()
+                    .filter(|_| hex.len() == 2)
+                    .with_context(|| format!("Invalid escape %{hex} in {s:?}"))?;
+                decoded.push(byte);
+            }
+            byte => decoded.push(byte),
+        }
+    }
+
+    String::from_utf8(decoded).with_context(|| format!("{s:?} is not UTF-8"))
+}
+
+fn get_body<T: DeserializeOwned>(body: &[u8]) -> Result<T, ApiError> {
+    if body.len() > MAX_LEN {
+        return Err(ApiError::RequestTooBig);
+    }
+    serde_json::from_slice(body).map_err(|err| ApiError::BadRequest(err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Api with nobody listening on the other side of the channels
+    fn api() -> Api {
+        Api::new(
+            std::sync::mpsc::channel().0,
+            std::sync::mpsc::channel().0,
+            std::sync::mpsc::channel().0,
+            SectionsConfig::from_json(include_str!("../sections.json")).unwrap(),
+            "PowerOn".to_string(),
+        )
+    }
+
+    #[test]
+    fn ui_is_served_gzipped() {
+        let response = api().handle(Method::Get, "/", None, &[]);
+        assert_eq!(response.status, 200);
+        assert!(response.headers.contains(&("Content-Encoding", "gzip")));
+        // Gzip magic number
+        assert_eq!(response.body[..2], [0x1f, 0x8b]);
+    }
+
+    #[test]
+    fn changes_need_the_token() {
+        // `echo -n garden | sha256sum`
+        let auth =
+            Auth::new("23eeb69c681dfdb8eacc7ce9e55ea007d41c2dd5273848c597f1b3e49dbd86e1").unwrap();
+        let api = api().with_auth(auth.clone());
+
+        let response = api.handle(Method::Post, "/remove_program", None, b"{}");
+        assert_eq!(response.status, 401);
+        let response = api.handle(Method::Post, "/remove_program", Some("Bearer weeds"), b"{}");
+        assert_eq!(response.status, 401);
+        // Token lets the request through, to the validation of the body
+        let response = api.handle(
+            Method::Post,
+            "/remove_program",
+            Some("Bearer garden"),
+            b"{}",
+        );
+        assert_eq!(response.status, 400);
+
+        assert_eq!(api.handle(Method::Get, "/", None, &[]).status, 200);
+        let api = api.with_auth(auth.with_protected_reads(true));
+        assert_eq!(api.handle(Method::Get, "/", None, &[]).status, 401);
+
+        // Unknown routes are told apart without the token
+        assert_eq!(api.handle(Method::Post, "/weeds", None, b"{}").status, 404);
+        assert_eq!(api.handle(Method::Post, "/status", None, &[]).status, 405);
+    }
+
+    /// Status and the `error` of the envelope
+    fn error(response: Response) -> (u16, serde_json::Value) {
+        let envelope: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(envelope["ok"], false);
+        (response.status, envelope["error"].clone())
+    }
+
+    #[test]
+    fn errors_come_in_the_envelope() {
+        let api = api();
+
+        let (status, err) = error(api.handle(Method::Post, "/enable_section_for", None, b"{"));
+        assert_eq!((status, &err["code"]), (400, &"BAD_REQUEST".into()));
+        assert!(err["message"].as_str().unwrap().contains("EOF"));
+
+        let (status, err) = error(api.handle(
+            Method::Post,
+            "/enable_section_for",
+            None,
+            br#"{"section":"Lawn","duration":5}"#,
+        ));
+        assert_eq!(status, 404);
+        assert_eq!(
+            err,
+            serde_json::json!({"code": "SECTION_NOT_FOUND", "message": "There is no Lawn section"})
+        );
+
+        let (status, err) = error(api.handle(Method::Get, "/history?section=Lawn", None, &[]));
+        assert_eq!((status, &err["code"]), (404, &"SECTION_NOT_FOUND".into()));
+
+        let (status, err) = error(api.handle(Method::Get, "/weeds", None, &[]));
+        assert_eq!((status, &err["code"]), (404, &"NOT_FOUND".into()));
+
+        let (status, err) = error(api.handle(Method::Get, "/set_program", None, &[]));
+        assert_eq!((status, &err["code"]), (405, &"METHOD_NOT_ALLOWED".into()));
+        let (status, _) = error(api.handle(Method::Post, "/events", None, &[]));
+        assert_eq!(status, 405);
+
+        // Nobody listens on the other side of the channel
+        let (status, err) = error(api.handle(Method::Post, "/close_all_valves", None, &[]));
+        assert_eq!((status, &err["code"]), (503, &"SERVICE_UNAVAILABLE".into()));
+    }
+
+    #[test]
+    fn rejected_watering_is_reported() {
+        let (watering_tx, watering_rx) = channel();
+        let api = Api {
+            watering_tx,
+            ..api()
+        };
+        std::thread::spawn(move || {
+            while let Ok(msg) = watering_rx.recv() {
+                match msg {
+                    WateringServiceMessage::EnableSectionFor(_, _, tx) => {
+                        let _ = tx.send(Err(WateringError::SectionBusy("Vegs".into())));
+                    }
+                    WateringServiceMessage::RemoveProgram(name, tx) => {
+                        let _ = tx.send(Err(WateringError::UnknownProgram(name)));
+                    }
+                    WateringServiceMessage::SetSectionDuration(_, _, tx) => {
+                        let _ = tx.send(Ok(()));
+                    }
+                    _ => {}
+                }
+            }
+        });
+
+        let (status, err) = error(api.handle(
+            Method::Post,
+            "/enable_section_for",
+            None,
+            br#"{"section":"Grass","duration":5}"#,
+        ));
+        assert_eq!(status, 409);
+        assert_eq!(
+            err,
+            serde_json::json!({"code": "SECTION_BUSY", "message": "Scheduled watering of Vegs is in progress"})
+        );
+
+        let (status, err) = error(api.handle(
+            Method::Post,
+            "/remove_program",
+            None,
+            br#"{"name":"early"}"#,
+        ));
+        assert_eq!((status, &err["code"]), (404, &"PROGRAM_NOT_FOUND".into()));
+
+        let response = api.handle(
+            Method::Post,
+            "/set_section_duration",
+            None,
+            br#"{"section":"Grass","duration":5}"#,
+        );
+        assert_eq!(response.status, 200);
+        assert_eq!(*response.body, *br#"{"ok":true}"#);
+    }
+
+    #[test]
+    fn program_with_many_sections_fits_in_the_request() {
+        let ids = [
+            "Vegetables",
+            "Flowers",
+            "FrontLawn",
+            "BackLawn",
+            "Terrace",
+            "Hedge",
+            "Orchard",
+            "Greenhouse",
+        ];
+        let sections = ids
+            .iter()
+            .zip([14, 27, 33, 26, 25, 32, 13, 12])
+            .enumerate()
+            .map(|(order, (id, gpio))| {
+                serde_json::json!({"id": id, "name": id, "gpio": gpio, "order": order})
+            })
+            .collect::<Vec<_>>();
+        let (watering_tx, watering_rx) = channel();
+        let api = Api {
+            watering_tx,
+            sections: SectionsConfig::from_json(&serde_json::to_string(&sections).unwrap())
+                .unwrap(),
+            ..api()
+        };
+        std::thread::spawn(move || {
+            while let Ok(msg) = watering_rx.recv() {
+                if let WateringServiceMessage::SetProgram(_, _, tx) = msg {
+                    let _ = tx.send(Ok(()));
+                }
+            }
+        });
+
+        let program = serde_json::json!({
+            "name": "summer_evening",
+            "time": "20:30:00",
+            "rule": {"weekdays": ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"]},
+            "sections": ids.iter().map(|id| (*id, 30)).collect::<HashMap<_, _>>(),
+            "section_rules": ids
+                .iter()
+                .map(|id| {
+                    let rule = serde_json::json!({"every_n_days": {"days": 3, "anchor": "2024-05-01"}});
+                    (*id, rule)
+                })
+                .collect::<HashMap<_, _>>(),
+        });
+        let body = serde_json::to_vec_pretty(&program).unwrap();
+        assert!(body.len() > 1024);
+
+        let response = api.handle(Method::Post, "/set_program", None, &body);
+        assert_eq!(
+            response.status,
+            200,
+            "{}",
+            String::from_utf8_lossy(&response.body)
+        );
+    }
+
+    #[test]
+    fn history_query_is_decoded() {
+        let query = parse_history_query("since=2024-05-01T20%3A00%3A00&section=Grass").unwrap();
+        assert_eq!(
+            query,
+            HistoryQuery {
+                since: "2024-05-01T20:00:00".parse().ok(),
+                section: Some(SectionId::from("Grass")),
+            }
+        );
+
+        let query = parse_history_query("since=2024-05-01").unwrap();
+        assert_eq!(query.since, "2024-05-01T00:00:00".parse().ok());
+        assert_eq!(parse_history_query("").unwrap(), HistoryQuery::default());
+
+        assert!(parse_history_query("since=yesterday").is_err());
+        assert!(parse_history_query("until=2024-05-01").is_err());
+        assert!(parse_history_query("section=%4").is_err());
+    }
+}
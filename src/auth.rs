@@ -0,0 +1,141 @@
+//! Access to the API: requests changing anything need the token, reading might need it as well
+
+use anyhow::{bail, Context, Result};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use sha2::{Digest, Sha256};
+
+/// Only the SHA-256 of the token is kept, the token itself is known to the clients alone
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Auth {
+    /// None lets everyone in
+    token_sha256: Option<[u8; 32]>,
+    /// GET requests need the token as well
+    protect_reads: bool,
+}
+
+impl Auth {
+    /// No token, everyone can do everything
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    /// `token_sha256` is hex, like the one printed by `echo -n TOKEN | sha256sum`
+    pub fn new(token_sha256: &str) -> Result<Self> {
+        let token_sha256 = token_sha256.trim();
+        if token_sha256.len() != 64 {
+            bail!(
+                "SHA-256 of the token is 64 hex digits, got {}",
+                token_sha256.len()
+            );
+        }
+
+        let mut hash = [0; 32];
+        for (byte, hex) in hash.iter_mut().zip(token_sha256.as_bytes().chunks(2)) {
+            let hex = std::str::from_utf8(hex)?;
+            *byte = u8::from_str_radix(hex, 16)
+                .with_context(|| format!("{hex:?} is not a hex number"))?;
+        }
+
+        Ok(Self {
+            token_sha256: Some(hash),
+            protect_reads: false,
+        })
+    }
+
+    /// Reading, e.g. `/status`, needs the token too. Otherwise it is read-only access for everyone.
+    pub fn with_protected_reads(mut self, protect_reads: bool) -> Self {
+        self.protect_reads = protect_reads;
+        self
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.token_sha256.is_some()
+    }
+
+    /// Whether the request needs the token, `read` is for the requests that change nothing
+    pub fn is_required(&self, read: bool) -> bool {
+        self.is_enabled() && (!read || self.protect_reads)
+    }
+
+    /// `authorization` is the value of the header: `Bearer TOKEN`,
+    /// or `Basic` with any user name and the token as the password
+    pub fn is_authorized(&self, authorization: Option<&str>) -> bool {
+        let Some(expected) = &self.token_sha256 else {
+            return true;
+        };
+
+        let token = match authorization.and_then(|header| header.trim().split_once(' ')) {
+            Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => {
+                token.trim().as_bytes().to_vec()
+            }
+            Some((scheme, credentials)) if scheme.eq_ignore_ascii_case("basic") => {
+                let Ok(credentials) = BASE64_STANDARD.decode(credentials.trim()) else {
+                    return false;
+                };
+                match credentials.iter().position(|byte| *byte == b':') {
+                    Some(colon) => credentials[colon + 1..].to_vec(),
+                    None => return false,
+                }
+            }
+            _ => return false,
+        };
+
+        // Hashes are compared, every byte of them, so the time it takes tells nothing about the token
+        let digest = Sha256::digest(token);
+        digest
+            .iter()
+            .zip(expected)
+            .fold(0, |diff, (actual, expected)| diff | (actual ^ expected))
+            == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `echo -n garden | sha256sum`
+    const GARDEN_SHA256: &str = "23eeb69c681dfdb8eacc7ce9e55ea007d41c2dd5273848c597f1b3e49dbd86e1";
+
+    fn auth() -> Auth {
+        Auth::new(GARDEN_SHA256).unwrap()
+    }
+
+    #[test]
+    fn token_is_accepted_as_bearer_or_basic_password() {
+        let auth = auth();
+
+        assert!(auth.is_authorized(Some("Bearer garden")));
+        // `gardener:garden`
+        assert!(auth.is_authorized(Some("Basic Z2FyZGVuZXI6Z2FyZGVu")));
+
+        assert!(!auth.is_authorized(None));
+        assert!(!auth.is_authorized(Some("Bearer weeds")));
+        assert!(!auth.is_authorized(Some("Basic Z2FyZGVu")));
+        assert!(!auth.is_authorized(Some("Digest garden")));
+
+        assert!(Auth::disabled().is_authorized(None));
+    }
+
+    #[test]
+    fn reads_are_open_unless_protected() {
+        let auth = auth();
+        assert!(auth.is_required(false));
+        assert!(!auth.is_required(true));
+        assert!(auth.clone().with_protected_reads(true).is_required(true));
+
+        assert!(!Auth::disabled()
+            .with_protected_reads(true)
+            .is_required(false));
+    }
+
+    #[test]
+    fn hash_is_parsed_from_hex() {
+        assert_eq!(
+            Auth::new(&format!("{:x}", Sha256::digest("garden"))).unwrap(),
+            auth()
+        );
+        assert!(Auth::new("d7d4").is_err());
+        assert!(Auth::new(&"x".repeat(64)).is_err());
+    }
+}
@@ -0,0 +1,295 @@
+//! Garden simulator: the watering logic and the HTTP API running on the host,
+//! against simulated RTC and valves. Virtual time runs `--speed` times faster than the real one,
+//! so the whole schedule can be watched in seconds.
+//!
+//! `cargo +stable run --no-default-features --features simulator --target x86_64-unknown-linux-gnu --bin simulator -- --speed 600`
+
+use std::{
+    io::{Cursor, Read, Write},
+    path::PathBuf,
+    sync::mpsc::{Receiver, RecvTimeoutError},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{Local, NaiveDateTime, TimeDelta};
+use log::{info, warn};
+use water_my_garden_rs::{
+    api::{self, Api, ApiError, MAX_LEN},
+    auth::Auth,
+    clock::ClockService,
+    events::{Event, EventsService},
+    fail_safe::{install_panic_hook, take_last_panic},
+    host::{FakeRtc, FakeValve, FileStorage},
+    recovery::RecoveryPolicy,
+    sections::{SectionId, Sections, SectionsConfig},
+    time_zone::TimeZone,
+    watering::{OnScheduleWatering, WateringConfig},
+};
+
+const USAGE: &str = "Usage: simulator [--port 8080] [--speed 60] [--start 2024-05-01T05:55:00] [--storage target/simulator] [--sections sections.json] [--tz CET-1CEST,M3.5.0,M10.5.0/3] [--recovery skip|run_now|run_remaining] [--token-sha256 HEX] [--protect-reads]";
+
+struct Args {
+    port: u16,
+    /// How many virtual seconds pass in one real second
+    speed: u32,
+    /// Virtual local time at the simulator start
+    start: NaiveDateTime,
+    time_zone: TimeZone,
+    /// Simulated flash, configuration survives the simulator restart
+    storage: PathBuf,
+    /// Sections configuration, the one flashed on the device by default
+    sections: Option<PathBuf>,
+    /// Restart with a later `--start` is the power cut
+    recovery_policy: RecoveryPolicy,
+    auth: Auth,
+}
+
+fn parse_args() -> Result<Args> {
+    let mut args = Args {
+        port: 8080,
+        speed: 60,
+        start: Local::now().naive_local(),
+        time_zone: TimeZone::utc(),
+        storage: PathBuf::from("target/simulator"),
+        sections: None,
+        recovery_policy: RecoveryPolicy::default(),
+        auth: Auth::disabled(),
+    };
+    let mut protect_reads = false;
+
+    let mut argv = std::env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        let mut value = || {
+            argv.next()
+                .ok_or_else(|| anyhow!("Missing value for {arg}"))
+        };
+
+        match arg.as_str() {
+            "--port" => args.port = value()?.parse().context("Invalid port")?,
+            "--speed" => args.speed = value()?.parse().context("Invalid speed")?,
+            "--start" => args.start = value()?.parse().context("Invalid start time")?,
+            "--storage" => args.storage = value()?.into(),
+            "--tz" => args.time_zone = TimeZone::from_posix(&value()?)?,
+            "--sections" => args.sections = Some(value()?.into()),
+            "--recovery" => args.recovery_policy = value()?.parse()?,
+            "--token-sha256" => args.auth = Auth::new(&value()?)?,
+            "--protect-reads" => protect_reads = true,
+            "--help" | "-h" => {
+                println!("{USAGE}");
+                std::process::exit(0);
+            }
+            _ => bail!("Unknown argument {arg}\n{USAGE}"),
+        }
+    }
+
+    if args.speed == 0 {
+        bail!("Speed has to be positive");
+    }
+    args.auth = args.auth.with_protected_reads(protect_reads);
+
+    Ok(args)
+}
+
+fn main() -> Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let args = parse_args()?;
+
+    let sections = match &args.sections {
+        Some(path) => SectionsConfig::from_json(
+            &std::fs::read_to_string(path)
+                .with_context(|| format!("Cannot read {}", path.display()))?,
+        )?,
+        None => SectionsConfig::from_json(include_str!("../../sections.json"))?,
+    };
+
+    // RTC keeps UTC
+    let rtc = FakeRtc::new(args.time_zone.to_utc_lenient(args.start));
+    let valves = sections
+        .ids()
+        .into_iter()
+        .map(|section| (section, FakeValve::default()))
+        .collect::<Vec<_>>();
+
+    let events_service_channel = EventsService::new().start();
+
+    let sections_service =
+        Sections::new(valves.clone())?.with_events(events_service_channel.clone());
+    let clock_service = ClockService::new(rtc.clone())?
+        .with_time_zone(args.time_zone.clone())
+        .with_events(events_service_channel.clone());
+
+    let mut system_storage = FileStorage::new(args.storage.join("system"))?;
+    let last_reset_cause = take_last_panic(&mut system_storage)?.unwrap_or("PowerOn".to_string());
+    info!("Last reset cause: {last_reset_cause}");
+    install_panic_hook(
+        sections_service.emergency_closers(),
+        Box::new(system_storage),
+    );
+
+    let clock_service_channel = clock_service.start();
+    let sections_service_channel = sections_service.start();
+
+    let mut watering_storage = FileStorage::new(args.storage.join("watering"))?;
+    let watering_config = WateringConfig::load(&mut watering_storage)?;
+    info!("Loaded watering configuration {watering_config:?}");
+
+    let watering_service = OnScheduleWatering::new(
+        clock_service_channel.clone(),
+        sections_service_channel,
+        sections.ids(),
+        watering_config,
+        Box::new(watering_storage),
+    )
+    .with_recovery_policy(args.recovery_policy)
+    .with_events(events_service_channel.clone());
+    let watering_service_channel = watering_service.start();
+
+    start_virtual_time(rtc, args.time_zone, args.speed, valves);
+
+    let api = Api::new(
+        clock_service_channel,
+        watering_service_channel,
+        events_service_channel,
+        sections,
+        last_reset_cause,
+    )
+    .with_auth(args.auth);
+    serve(&api, args.port)
+}
+
+/// Moves the simulated RTC forward, reports valves that changed the state
+fn start_virtual_time(
+    rtc: FakeRtc,
+    time_zone: TimeZone,
+    speed: u32,
+    valves: Vec<(SectionId, FakeValve)>,
+) {
+    info!(
+        "Virtual time starts at {} {time_zone}, running {speed}x faster",
+        time_zone.to_local(rtc.now())
+    );
+
+    std::thread::spawn(move || {
+        let real_start = Instant::now();
+        let mut open = vec![false; valves.len()];
+
+        // Move second by second, so services react on time even with high speed
+        for virtual_seconds in 1.. {
+            let due = real_start + Duration::from_secs(virtual_seconds) / speed;
+            if let Some(wait) = due.checked_duration_since(Instant::now()) {
+                std::thread::sleep(wait);
+            }
+
+            rtc.advance(TimeDelta::seconds(1));
+
+            for ((section, valve), was_open) in valves.iter().zip(open.iter_mut()) {
+                if valve.is_open() != *was_open {
+                    *was_open = valve.is_open();
+                    let state = if *was_open { "OPEN" } else { "CLOSED" };
+                    info!(
+                        "[{}] {section} valve {state}",
+                        time_zone.to_local(rtc.now())
+                    );
+                }
+            }
+        }
+    });
+}
+
+fn serve(api: &Api, port: u16) -> Result<()> {
+    let server = tiny_http::Server::http(("0.0.0.0", port))
+        .map_err(|e| anyhow!("Cannot start the http server on port {port}: {e}"))?;
+    info!("Listening on http://localhost:{port}");
+
+    for request in server.incoming_requests() {
+        // One client going away mid-request does not stop the others
+        if let Err(e) = handle_request(api, request) {
+            warn!("Failed to handle the request {e:?}");
+        }
+    }
+
+    Ok(())
+}
+
+fn handle_request(api: &Api, mut request: tiny_http::Request) -> Result<()> {
+    let method = match request.method() {
+        tiny_http::Method::Get => Some(api::Method::Get),
+        tiny_http::Method::Post => Some(api::Method::Post),
+        tiny_http::Method::Put => Some(api::Method::Put),
+        _ => None,
+    };
+
+    let authorization = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Authorization"))
+        .map(|header| header.value.to_string());
+
+    if method == Some(api::Method::Get) && request.url() == api::EVENTS_PATH {
+        let events = api
+            .authorize(api::Method::Get, authorization.as_deref())
+            .and_then(|()| {
+                api.subscribe_events()
+                    .ok_or_else(|| ApiError::Unavailable("Events are not available".to_string()))
+            });
+        match events {
+            Ok(events) => {
+                // Stream lasts as long as the client stays, do not hold the other requests
+                std::thread::spawn(move || {
+                    if let Err(err) = stream_events(request, events) {
+                        info!("Events stream closed: {err}");
+                    }
+                });
+            }
+            Err(err) => request.respond(http_response(err.into())?)?,
+        }
+        return Ok(());
+    }
+
+    let mut body = vec![];
+    let read = request
+        .as_reader()
+        .take(MAX_LEN as u64 + 1)
+        .read_to_end(&mut body);
+
+    let response = match (method, read) {
+        (_, Err(e)) => ApiError::BadRequest(format!("Cannot read the body: {e}")).into(),
+        (Some(method), Ok(_)) => api.handle(method, request.url(), authorization.as_deref(), &body),
+        (None, Ok(_)) => ApiError::MethodNotAllowed.into(),
+    };
+
+    request.respond(http_response(response)?)?;
+
+    Ok(())
+}
+
+fn http_response(response: api::Response) -> Result<tiny_http::Response<Cursor<Vec<u8>>>> {
+    let mut http_response =
+        tiny_http::Response::from_data(response.body).with_status_code(response.status);
+    for (name, value) in response.headers {
+        let header = tiny_http::Header::from_bytes(name, value)
+            .map_err(|()| anyhow!("Invalid header {name}: {value}"))?;
+        http_response.add_header(header);
+    }
+    Ok(http_response)
+}
+
+/// Writes the events to the socket as they come, until the client goes away.
+/// tiny_http buffers the chunked responses, the stream is written by hand to flush every event.
+fn stream_events(request: tiny_http::Request, events: Receiver<Event>) -> std::io::Result<()> {
+    let mut writer = request.into_writer();
+    writer.write_all(api::EVENTS_RESPONSE_HEAD.as_bytes())?;
+    writer.flush()?;
+
+    loop {
+        let frame = match events.recv_timeout(api::EVENTS_KEEP_ALIVE_PERIOD) {
+            Ok(event) => event.to_sse(),
+            Err(RecvTimeoutError::Timeout) => api::EVENTS_KEEP_ALIVE.to_string(),
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+        writer.write_all(frame.as_bytes())?;
+        writer.flush()?;
+    }
+}
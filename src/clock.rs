@@ -1,37 +1,141 @@
-//! Abstraction over timekeeping hardware
-
-use anyhow::{anyhow, Result};
-use chrono::{NaiveDateTime, NaiveTime};
-use ds323x::{ic::DS3231, interface::I2cInterface, DateTimeAccess, Ds323x};
-use esp_idf_svc::hal::{
-    delay,
-    gpio::{IOPin, Input, PinDriver},
-    i2c::{I2c, I2cConfig, I2cDriver},
-    peripheral::Peripheral,
-    prelude::*,
-    task::queue::Queue,
-};
-use log::{error, info};
+//! Abstraction over timekeeping hardware.
+//! RTC keeps UTC, the service talks to the others in the local time of the garden.
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, NaiveDateTime, NaiveTime, SubsecRound, TimeDelta, Timelike};
+use log::{error, info, warn};
 use serde::Serialize;
-use std::sync::mpsc::{Receiver, Sender};
+use std::{
+    str::FromStr,
+    sync::mpsc::{Receiver, RecvTimeoutError, Sender},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    events::{Event, EventsServiceChannel, Publisher},
+    sections::SectionDuration,
+    time_zone::TimeZone,
+    watering::WateringServiceMessage,
+};
 
-use crate::{sections::SectionDuration, watering::WateringServiceMessage};
+/// Real time clock with two daily alarms, modeled after DS3231: alarm1 matches H:M:S, alarm2 matches H:M.
+/// Both alarms signal on the same interrupt line.
+pub trait RealTimeClock: Send + 'static {
+    fn datetime(&mut self) -> Result<NaiveDateTime>;
+    fn set_datetime(&mut self, datetime: NaiveDateTime) -> Result<()>;
+    /// Oscillator-stop flag, RTC lost the time, e.g. the backup battery ran out
+    fn has_been_stopped(&mut self) -> Result<bool>;
+    fn clear_has_been_stopped_flag(&mut self) -> Result<()>;
+    fn temperature(&mut self) -> Result<f32>;
+
+    fn set_alarm1_hms(&mut self, when: NaiveTime) -> Result<()>;
+    fn enable_alarm1_interrupts(&mut self) -> Result<()>;
+    fn disable_alarm1_interrupts(&mut self) -> Result<()>;
+    fn has_alarm1_matched(&mut self) -> Result<bool>;
+    fn clear_alarm1_matched_flag(&mut self) -> Result<()>;
+
+    fn set_alarm2_hm(&mut self, when: NaiveTime) -> Result<()>;
+    fn enable_alarm2_interrupts(&mut self) -> Result<()>;
+    fn disable_alarm2_interrupts(&mut self) -> Result<()>;
+    fn has_alarm2_matched(&mut self) -> Result<bool>;
+    fn clear_alarm2_matched_flag(&mut self) -> Result<()>;
+
+    /// Start listening on the interrupt line, every interrupt is passed to the Clock service
+    /// as `ClockServiceMessage::InterruptArrived`
+    fn start_interrupt_service(&mut self, tx: ClockServiceChannel) -> Result<()>;
+    /// Interrupt gets disabled after it fires, re-enable it
+    fn enable_interrupt(&mut self) -> Result<()>;
+}
+
+/// Time from the network, e.g. SNTP, used to keep the RTC on time
+pub trait NetworkTime: Send + 'static {
+    /// Current UTC time, fails when the network time is not available
+    fn now(&mut self) -> Result<NaiveDateTime>;
+}
 
-pub struct ClockService<IntGPIO: IOPin> {
-    rtc: Ds323x<I2cInterface<I2cDriver<'static>>, DS3231>,
-    int_pin: PinDriver<'static, IntGPIO, Input>,
+pub const DEFAULT_SYNC_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+/// Failed sync is retried sooner, network might be back in a moment
+const SYNC_RETRY: Duration = Duration::from_secs(60);
+
+pub struct ClockService<Rtc: RealTimeClock> {
+    rtc: Rtc,
     section_alarm_subscribers: Vec<Sender<WateringServiceMessage>>,
     watering_alarm_subscribers: Vec<Sender<WateringServiceMessage>>,
+    /// Given by the one arming the section alarm, it comes back with the alarm
+    section_alarm_id: u32,
+    network_time: Option<Box<dyn NetworkTime>>,
+    sync_interval: Duration,
+    next_sync: Option<Instant>,
+    last_sync: Option<NaiveDateTime>,
+    drift: Option<TimeDelta>,
+    time_zone: TimeZone,
+    /// UTC time the section alarm is armed for
+    section_alarm: Option<NaiveDateTime>,
+    /// Local time of the watering alarm and UTC time it is armed for
+    watering_alarm: Option<(NaiveTime, NaiveDateTime)>,
+    /// Written to the alarm registers, they stay there when the alarms get disarmed
+    alarm1_register: Option<NaiveTime>,
+    alarm2_register: Option<NaiveTime>,
+    events: Publisher,
 }
 
 #[derive(Serialize, Debug)]
 pub struct ClockStatus {
-    // alarm1 when
-    // alarm2 when
-    // status reg
-    // other regs
     temp: f32,
+    /// Local time
     now: NaiveDateTime,
+    time_zone: String,
+    /// None if RTC was never synced with the network time since boot
+    last_sync: Option<NaiveDateTime>,
+    /// Network time minus RTC time measured on the last sync, positive if RTC was late
+    drift_seconds: Option<i64>,
+    /// RTC lost the time at some point, it is not to be trusted until set again
+    oscillator_stopped: bool,
+    /// Alarm1
+    section_alarm: AlarmStatus,
+    /// Alarm2
+    watering_alarm: AlarmStatus,
+}
+
+impl ClockStatus {
+    pub fn temperature(&self) -> f32 {
+        self.temp
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AlarmStatus {
+    /// Interrupt of the alarm is enabled
+    armed: bool,
+    /// Local time the armed alarm fires at
+    at: Option<NaiveDateTime>,
+    /// Written to the alarm register last, UTC. H:M:S for alarm1, H:M for alarm2.
+    register: Option<NaiveTime>,
+    /// Alarm flag of the status register, set on the match until the interrupt is handled
+    matched: bool,
+}
+
+/// New time of the clock, ISO-8601 with the offset is any time zone, without the offset it is the local time
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DateTimeSetting {
+    /// `2024-05-01T06:00:00+02:00` or `2024-05-01T04:00:00Z`
+    Utc(NaiveDateTime),
+    /// `2024-05-01T06:00:00`
+    Local(NaiveDateTime),
+}
+
+impl FromStr for DateTimeSetting {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match DateTime::parse_from_rfc3339(s) {
+            Ok(datetime) => Ok(DateTimeSetting::Utc(datetime.naive_utc())),
+            Err(_) => s
+                .parse()
+                .map(DateTimeSetting::Local)
+                .with_context(|| format!("{s:?} is not ISO-8601 date and time")),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -41,68 +145,111 @@ pub enum ClockServiceMessage {
     SubscribeForSectionAlarm(Sender<WateringServiceMessage>),
     /// Use alarm2 for watering notification - it has H:M resolution, it's enough for "set watering on 20:30"
     SubscribeForWateringAlarm(Sender<WateringServiceMessage>),
-    /// Set section alarm in some time in the future starting from now. E.g. in 15 minutes
-    SetSectionAlarmAfter(SectionDuration),
-    /// Set watering to exact time HH:MM::00
+    /// Set section alarm in some time in the future starting from now. E.g. in 15 minutes.
+    /// Alarm fires with the given id, so it can be told apart from the one armed before.
+    SetSectionAlarmAfter(SectionDuration, u32),
+    /// Set watering to exact local time HH:MM::00, every day
     SetWateringAlarmAt(NaiveTime),
     DisableSectionAlarm,
     DisableWateringAlarm,
     GetStatus(Sender<ClockStatus>),
+    /// Local time
     GetDateTime(Sender<NaiveDateTime>),
+    /// Sets the RTC, armed alarms are moved along
+    SetDateTime(DateTimeSetting, Sender<Result<(), ClockError>>),
 }
 
 pub type ClockServiceChannel = Sender<ClockServiceMessage>;
 
-impl<IntGPIO: IOPin> ClockService<IntGPIO> {
-    pub fn new(
-        sda_pin: impl Peripheral<P = impl IOPin> + 'static,
-        scl_pin: impl Peripheral<P = impl IOPin> + 'static,
-        int_pin: impl Peripheral<P = IntGPIO> + 'static,
-        i2c: impl Peripheral<P = impl I2c> + 'static,
-    ) -> Result<Self> {
-        // Configure RTC I2C driver
-        let config = I2cConfig::new().baudrate(400.kHz().into());
-        let i2c_dev = I2cDriver::new(i2c, sda_pin, scl_pin, &config)?;
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClockError {
+    /// RTC did not respond, or refused the request
+    Rtc(String),
+}
 
-        let mut rtc = Ds323x::new_ds3231(i2c_dev);
+impl ClockError {
+    /// Machine readable, stays the same when the message changes
+    pub fn code(&self) -> &'static str {
+        match self {
+            ClockError::Rtc(_) => "RTC_UNAVAILABLE",
+        }
+    }
+}
 
-        // This pin is unused
-        rtc.disable_32khz_output()
-            .map_err(|e| anyhow!("Cannot disable 32khz output {e:?}"))?;
+impl std::fmt::Display for ClockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClockError::Rtc(reason) => write!(f, "RTC failed: {reason}"),
+        }
+    }
+}
 
-        rtc.use_int_sqw_output_as_interrupt()
-            .map_err(|e| anyhow!("Cannot set sqw as interrupt {e:?}"))?;
+impl std::error::Error for ClockError {}
 
+impl<Rtc: RealTimeClock> ClockService<Rtc> {
+    pub fn new(mut rtc: Rtc) -> Result<Self> {
         // Cleanup state from previous reboot
-        rtc.disable_alarm1_interrupts()
-            .map_err(|e| anyhow!("Cannot disable alarm1 INT {e:?}"))?;
-        rtc.disable_alarm2_interrupts()
-            .map_err(|e| anyhow!("Cannot disable alarm2 INT {e:?}"))?;
-
-        // Configure INT GPIO, the SQW output pin of the RTC is connected to it
-        let mut int_pin = PinDriver::input(int_pin).unwrap();
-
-        int_pin
-            .set_pull(esp_idf_svc::hal::gpio::Pull::Down)
-            .unwrap();
+        rtc.disable_alarm1_interrupts()?;
+        rtc.disable_alarm2_interrupts()?;
 
         Ok(Self {
             rtc,
-            int_pin,
             section_alarm_subscribers: vec![],
             watering_alarm_subscribers: vec![],
+            section_alarm_id: 0,
+            network_time: None,
+            sync_interval: DEFAULT_SYNC_INTERVAL,
+            next_sync: None,
+            last_sync: None,
+            drift: None,
+            time_zone: TimeZone::utc(),
+            section_alarm: None,
+            watering_alarm: None,
+            alarm1_register: None,
+            alarm2_register: None,
+            events: Publisher::default(),
         })
     }
 
+    /// Fired alarms are published as the events
+    pub fn with_events(mut self, events_tx: EventsServiceChannel) -> Self {
+        self.events = Publisher::new(events_tx);
+        self
+    }
+
+    /// Local time zone, UTC by default
+    pub fn with_time_zone(mut self, time_zone: TimeZone) -> Self {
+        self.time_zone = time_zone;
+        self
+    }
+
+    /// Sets the RTC from the network time on start and then every `sync_interval`.
+    /// RTC keeps the time on its own when the network time is not available.
+    pub fn with_network_time(
+        mut self,
+        network_time: impl NetworkTime,
+        sync_interval: Duration,
+    ) -> Self {
+        self.network_time = Some(Box::new(network_time));
+        self.sync_interval = sync_interval;
+        self
+    }
+
     /// Starts the Clock Service, returns the ClockServiceChannel to communicate with it
     pub fn start(mut self) -> ClockServiceChannel {
         // Create channel that is used to communicate with this service
         let (tx, rx) = std::sync::mpsc::channel();
 
-        self.start_interrupt_service(tx.clone());
+        self.rtc
+            .start_interrupt_service(tx.clone())
+            .expect("Cannot start RTC interrupt service");
+        self.enable_interrupt();
 
         // Create Clock service
-        std::thread::spawn(move || self.clock_service(rx));
+        std::thread::Builder::new()
+            .name("clock service".to_string())
+            .spawn(move || self.clock_service(rx))
+            .expect("Cannot spawn Clock service");
 
         tx
     }
@@ -110,22 +257,48 @@ impl<IntGPIO: IOPin> ClockService<IntGPIO> {
     fn clock_service(mut self, rx: Receiver<ClockServiceMessage>) {
         log::info!("Hello from Clock service!");
 
-        while let Ok(msg) = rx.recv() {
+        self.sync_with_network_time();
+
+        loop {
+            let msg = match self.next_sync {
+                Some(next_sync) => {
+                    match rx.recv_timeout(next_sync.saturating_duration_since(Instant::now())) {
+                        Ok(msg) => msg,
+                        Err(RecvTimeoutError::Timeout) => {
+                            self.sync_with_network_time();
+                            continue;
+                        }
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+                None => match rx.recv() {
+                    Ok(msg) => msg,
+                    Err(_) => break,
+                },
+            };
+
             match msg {
                 ClockServiceMessage::InterruptArrived(int_count) => {
                     log::info!("Got interrupt notification in service! #{int_count}");
 
-                    // Try to send to the subscribers, if fails, it means rx "unsubscribed", filter such entries
-                    if self.rtc.has_alarm1_matched().unwrap() {
+                    // Try to send to the subscribers, if fails, it means rx "unsubscribed", filter such entries.
+                    // Flag of the disabled alarm is still set on the match, e.g. section alarm of the aborted run
+                    if self.rtc.has_alarm1_matched().unwrap() && self.section_alarm.is_some() {
+                        self.events.publish(Event::SectionAlarmFired);
+                        let id = self.section_alarm_id;
                         let subscribers = self
                             .section_alarm_subscribers
                             .into_iter()
-                            .filter(|tx| tx.send(WateringServiceMessage::SectionAlarmFired).is_ok())
+                            .filter(|tx| {
+                                tx.send(WateringServiceMessage::SectionAlarmFired(id))
+                                    .is_ok()
+                            })
                             .collect();
                         self.section_alarm_subscribers = subscribers;
                     }
 
-                    if self.rtc.has_alarm2_matched().unwrap() {
+                    if self.rtc.has_alarm2_matched().unwrap() && self.watering_alarm_due() {
+                        self.events.publish(Event::WateringAlarmFired);
                         let subscribers = self
                             .watering_alarm_subscribers
                             .into_iter()
@@ -144,32 +317,54 @@ impl<IntGPIO: IOPin> ClockService<IntGPIO> {
                 ClockServiceMessage::SubscribeForWateringAlarm(tx) => {
                     self.watering_alarm_subscribers.push(tx)
                 }
-                ClockServiceMessage::SetSectionAlarmAfter(offset) => {
-                    info!("Handling Alarm1 - Section with offset {offset}");
-                    let now = self.get_current_datetime().unwrap();
+                ClockServiceMessage::SetSectionAlarmAfter(offset, id) => {
+                    info!("Handling Alarm1 - Section #{id} with offset {offset}");
+                    self.section_alarm_id = id;
+                    let now = self.rtc.datetime().unwrap();
                     let future = now.checked_add_signed(offset.into_inner()).unwrap();
-                    info!("Setting Alarm1 - Section to {future}");
-                    self.rtc.set_alarm1_hms(future.time()).unwrap();
-                    self.rtc.enable_alarm1_interrupts().unwrap();
+                    self.arm_section_alarm(future).unwrap();
                 }
                 ClockServiceMessage::SetWateringAlarmAt(when) => {
-                    info!("Setting Alarm2 - Watering to {when}");
-                    self.rtc.set_alarm2_hm(when).unwrap();
-                    self.rtc.enable_alarm2_interrupts().unwrap();
+                    // Register has a minute resolution
+                    let when = NaiveTime::from_hms_opt(when.hour(), when.minute(), 0).unwrap();
+                    self.arm_watering_alarm(when).unwrap();
                 }
                 ClockServiceMessage::DisableSectionAlarm => {
                     info!("Disabling Alarm1 - Section");
                     self.rtc.disable_alarm1_interrupts().unwrap();
+                    self.section_alarm = None;
                 }
                 ClockServiceMessage::DisableWateringAlarm => {
                     info!("Disabling Alarm2 - Watering");
                     self.rtc.disable_alarm2_interrupts().unwrap();
+                    self.watering_alarm = None;
                 }
                 ClockServiceMessage::GetStatus(tx) => {
                     let temp = self.get_temperature().unwrap();
                     let now = self.get_current_datetime().unwrap();
 
-                    let status = ClockStatus { temp, now };
+                    let status = ClockStatus {
+                        temp,
+                        now,
+                        time_zone: self.time_zone.to_string(),
+                        last_sync: self.last_sync.map(|utc| self.time_zone.to_local(utc)),
+                        drift_seconds: self.drift.map(|drift| drift.num_seconds()),
+                        oscillator_stopped: self.rtc.has_been_stopped().unwrap(),
+                        section_alarm: AlarmStatus {
+                            armed: self.section_alarm.is_some(),
+                            at: self.section_alarm.map(|at| self.time_zone.to_local(at)),
+                            register: self.alarm1_register,
+                            matched: self.rtc.has_alarm1_matched().unwrap(),
+                        },
+                        watering_alarm: AlarmStatus {
+                            armed: self.watering_alarm.is_some(),
+                            at: self
+                                .watering_alarm
+                                .map(|(_, at)| self.time_zone.to_local(at)),
+                            register: self.alarm2_register,
+                            matched: self.rtc.has_alarm2_matched().unwrap(),
+                        },
+                    };
 
                     info!("Reporting Clock status {status:#?}");
                     if let Err(e) = tx.send(status) {
@@ -184,100 +379,531 @@ impl<IntGPIO: IOPin> ClockService<IntGPIO> {
                         error!("Failed to send time status as a response {e}");
                     }
                 }
+                ClockServiceMessage::SetDateTime(setting, tx) => {
+                    let utc = match setting {
+                        DateTimeSetting::Utc(utc) => utc,
+                        DateTimeSetting::Local(local) => self.time_zone.to_utc_lenient(local),
+                    };
+
+                    info!("Setting RTC to {utc} UTC");
+                    // Sub-second part would be truncated by the RTC anyway
+                    let result = match self.set_rtc_datetime(utc.trunc_subsecs(0)) {
+                        Ok(jump) => {
+                            info!("RTC moved by {jump}");
+                            Ok(())
+                        }
+                        Err(e) => {
+                            error!("Cannot set RTC {e:?}");
+                            Err(ClockError::Rtc(format!("{e:#}")))
+                        }
+                    };
+                    if tx.send(result).is_err() {
+                        warn!("Nobody waits for the RTC to be set");
+                    }
+                }
             }
         }
     }
 
-    /// Setup RTC interrupt handling: ISR -> interrupt-handler task -> ClockService task
-    fn start_interrupt_service(&mut self, tx: ClockServiceChannel) {
-        // Communicate from ISR with task using FreeRTOS queue.
-        // Alternative is to use Notification - this one is however bounded to the task,
-        // and cannot be moved across threads.
+    fn enable_interrupt(&mut self) {
+        // Clear the flag on RTC indicating the interrupt got handled, it will enable RTC to trigger again.
+        if self.rtc.has_alarm1_matched().unwrap() {
+            self.rtc.clear_alarm1_matched_flag().unwrap();
+        }
 
-        // ISR part, will use it to push back notifications
-        let queue_isr = Queue::new(10);
+        if self.rtc.has_alarm2_matched().unwrap() {
+            self.rtc.clear_alarm2_matched_flag().unwrap();
+        }
 
-        // Thread part, will pop front notifications
-        // SAFETY: Owner of this queue is ISR, captured in a closure. Will never drop.
-        let queue_thread = unsafe { Queue::<u32>::new_borrowed(queue_isr.as_raw()) };
+        // GPIO interrupt got disabled after fire, re-enable again
+        self.rtc.enable_interrupt().unwrap();
+    }
 
-        // INT pin on RTC is high by default, listen on falling edge
-        self.int_pin
-            .set_interrupt_type(esp_idf_svc::hal::gpio::InterruptType::NegEdge)
-            .unwrap();
+    fn arm_section_alarm(&mut self, at: NaiveDateTime) -> Result<()> {
+        info!("Setting Alarm1 - Section to {at} UTC");
+        self.rtc.set_alarm1_hms(at.time())?;
+        self.alarm1_register = Some(at.time().trunc_subsecs(0));
+        self.rtc.enable_alarm1_interrupts()?;
+        self.section_alarm = Some(at);
+        Ok(())
+    }
 
-        // Start listening on interrupt, set ISR that pushes interrupt notifications to the queue
-        // SAFETY: Using ISR-safe calls here
-        unsafe {
-            self.int_pin
-                .subscribe(move || {
-                    static mut INT_COUNT: u32 = 1;
-
-                    let high_prio_task_was_awoken = queue_isr
-                        .send_back(INT_COUNT, delay::NON_BLOCK)
-                        .expect("The interrupt queue is full!");
-                    INT_COUNT += 1;
-
-                    if high_prio_task_was_awoken {
-                        // This is FreeRTOS detail:
-                        // Context switch should be performed before the interrupt is exited. This will ensure that the
-                        // interrupt returns directly to the highest priority Ready state task
-                        esp_idf_svc::hal::task::do_yield();
-                    }
-                })
-                .unwrap()
+    /// Alarm2 matches H:M in UTC, local time of the next watering might be at the other UTC time
+    /// after the DST change, so the alarm is armed again every time
+    fn arm_watering_alarm(&mut self, when: NaiveTime) -> Result<()> {
+        let at = self.time_zone.next_utc_at(when, self.rtc.datetime()?);
+
+        info!("Setting Alarm2 - Watering to {when}, {at} UTC");
+        self.rtc.set_alarm2_hm(at.time())?;
+        self.alarm2_register = NaiveTime::from_hms_opt(at.hour(), at.minute(), 0);
+        self.rtc.enable_alarm2_interrupts()?;
+        self.watering_alarm = Some((when, at));
+        Ok(())
+    }
+
+    /// Day the clock goes back is 25 hours long, alarm2 set for the next day matches an hour
+    /// after it is set - too early, it has to wait for the next match.
+    /// Flag of the disabled alarm is still set on the match, it is not due then
+    fn watering_alarm_due(&mut self) -> bool {
+        let Some((when, at)) = self.watering_alarm else {
+            return false;
         };
 
-        self.enable_interrupt();
+        let now = self.rtc.datetime().unwrap();
+        if now < at {
+            info!("Alarm2 - Watering matched at {now} UTC, before {at} UTC, waiting");
+            return false;
+        }
 
-        // Create interrupt-handler task, will communicate with the Clock service when interrupt arrive
-        // This is a thin wrapper over the FreeRTOS task
-        // TODO: that thread might be redundant if embassy channels are safe to call from ISR.
-        // The std::sync::mpsc channels are not. Therefore current solution uses ISR-safe primitive (FreeRTOS queue)
-        // to communicate with following thread, and this thread finally communicates with the Clock service using mpsc channel.
-        std::thread::spawn(move || {
-            log::info!("Hello from RTC interrupt task!");
-
-            // Receive interrupt from ISR
-            while let Some((int_count, _)) = queue_thread.recv_front(delay::BLOCK) {
-                log::debug!("Got interrupt notification! #{int_count}");
-                // Pass it to the service
-                tx.send(ClockServiceMessage::InterruptArrived(int_count))
-                    .expect("Cannot notify Clock service");
-            }
+        self.arm_watering_alarm(when).unwrap();
+        true
+    }
+
+    fn sync_with_network_time(&mut self) {
+        let Some(network_time) = self.network_time.as_mut() else {
+            return;
+        };
+
+        let synced = network_time.now().and_then(|now| {
+            // RTC has a second resolution
+            let now = now.round_subsecs(0);
+            Ok((now, self.set_rtc_datetime(now)?))
         });
+
+        let next_sync = match synced {
+            Ok((now, drift)) => {
+                info!("RTC synced with the network time {now}, drift {drift}");
+                self.last_sync = Some(now);
+                self.drift = Some(drift);
+                self.sync_interval
+            }
+            Err(e) => {
+                warn!("Cannot sync RTC with the network time, RTC keeps the time {e:?}");
+                self.sync_interval.min(SYNC_RETRY)
+            }
+        };
+
+        self.next_sync = Some(Instant::now() + next_sync);
     }
 
-    fn enable_interrupt(&mut self) {
-        // Clear the flag on RTC indicating the interrupt got handled, it will enable RTC to trigger again.
-        if self.rtc.has_alarm1_matched().unwrap() {
-            self.rtc.clear_alarm1_matched_flag().unwrap();
+    /// Section alarm keeps the remaining duration, watering alarm keeps the local time.
+    /// Returns how far the time moved.
+    fn set_rtc_datetime(&mut self, utc: NaiveDateTime) -> Result<TimeDelta> {
+        let before = self.rtc.datetime()?;
+        self.rtc.set_datetime(utc)?;
+        // Time is valid from now on
+        self.rtc.clear_has_been_stopped_flag()?;
+
+        if let Some(at) = self.section_alarm {
+            let remaining = at - before;
+            if remaining > TimeDelta::zero() {
+                self.arm_section_alarm(utc + remaining)?;
+            }
         }
 
-        if self.rtc.has_alarm2_matched().unwrap() {
-            self.rtc.clear_alarm2_matched_flag().unwrap();
+        if let Some((when, _)) = self.watering_alarm {
+            self.arm_watering_alarm(when)?;
         }
 
-        // GPIO interrupt got disabled after fire, re-enable again
-        self.int_pin.enable_interrupt().unwrap();
+        Ok(utc - before)
     }
 
     fn get_current_datetime(&mut self) -> Result<NaiveDateTime> {
-        // TODO: since wifi is connected, NTP for the clock
-        let datetime = self
-            .rtc
-            .datetime()
-            .map_err(|e| anyhow!("Failed to read current datetime {e:?}"))?;
-
-        info!("RTC: {datetime}");
-        Ok(datetime)
+        let datetime = self.rtc.datetime()?;
+
+        info!("RTC: {datetime} UTC");
+        Ok(self.time_zone.to_local(datetime))
     }
 
     fn get_temperature(&mut self) -> Result<f32> {
-        let temp = self
-            .rtc
-            .temperature()
-            .map_err(|e| anyhow!("Failed to read temperature {e:?}"))?;
+        let temp = self.rtc.temperature()?;
         Ok(temp)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc::channel, time::Duration};
+
+    use chrono::{NaiveDate, TimeDelta};
+
+    use crate::host::{wait_until, FakeNtpServer, FakeRtc, SntpClient};
+
+    use super::*;
+
+    fn start_clock() -> (
+        FakeRtc,
+        ClockServiceChannel,
+        Receiver<WateringServiceMessage>,
+    ) {
+        let now = NaiveDate::from_ymd_opt(2024, 5, 1)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap();
+        let rtc = FakeRtc::new(now);
+
+        let clock_tx = ClockService::new(rtc.clone()).unwrap().start();
+
+        let (tx, rx) = channel();
+        clock_tx
+            .send(ClockServiceMessage::SubscribeForSectionAlarm(tx.clone()))
+            .unwrap();
+        clock_tx
+            .send(ClockServiceMessage::SubscribeForWateringAlarm(tx))
+            .unwrap();
+
+        (rtc, clock_tx, rx)
+    }
+
+    fn get_status(clock_tx: &ClockServiceChannel) -> ClockStatus {
+        let (tx, rx) = channel();
+        clock_tx.send(ClockServiceMessage::GetStatus(tx)).unwrap();
+        rx.recv_timeout(Duration::from_secs(1)).unwrap()
+    }
+
+    /// Clock handles messages in order, once it responds all previous messages are handled
+    fn sync(clock_tx: &ClockServiceChannel) {
+        let (tx, rx) = channel();
+        clock_tx.send(ClockServiceMessage::GetDateTime(tx)).unwrap();
+        rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    }
+
+    #[test]
+    fn section_alarm_fires_after_given_offset() {
+        let (rtc, clock_tx, rx) = start_clock();
+
+        clock_tx
+            .send(ClockServiceMessage::SetSectionAlarmAfter(
+                TimeDelta::minutes(5).try_into().unwrap(),
+                1,
+            ))
+            .unwrap();
+        sync(&clock_tx);
+        assert_eq!(rtc.alarm1(), NaiveTime::from_hms_opt(12, 5, 0));
+
+        rtc.advance(TimeDelta::seconds(299));
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        rtc.advance(TimeDelta::seconds(1));
+        assert!(matches!(
+            rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            WateringServiceMessage::SectionAlarmFired(1)
+        ));
+    }
+
+    #[test]
+    fn watering_alarm_fires_every_day() {
+        let (rtc, clock_tx, rx) = start_clock();
+
+        clock_tx
+            .send(ClockServiceMessage::SetWateringAlarmAt(
+                NaiveTime::from_hms_opt(6, 30, 0).unwrap(),
+            ))
+            .unwrap();
+        sync(&clock_tx);
+
+        // Next day, 06:30
+        rtc.advance(TimeDelta::hours(18) + TimeDelta::minutes(30));
+        assert!(matches!(
+            rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            WateringServiceMessage::WateringAlarmFired
+        ));
+        sync(&clock_tx);
+
+        // Day after
+        rtc.advance(TimeDelta::days(1));
+        assert!(matches!(
+            rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            WateringServiceMessage::WateringAlarmFired
+        ));
+    }
+
+    #[test]
+    fn disabled_alarms_do_not_fire() {
+        let (rtc, clock_tx, rx) = start_clock();
+
+        clock_tx
+            .send(ClockServiceMessage::SetSectionAlarmAfter(
+                TimeDelta::minutes(1).try_into().unwrap(),
+                1,
+            ))
+            .unwrap();
+        clock_tx
+            .send(ClockServiceMessage::SetWateringAlarmAt(
+                NaiveTime::from_hms_opt(12, 2, 0).unwrap(),
+            ))
+            .unwrap();
+        clock_tx
+            .send(ClockServiceMessage::DisableSectionAlarm)
+            .unwrap();
+        clock_tx
+            .send(ClockServiceMessage::DisableWateringAlarm)
+            .unwrap();
+        sync(&clock_tx);
+
+        assert_eq!(rtc.alarm1(), None);
+        assert_eq!(rtc.alarm2(), None);
+
+        rtc.advance(TimeDelta::minutes(5));
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn disabled_watering_alarm_does_not_come_with_the_section_alarm() {
+        let (rtc, clock_tx, rx) = start_clock();
+
+        clock_tx
+            .send(ClockServiceMessage::SetWateringAlarmAt(
+                NaiveTime::from_hms_opt(12, 2, 0).unwrap(),
+            ))
+            .unwrap();
+        clock_tx
+            .send(ClockServiceMessage::DisableWateringAlarm)
+            .unwrap();
+        clock_tx
+            .send(ClockServiceMessage::SetSectionAlarmAfter(
+                TimeDelta::minutes(3).try_into().unwrap(),
+                1,
+            ))
+            .unwrap();
+        sync(&clock_tx);
+
+        // Alarm2 flag is set on the match, the interrupt of alarm1 finds it
+        rtc.advance(TimeDelta::minutes(3));
+        assert!(matches!(
+            rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            WateringServiceMessage::SectionAlarmFired(1)
+        ));
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn reports_status() {
+        let (rtc, clock_tx, _rx) = start_clock();
+        rtc.set_temperature(30.25);
+        rtc.advance(TimeDelta::seconds(42));
+
+        let (tx, rx) = channel();
+        clock_tx.send(ClockServiceMessage::GetStatus(tx)).unwrap();
+        let status = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+
+        assert_eq!(status.temp, 30.25);
+        assert_eq!(
+            status.now.time(),
+            NaiveTime::from_hms_opt(12, 0, 42).unwrap()
+        );
+        assert!(!status.section_alarm.armed && !status.watering_alarm.armed);
+
+        clock_tx
+            .send(ClockServiceMessage::SetSectionAlarmAfter(
+                TimeDelta::minutes(5).try_into().unwrap(),
+                1,
+            ))
+            .unwrap();
+        clock_tx
+            .send(ClockServiceMessage::SetWateringAlarmAt(
+                NaiveTime::from_hms_opt(12, 30, 0).unwrap(),
+            ))
+            .unwrap();
+        let status = get_status(&clock_tx);
+        assert!(status.section_alarm.armed);
+        assert_eq!(
+            status.section_alarm.at,
+            "2024-05-01T12:05:42".parse::<NaiveDateTime>().ok()
+        );
+        assert_eq!(
+            status.section_alarm.register,
+            NaiveTime::from_hms_opt(12, 5, 42)
+        );
+        assert!(status.watering_alarm.armed);
+        assert_eq!(
+            status.watering_alarm.at,
+            "2024-05-01T12:30:00".parse::<NaiveDateTime>().ok()
+        );
+
+        // Register keeps the time of the disarmed alarm
+        clock_tx
+            .send(ClockServiceMessage::DisableSectionAlarm)
+            .unwrap();
+        let status = get_status(&clock_tx);
+        assert!(!status.section_alarm.armed);
+        assert_eq!(status.section_alarm.at, None);
+        assert_eq!(
+            status.section_alarm.register,
+            NaiveTime::from_hms_opt(12, 5, 42)
+        );
+    }
+
+    #[test]
+    fn rtc_is_synced_with_network_time() {
+        let rtc_now = NaiveDate::from_ymd_opt(2024, 5, 1)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap();
+        let rtc = FakeRtc::new(rtc_now);
+        let ntp = FakeNtpServer::start(rtc_now + TimeDelta::seconds(42)).unwrap();
+
+        let clock_tx = ClockService::new(rtc.clone())
+            .unwrap()
+            .with_network_time(
+                SntpClient::new(ntp.addr(), Duration::from_millis(100)),
+                DEFAULT_SYNC_INTERVAL,
+            )
+            .start();
+
+        let status = get_status(&clock_tx);
+        assert_eq!(status.drift_seconds, Some(42));
+        assert!(status.last_sync.is_some());
+        assert_eq!(
+            status.now.time(),
+            NaiveTime::from_hms_opt(12, 0, 42).unwrap()
+        );
+    }
+
+    #[test]
+    fn rtc_keeps_time_when_network_is_offline() {
+        let rtc_now = NaiveDate::from_ymd_opt(2024, 5, 1)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap();
+        let rtc = FakeRtc::new(rtc_now);
+        let ntp = FakeNtpServer::start(rtc_now - TimeDelta::seconds(5)).unwrap();
+        ntp.set_online(false);
+
+        let clock_tx = ClockService::new(rtc.clone())
+            .unwrap()
+            .with_network_time(
+                SntpClient::new(ntp.addr(), Duration::from_millis(50)),
+                Duration::from_millis(100),
+            )
+            .start();
+
+        // RTC stays the source of time
+        let status = get_status(&clock_tx);
+        assert_eq!(status.last_sync, None);
+        assert_eq!(status.drift_seconds, None);
+        assert_eq!(status.now, rtc_now);
+
+        // Sync is retried, once the network is back
+        ntp.set_online(true);
+        assert!(wait_until(Duration::from_secs(2), || get_status(&clock_tx)
+            .drift_seconds
+            == Some(-5)));
+        assert_eq!(rtc.now(), rtc_now - TimeDelta::seconds(5));
+    }
+
+    #[test]
+    fn watering_alarm_follows_local_time_over_dst_change() {
+        // 2024-10-27 03:00 CEST clock goes back to 02:00 CET, 06:00 CEST is 04:00 UTC, 06:00 CET is 05:00 UTC
+        let rtc = FakeRtc::new("2024-10-26T03:30:00".parse().unwrap());
+        let clock_tx = ClockService::new(rtc.clone())
+            .unwrap()
+            .with_time_zone(TimeZone::from_posix("CET-1CEST,M3.5.0,M10.5.0/3").unwrap())
+            .start();
+        let (tx, rx) = channel();
+        clock_tx
+            .send(ClockServiceMessage::SubscribeForWateringAlarm(tx))
+            .unwrap();
+
+        let (tx, now_rx) = channel();
+        clock_tx.send(ClockServiceMessage::GetDateTime(tx)).unwrap();
+        assert_eq!(
+            now_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            "2024-10-26T05:30:00".parse().unwrap()
+        );
+
+        clock_tx
+            .send(ClockServiceMessage::SetWateringAlarmAt(
+                NaiveTime::from_hms_opt(6, 0, 0).unwrap(),
+            ))
+            .unwrap();
+        sync(&clock_tx);
+        assert_eq!(rtc.alarm2(), NaiveTime::from_hms_opt(4, 0, 0));
+
+        // 06:00 CEST
+        rtc.advance(TimeDelta::minutes(30));
+        assert!(matches!(
+            rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            WateringServiceMessage::WateringAlarmFired
+        ));
+        sync(&clock_tx);
+        assert_eq!(rtc.alarm2(), NaiveTime::from_hms_opt(5, 0, 0));
+
+        // 07:00 CEST, alarm2 matches, but it is 06:00 CET tomorrow that is awaited
+        rtc.advance(TimeDelta::hours(1));
+        sync(&clock_tx);
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        // 06:00 CET
+        rtc.advance(TimeDelta::hours(24));
+        assert!(matches!(
+            rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            WateringServiceMessage::WateringAlarmFired
+        ));
+        assert_eq!(rtc.now(), "2024-10-27T05:00:00".parse().unwrap());
+    }
+
+    #[test]
+    fn date_time_setting_is_iso_8601() {
+        let datetime = |s: &str| s.parse::<NaiveDateTime>().unwrap();
+
+        assert_eq!(
+            "2024-05-01T06:00:00+02:00"
+                .parse::<DateTimeSetting>()
+                .unwrap(),
+            DateTimeSetting::Utc(datetime("2024-05-01T04:00:00"))
+        );
+        assert_eq!(
+            "2024-05-01T04:00:00Z".parse::<DateTimeSetting>().unwrap(),
+            DateTimeSetting::Utc(datetime("2024-05-01T04:00:00"))
+        );
+        assert_eq!(
+            "2024-05-01T06:00:00".parse::<DateTimeSetting>().unwrap(),
+            DateTimeSetting::Local(datetime("2024-05-01T06:00:00"))
+        );
+        assert!("2024-05-01".parse::<DateTimeSetting>().is_err());
+        assert!("06:00".parse::<DateTimeSetting>().is_err());
+    }
+
+    #[test]
+    fn setting_time_moves_alarms_along() {
+        let (rtc, clock_tx, rx) = start_clock();
+        rtc.stop_oscillator();
+        assert!(get_status(&clock_tx).oscillator_stopped);
+
+        clock_tx
+            .send(ClockServiceMessage::SetSectionAlarmAfter(
+                TimeDelta::minutes(5).try_into().unwrap(),
+                1,
+            ))
+            .unwrap();
+        clock_tx
+            .send(ClockServiceMessage::SetWateringAlarmAt(
+                NaiveTime::from_hms_opt(6, 30, 0).unwrap(),
+            ))
+            .unwrap();
+        sync(&clock_tx);
+        rtc.advance(TimeDelta::minutes(2));
+
+        let (tx, set) = channel();
+        clock_tx
+            .send(ClockServiceMessage::SetDateTime(
+                DateTimeSetting::Local("2024-05-02T06:00:00".parse().unwrap()),
+                tx,
+            ))
+            .unwrap();
+        assert_eq!(set.recv_timeout(Duration::from_secs(1)).unwrap(), Ok(()));
+
+        assert_eq!(rtc.now(), "2024-05-02T06:00:00".parse().unwrap());
+        assert!(!get_status(&clock_tx).oscillator_stopped);
+        // Section alarm keeps the remaining 3 minutes
+        assert_eq!(rtc.alarm1(), NaiveTime::from_hms_opt(6, 3, 0));
+        assert_eq!(rtc.alarm2(), NaiveTime::from_hms_opt(6, 30, 0));
+
+        rtc.advance(TimeDelta::minutes(3));
+        assert!(matches!(
+            rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            WateringServiceMessage::SectionAlarmFired(1)
+        ));
+    }
+}
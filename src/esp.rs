@@ -0,0 +1,10 @@
+//! ESP32 implementations of the hardware traits
+
+pub mod moisture;
+pub mod mqtt;
+pub mod ntp;
+pub mod rain;
+pub mod rtc;
+pub mod storage;
+pub mod tls;
+pub mod valve;
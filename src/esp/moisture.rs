@@ -0,0 +1,72 @@
+//! Capacitive soil moisture probes on the ADC1 inputs, ADC2 is taken by Wi-Fi
+
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use esp_idf_svc::{
+    hal::{
+        adc::{
+            attenuation::DB_11,
+            oneshot::{config::AdcChannelConfig, AdcChannelDriver, AdcDriver},
+            ADC1,
+        },
+        gpio::{ADCPin, Gpio32, Gpio33, Gpio34, Gpio35, Gpio36, Gpio37, Gpio38, Gpio39},
+        peripheral::Peripheral,
+    },
+    sys::EspError,
+};
+
+use crate::moisture::MoistureProbe;
+
+/// ADC unit shared by all the probes
+pub type MoistureAdc = Arc<AdcDriver<'static, ADC1>>;
+
+pub struct EspMoistureProbe {
+    /// Channel driver is typed by its pin, the probes of different pins have to be the same type
+    read: Box<dyn FnMut() -> Result<u16, EspError> + Send>,
+}
+
+impl MoistureProbe for EspMoistureProbe {
+    fn read_mv(&mut self) -> Result<u16> {
+        Ok((self.read)()?)
+    }
+}
+
+pub fn moisture_adc(adc1: ADC1) -> Result<MoistureAdc> {
+    Ok(Arc::new(AdcDriver::new(adc1)?))
+}
+
+/// Takes the GPIO of the probe, SectionsConfig makes sure none of the sections uses it
+pub fn moisture_probe(adc: &MoistureAdc, gpio: i32) -> Result<EspMoistureProbe> {
+    // Safety: GPIO is not used by the RTC nor the valves, nor the other probes
+    unsafe {
+        match gpio {
+            32 => probe(adc, Gpio32::new()),
+            33 => probe(adc, Gpio33::new()),
+            34 => probe(adc, Gpio34::new()),
+            35 => probe(adc, Gpio35::new()),
+            36 => probe(adc, Gpio36::new()),
+            37 => probe(adc, Gpio37::new()),
+            38 => probe(adc, Gpio38::new()),
+            39 => probe(adc, Gpio39::new()),
+            _ => bail!("GPIO {gpio} is not an ADC1 input"),
+        }
+    }
+}
+
+fn probe<T>(adc: &MoistureAdc, pin: T) -> Result<EspMoistureProbe>
+where
+    T: ADCPin<Adc = ADC1> + Peripheral<P = T> + 'static,
+{
+    // Probe output goes up to ~3V, it needs the whole range
+    let config = AdcChannelConfig {
+        attenuation: DB_11,
+        calibration: true,
+        ..Default::default()
+    };
+    let mut channel = AdcChannelDriver::new(adc.clone(), pin, &config)?;
+
+    Ok(EspMoistureProbe {
+        read: Box::new(move || channel.read()),
+    })
+}
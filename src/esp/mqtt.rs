@@ -0,0 +1,103 @@
+//! MQTT client of ESP-IDF, it reconnects to the broker on its own
+
+use anyhow::{Context, Result};
+use esp_idf_svc::mqtt::client::{
+    Details, EspMqttClient, EspMqttConnection, EventPayload, LwtConfiguration,
+    MqttClientConfiguration, QoS,
+};
+use log::{info, warn};
+
+use crate::mqtt::{availability_topic, MqttClient, MqttServiceChannel, MqttServiceMessage};
+
+pub struct EspMqtt {
+    client: EspMqttClient<'static>,
+    /// Taken by the receiving thread
+    connection: Option<EspMqttConnection>,
+}
+
+impl EspMqtt {
+    /// `url` like `mqtt://192.168.68.2:1883`, empty `username` connects anonymously.
+    /// Broker marks the node `offline` when the connection is lost.
+    pub fn new(url: &str, username: &str, password: &str, node_id: &str) -> Result<Self> {
+        let availability = availability_topic(node_id);
+        let conf = MqttClientConfiguration {
+            client_id: Some(node_id),
+            username: (!username.is_empty()).then_some(username),
+            password: (!password.is_empty()).then_some(password),
+            lwt: Some(LwtConfiguration {
+                topic: &availability,
+                payload: b"offline",
+                qos: QoS::AtLeastOnce,
+                retain: true,
+            }),
+            ..Default::default()
+        };
+
+        let (client, connection) =
+            EspMqttClient::new(url, &conf).with_context(|| format!("while connecting to {url}"))?;
+
+        Ok(Self {
+            client,
+            connection: Some(connection),
+        })
+    }
+}
+
+impl MqttClient for EspMqtt {
+    fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) -> Result<()> {
+        self.client
+            .enqueue(topic, QoS::AtLeastOnce, retain, payload)
+            .with_context(|| format!("while publishing to {topic}"))?;
+        Ok(())
+    }
+
+    fn subscribe(&mut self, topic: &str) -> Result<()> {
+        self.client
+            .subscribe(topic, QoS::AtLeastOnce)
+            .with_context(|| format!("while subscribing to {topic}"))?;
+        Ok(())
+    }
+
+    fn start_receiving(&mut self, tx: MqttServiceChannel) -> Result<()> {
+        let mut connection = self
+            .connection
+            .take()
+            .context("MQTT messages are received already")?;
+
+        std::thread::Builder::new()
+            .name("mqtt connection".to_string())
+            .spawn(move || {
+                while let Ok(event) = connection.next() {
+                    let msg = match event.payload() {
+                        EventPayload::Connected(_) => MqttServiceMessage::Connected,
+                        EventPayload::Disconnected => {
+                            info!("Disconnected from the MQTT broker");
+                            continue;
+                        }
+                        // Commands are short, they never come in chunks
+                        EventPayload::Received {
+                            topic: Some(topic),
+                            data,
+                            details: Details::Complete,
+                            ..
+                        } => MqttServiceMessage::Received {
+                            topic: topic.to_string(),
+                            payload: data.to_vec(),
+                        },
+                        EventPayload::Error(e) => {
+                            warn!("MQTT error {e:?}");
+                            continue;
+                        }
+                        _ => continue,
+                    };
+
+                    if tx.send(msg).is_err() {
+                        break;
+                    }
+                }
+                info!("MQTT connection closed");
+            })?;
+
+        Ok(())
+    }
+}
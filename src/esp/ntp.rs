@@ -0,0 +1,54 @@
+//! SNTP service of ESP-IDF, it keeps the system time in sync in the background
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use chrono::{NaiveDateTime, Utc};
+use esp_idf_svc::sntp::{EspSntp, SntpConf};
+use log::info;
+
+use crate::clock::NetworkTime;
+
+/// ESP-IDF syncs every hour by default, older time is not better than the RTC one
+const MAX_SYNC_AGE: Duration = Duration::from_secs(2 * 60 * 60);
+
+pub struct EspNtp {
+    _sntp: EspSntp<'static>,
+    last_sync: Arc<Mutex<Option<Instant>>>,
+}
+
+impl EspNtp {
+    pub fn new(server: &str) -> Result<Self> {
+        let mut conf = SntpConf::default();
+        conf.servers[0] = server;
+
+        let last_sync = Arc::new(Mutex::new(None));
+        let sntp = EspSntp::new_with_callback(&conf, {
+            let last_sync = last_sync.clone();
+            move |since_epoch| {
+                info!("System time synced with {since_epoch:?} since epoch");
+                *last_sync.lock().unwrap() = Some(Instant::now());
+            }
+        })?;
+
+        Ok(Self {
+            _sntp: sntp,
+            last_sync,
+        })
+    }
+}
+
+impl NetworkTime for EspNtp {
+    fn now(&mut self) -> Result<NaiveDateTime> {
+        match *self.last_sync.lock().unwrap() {
+            None => bail!("System time is not synced yet"),
+            Some(at) if at.elapsed() > MAX_SYNC_AGE => {
+                bail!("System time was last synced {:?} ago", at.elapsed())
+            }
+            Some(_) => Ok(Utc::now().naive_utc()),
+        }
+    }
+}
@@ -0,0 +1,102 @@
+//! Normally-closed rain switch between the GPIO and the ground, the GPIO is pulled up:
+//! low while the switch is closed (dry), high once it opens (wet)
+
+use anyhow::{bail, Result};
+use esp_idf_svc::hal::{
+    delay,
+    gpio::{AnyIOPin, Input, InterruptType, PinDriver, Pull},
+    task::queue::Queue,
+};
+
+use crate::{
+    esp::valve::RESERVED_GPIOS,
+    rain::{RainSensor, RainServiceChannel, RainServiceMessage},
+    sections::SectionsConfig,
+};
+
+pub struct EspRainSensor {
+    pin: PinDriver<'static, AnyIOPin, Input>,
+}
+
+impl RainSensor for EspRainSensor {
+    fn is_wet(&mut self) -> Result<bool> {
+        Ok(self.pin.is_high())
+    }
+
+    /// Setup the pin interrupt handling: ISR -> interrupt-handler task -> RainService task,
+    /// the same way as the RTC interrupt
+    fn start_interrupt_service(&mut self, tx: RainServiceChannel) -> Result<()> {
+        let queue_isr = Queue::new(10);
+
+        // SAFETY: Owner of this queue is ISR, captured in a closure. Will never drop.
+        let queue_thread = unsafe { Queue::<u32>::new_borrowed(queue_isr.as_raw()) };
+
+        // Sensor getting wet and drying out both matter
+        self.pin.set_interrupt_type(InterruptType::AnyEdge)?;
+
+        // SAFETY: Using ISR-safe calls here
+        unsafe {
+            self.pin.subscribe(move || {
+                static mut INT_COUNT: u32 = 1;
+
+                // Interrupt is disabled until the service enables it again, the queue does not fill up
+                let high_prio_task_was_awoken = queue_isr
+                    .send_back(INT_COUNT, delay::NON_BLOCK)
+                    .expect("The rain interrupt queue is full!");
+                INT_COUNT += 1;
+
+                if high_prio_task_was_awoken {
+                    esp_idf_svc::hal::task::do_yield();
+                }
+            })?
+        };
+
+        std::thread::Builder::new()
+            .name("rain interrupt".to_string())
+            .spawn(move || {
+                log::info!("Hello from rain sensor interrupt task!");
+
+                while let Some((int_count, _)) = queue_thread.recv_front(delay::BLOCK) {
+                    log::debug!("Got rain sensor interrupt! #{int_count}");
+                    tx.send(RainServiceMessage::InterruptArrived(int_count))
+                        .expect("Cannot notify Rain service");
+                }
+            })?;
+
+        Ok(())
+    }
+
+    fn enable_interrupt(&mut self) -> Result<()> {
+        self.pin.enable_interrupt()?;
+        Ok(())
+    }
+}
+
+/// Takes the GPIO, it cannot be the one of the RTC or any of the sections
+pub fn rain_sensor(gpio: i32, sections: &SectionsConfig) -> Result<EspRainSensor> {
+    // GPIOs above 33 have no pull-up
+    if !(0..=33).contains(&gpio) || RESERVED_GPIOS.contains(&gpio) {
+        bail!("GPIO {gpio} cannot read the rain sensor");
+    }
+    if let Some(section) = sections
+        .sections()
+        .iter()
+        .find(|section| section.gpio == gpio)
+    {
+        bail!("GPIO {gpio} drives {} valve already", section.id);
+    }
+    if let Some(section) = sections.sections().iter().find(|section| {
+        section
+            .moisture_probe
+            .is_some_and(|probe| probe.gpio == gpio)
+    }) {
+        bail!("GPIO {gpio} reads {} moisture probe already", section.id);
+    }
+
+    // Safety: GPIO is not used by the RTC nor the valves
+    let pin = unsafe { AnyIOPin::new(gpio) };
+    let mut pin = PinDriver::input(pin)?;
+    pin.set_pull(Pull::Up)?;
+
+    Ok(EspRainSensor { pin })
+}
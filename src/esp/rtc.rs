@@ -0,0 +1,205 @@
+//! DS3231 connected over I2C, with the SQW/INT output wired to the GPIO
+
+use anyhow::{anyhow, Result};
+use chrono::{NaiveDateTime, NaiveTime};
+use ds323x::{ic::DS3231, interface::I2cInterface, DateTimeAccess, Ds323x};
+use esp_idf_svc::hal::{
+    delay,
+    gpio::{IOPin, Input, PinDriver},
+    i2c::{I2c, I2cConfig, I2cDriver},
+    peripheral::Peripheral,
+    prelude::*,
+    task::queue::Queue,
+};
+
+use crate::clock::{ClockServiceChannel, ClockServiceMessage, RealTimeClock};
+
+pub struct EspRtc<IntGPIO: IOPin> {
+    rtc: Ds323x<I2cInterface<I2cDriver<'static>>, DS3231>,
+    int_pin: PinDriver<'static, IntGPIO, Input>,
+}
+
+impl<IntGPIO: IOPin> EspRtc<IntGPIO> {
+    pub fn new(
+        sda_pin: impl Peripheral<P = impl IOPin> + 'static,
+        scl_pin: impl Peripheral<P = impl IOPin> + 'static,
+        int_pin: impl Peripheral<P = IntGPIO> + 'static,
+        i2c: impl Peripheral<P = impl I2c> + 'static,
+    ) -> Result<Self> {
+        // Configure RTC I2C driver
+        let config = I2cConfig::new().baudrate(400.kHz().into());
+        let i2c_dev = I2cDriver::new(i2c, sda_pin, scl_pin, &config)?;
+
+        let mut rtc = Ds323x::new_ds3231(i2c_dev);
+
+        // This pin is unused
+        rtc.disable_32khz_output()
+            .map_err(|e| anyhow!("Cannot disable 32khz output {e:?}"))?;
+
+        rtc.use_int_sqw_output_as_interrupt()
+            .map_err(|e| anyhow!("Cannot set sqw as interrupt {e:?}"))?;
+
+        // Configure INT GPIO, the SQW output pin of the RTC is connected to it
+        let mut int_pin = PinDriver::input(int_pin)?;
+
+        int_pin.set_pull(esp_idf_svc::hal::gpio::Pull::Down)?;
+
+        Ok(Self { rtc, int_pin })
+    }
+}
+
+impl<IntGPIO: IOPin> RealTimeClock for EspRtc<IntGPIO> {
+    fn datetime(&mut self) -> Result<NaiveDateTime> {
+        self.rtc
+            .datetime()
+            .map_err(|e| anyhow!("Failed to read current datetime {e:?}"))
+    }
+
+    fn set_datetime(&mut self, datetime: NaiveDateTime) -> Result<()> {
+        self.rtc
+            .set_datetime(&datetime)
+            .map_err(|e| anyhow!("Failed to set datetime {e:?}"))
+    }
+
+    fn has_been_stopped(&mut self) -> Result<bool> {
+        self.rtc
+            .has_been_stopped()
+            .map_err(|e| anyhow!("Failed to read oscillator stop flag {e:?}"))
+    }
+
+    fn clear_has_been_stopped_flag(&mut self) -> Result<()> {
+        self.rtc
+            .clear_has_been_stopped_flag()
+            .map_err(|e| anyhow!("Cannot clear oscillator stop flag {e:?}"))
+    }
+
+    fn temperature(&mut self) -> Result<f32> {
+        self.rtc
+            .temperature()
+            .map_err(|e| anyhow!("Failed to read temperature {e:?}"))
+    }
+
+    fn set_alarm1_hms(&mut self, when: NaiveTime) -> Result<()> {
+        self.rtc
+            .set_alarm1_hms(when)
+            .map_err(|e| anyhow!("Cannot set alarm1 {e:?}"))
+    }
+
+    fn enable_alarm1_interrupts(&mut self) -> Result<()> {
+        self.rtc
+            .enable_alarm1_interrupts()
+            .map_err(|e| anyhow!("Cannot enable alarm1 INT {e:?}"))
+    }
+
+    fn disable_alarm1_interrupts(&mut self) -> Result<()> {
+        self.rtc
+            .disable_alarm1_interrupts()
+            .map_err(|e| anyhow!("Cannot disable alarm1 INT {e:?}"))
+    }
+
+    fn has_alarm1_matched(&mut self) -> Result<bool> {
+        self.rtc
+            .has_alarm1_matched()
+            .map_err(|e| anyhow!("Cannot read alarm1 flag {e:?}"))
+    }
+
+    fn clear_alarm1_matched_flag(&mut self) -> Result<()> {
+        self.rtc
+            .clear_alarm1_matched_flag()
+            .map_err(|e| anyhow!("Cannot clear alarm1 flag {e:?}"))
+    }
+
+    fn set_alarm2_hm(&mut self, when: NaiveTime) -> Result<()> {
+        self.rtc
+            .set_alarm2_hm(when)
+            .map_err(|e| anyhow!("Cannot set alarm2 {e:?}"))
+    }
+
+    fn enable_alarm2_interrupts(&mut self) -> Result<()> {
+        self.rtc
+            .enable_alarm2_interrupts()
+            .map_err(|e| anyhow!("Cannot enable alarm2 INT {e:?}"))
+    }
+
+    fn disable_alarm2_interrupts(&mut self) -> Result<()> {
+        self.rtc
+            .disable_alarm2_interrupts()
+            .map_err(|e| anyhow!("Cannot disable alarm2 INT {e:?}"))
+    }
+
+    fn has_alarm2_matched(&mut self) -> Result<bool> {
+        self.rtc
+            .has_alarm2_matched()
+            .map_err(|e| anyhow!("Cannot read alarm2 flag {e:?}"))
+    }
+
+    fn clear_alarm2_matched_flag(&mut self) -> Result<()> {
+        self.rtc
+            .clear_alarm2_matched_flag()
+            .map_err(|e| anyhow!("Cannot clear alarm2 flag {e:?}"))
+    }
+
+    /// Setup RTC interrupt handling: ISR -> interrupt-handler task -> ClockService task
+    fn start_interrupt_service(&mut self, tx: ClockServiceChannel) -> Result<()> {
+        // Communicate from ISR with task using FreeRTOS queue.
+        // Alternative is to use Notification - this one is however bounded to the task,
+        // and cannot be moved across threads.
+
+        // ISR part, will use it to push back notifications
+        let queue_isr = Queue::new(10);
+
+        // Thread part, will pop front notifications
+        // SAFETY: Owner of this queue is ISR, captured in a closure. Will never drop.
+        let queue_thread = unsafe { Queue::<u32>::new_borrowed(queue_isr.as_raw()) };
+
+        // INT pin on RTC is high by default, listen on falling edge
+        self.int_pin
+            .set_interrupt_type(esp_idf_svc::hal::gpio::InterruptType::NegEdge)?;
+
+        // Start listening on interrupt, set ISR that pushes interrupt notifications to the queue
+        // SAFETY: Using ISR-safe calls here
+        unsafe {
+            self.int_pin.subscribe(move || {
+                static mut INT_COUNT: u32 = 1;
+
+                let high_prio_task_was_awoken = queue_isr
+                    .send_back(INT_COUNT, delay::NON_BLOCK)
+                    .expect("The interrupt queue is full!");
+                INT_COUNT += 1;
+
+                if high_prio_task_was_awoken {
+                    // This is FreeRTOS detail:
+                    // Context switch should be performed before the interrupt is exited. This will ensure that the
+                    // interrupt returns directly to the highest priority Ready state task
+                    esp_idf_svc::hal::task::do_yield();
+                }
+            })?
+        };
+
+        // Create interrupt-handler task, will communicate with the Clock service when interrupt arrive
+        // This is a thin wrapper over the FreeRTOS task
+        // TODO: that thread might be redundant if embassy channels are safe to call from ISR.
+        // The std::sync::mpsc channels are not. Therefore current solution uses ISR-safe primitive (FreeRTOS queue)
+        // to communicate with following thread, and this thread finally communicates with the Clock service using mpsc channel.
+        std::thread::Builder::new()
+            .name("rtc interrupt".to_string())
+            .spawn(move || {
+                log::info!("Hello from RTC interrupt task!");
+
+                // Receive interrupt from ISR
+                while let Some((int_count, _)) = queue_thread.recv_front(delay::BLOCK) {
+                    log::debug!("Got interrupt notification! #{int_count}");
+                    // Pass it to the service
+                    tx.send(ClockServiceMessage::InterruptArrived(int_count))
+                        .expect("Cannot notify Clock service");
+                }
+            })?;
+
+        Ok(())
+    }
+
+    fn enable_interrupt(&mut self) -> Result<()> {
+        self.int_pin.enable_interrupt()?;
+        Ok(())
+    }
+}
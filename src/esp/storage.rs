@@ -0,0 +1,44 @@
+//! Storage in the NVS partition of the flash
+
+use anyhow::Result;
+use esp_idf_svc::nvs::{EspDefaultNvs, EspDefaultNvsPartition};
+
+use crate::storage::Storage;
+
+pub struct EspStorage {
+    nvs: EspDefaultNvs,
+}
+
+impl EspStorage {
+    /// Namespace separates keys of different services, cannot be longer than 15 characters
+    pub fn new(partition: EspDefaultNvsPartition, namespace: &str) -> Result<Self> {
+        Ok(Self {
+            nvs: EspDefaultNvs::new(partition, namespace, true)?,
+        })
+    }
+}
+
+impl Storage for EspStorage {
+    fn get(&mut self, key: &str) -> Result<Option<Vec<u8>>> {
+        let Some(len) = self.nvs.blob_len(key)? else {
+            return Ok(None);
+        };
+
+        let mut buf = vec![0; len];
+        let value = self
+            .nvs
+            .get_blob(key, &mut buf)?
+            .map(|value| value.to_vec());
+        Ok(value)
+    }
+
+    fn set(&mut self, key: &str, value: &[u8]) -> Result<()> {
+        self.nvs.set_blob(key, value)?;
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> Result<()> {
+        self.nvs.remove(key)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,143 @@
+//! Self-signed certificate generated on the board by mbedtls, the one ESP-IDF uses for TLS anyway
+
+use std::ffi::{c_int, c_uchar, c_void, CStr, CString};
+
+use anyhow::{bail, Result};
+use esp_idf_svc::sys::{
+    mbedtls_ecp_gen_key, mbedtls_ecp_group_id_MBEDTLS_ECP_DP_SECP256R1, mbedtls_ecp_keypair,
+    mbedtls_md_type_t_MBEDTLS_MD_SHA256, mbedtls_pk_context, mbedtls_pk_free,
+    mbedtls_pk_info_from_type, mbedtls_pk_init, mbedtls_pk_setup,
+    mbedtls_pk_type_t_MBEDTLS_PK_ECKEY, mbedtls_pk_write_key_pem, mbedtls_x509write_cert,
+    mbedtls_x509write_crt_free, mbedtls_x509write_crt_init, mbedtls_x509write_crt_pem,
+    mbedtls_x509write_crt_set_basic_constraints, mbedtls_x509write_crt_set_issuer_key,
+    mbedtls_x509write_crt_set_issuer_name, mbedtls_x509write_crt_set_md_alg,
+    mbedtls_x509write_crt_set_serial_raw, mbedtls_x509write_crt_set_subject_key,
+    mbedtls_x509write_crt_set_subject_name, mbedtls_x509write_crt_set_validity,
+    mbedtls_x509write_crt_set_version, MBEDTLS_X509_CRT_VERSION_3,
+};
+
+use crate::tls::TlsIdentity;
+
+/// Enough for the P-256 certificate and key in PEM
+const PEM_BUF_LEN: usize = 2048;
+/// The board does not know the time when it generates the certificate, make it valid for long
+const NOT_BEFORE: &CStr = c"20240101000000";
+const NOT_AFTER: &CStr = c"20491231235959";
+
+extern "C" {
+    /// Hardware RNG, seeded from the RF noise once the Wi-Fi is up
+    fn esp_fill_random(buf: *mut c_void, len: usize);
+}
+
+extern "C" fn random(_ctx: *mut c_void, buf: *mut c_uchar, len: usize) -> c_int {
+    unsafe { esp_fill_random(buf as _, len) };
+    0
+}
+
+/// Fails with the name of the mbedtls call that returned the error
+fn check(call: &str, ret: c_int) -> Result<()> {
+    if ret != 0 {
+        bail!("{call} failed with -0x{:04x}", -ret);
+    }
+    Ok(())
+}
+
+/// PEM written by mbedtls into the buffer, up to the terminating nul
+fn pem(buf: &[u8]) -> Result<String> {
+    Ok(CStr::from_bytes_until_nul(buf)?.to_str()?.to_string())
+}
+
+/// Self-signed certificate for `common_name`, with a P-256 key
+pub fn self_signed_identity(common_name: &str) -> Result<TlsIdentity> {
+    let name = CString::new(format!("CN={common_name}"))?;
+    let mut key = mbedtls_pk_context::default();
+    let mut crt = mbedtls_x509write_cert::default();
+
+    unsafe {
+        mbedtls_pk_init(&mut key);
+        mbedtls_x509write_crt_init(&mut crt);
+    }
+
+    let identity = unsafe { write_identity(&mut key, &mut crt, &name) };
+
+    unsafe {
+        mbedtls_x509write_crt_free(&mut crt);
+        mbedtls_pk_free(&mut key);
+    }
+
+    identity
+}
+
+/// `key` and `crt` are initialized, the caller frees them
+unsafe fn write_identity(
+    key: &mut mbedtls_pk_context,
+    crt: &mut mbedtls_x509write_cert,
+    name: &CStr,
+) -> Result<TlsIdentity> {
+    check(
+        "mbedtls_pk_setup",
+        mbedtls_pk_setup(
+            key,
+            mbedtls_pk_info_from_type(mbedtls_pk_type_t_MBEDTLS_PK_ECKEY),
+        ),
+    )?;
+    check(
+        "mbedtls_ecp_gen_key",
+        mbedtls_ecp_gen_key(
+            mbedtls_ecp_group_id_MBEDTLS_ECP_DP_SECP256R1,
+            key.private_pk_ctx as *mut mbedtls_ecp_keypair,
+            Some(random),
+            std::ptr::null_mut(),
+        ),
+    )?;
+
+    let mut serial = [0u8; 16];
+    random(std::ptr::null_mut(), serial.as_mut_ptr(), serial.len());
+    // Serial number is positive
+    serial[0] &= 0x7f;
+
+    mbedtls_x509write_crt_set_version(crt, MBEDTLS_X509_CRT_VERSION_3 as _);
+    mbedtls_x509write_crt_set_md_alg(crt, mbedtls_md_type_t_MBEDTLS_MD_SHA256);
+    mbedtls_x509write_crt_set_subject_key(crt, key);
+    mbedtls_x509write_crt_set_issuer_key(crt, key);
+    check(
+        "mbedtls_x509write_crt_set_subject_name",
+        mbedtls_x509write_crt_set_subject_name(crt, name.as_ptr()),
+    )?;
+    check(
+        "mbedtls_x509write_crt_set_issuer_name",
+        mbedtls_x509write_crt_set_issuer_name(crt, name.as_ptr()),
+    )?;
+    check(
+        "mbedtls_x509write_crt_set_serial_raw",
+        mbedtls_x509write_crt_set_serial_raw(crt, serial.as_mut_ptr(), serial.len()),
+    )?;
+    check(
+        "mbedtls_x509write_crt_set_validity",
+        mbedtls_x509write_crt_set_validity(crt, NOT_BEFORE.as_ptr(), NOT_AFTER.as_ptr()),
+    )?;
+    check(
+        "mbedtls_x509write_crt_set_basic_constraints",
+        mbedtls_x509write_crt_set_basic_constraints(crt, 0, -1),
+    )?;
+
+    let mut certificate = vec![0u8; PEM_BUF_LEN];
+    check(
+        "mbedtls_x509write_crt_pem",
+        mbedtls_x509write_crt_pem(
+            crt,
+            certificate.as_mut_ptr(),
+            certificate.len(),
+            Some(random),
+            std::ptr::null_mut(),
+        ),
+    )?;
+
+    let mut private_key = vec![0u8; PEM_BUF_LEN];
+    check(
+        "mbedtls_pk_write_key_pem",
+        mbedtls_pk_write_key_pem(key, private_key.as_mut_ptr(), private_key.len()),
+    )?;
+
+    TlsIdentity::from_pem(&pem(&certificate)?, &pem(&private_key)?)
+}
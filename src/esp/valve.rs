@@ -0,0 +1,76 @@
+//! Section valve relay driven directly by the GPIO
+
+use anyhow::{bail, Result};
+use esp_idf_svc::{
+    hal::gpio::{AnyOutputPin, Output, PinDriver},
+    sys::gpio_set_level,
+};
+
+use crate::sections::{SectionConfig, ValveCloser, ValveDriver};
+
+/// GPIOs used by the RTC, cannot drive the valves
+pub(crate) const RESERVED_GPIOS: [i32; 3] = [21, 22, 23];
+
+pub struct EspValve {
+    pin: PinDriver<'static, AnyOutputPin, Output>,
+    /// Relay opens the valve on the low output
+    active_low: bool,
+}
+
+impl EspValve {
+    fn closed_level(&self) -> u32 {
+        u32::from(self.active_low)
+    }
+}
+
+impl ValveDriver for EspValve {
+    fn open(&mut self) -> Result<()> {
+        if self.active_low {
+            self.pin.set_low()?;
+        } else {
+            self.pin.set_high()?;
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        if self.active_low {
+            self.pin.set_high()?;
+        } else {
+            self.pin.set_low()?;
+        }
+        Ok(())
+    }
+
+    fn is_open(&self) -> bool {
+        self.pin.is_set_high() != self.active_low
+    }
+
+    fn emergency_closer(&self) -> ValveCloser {
+        let pin = self.pin.pin();
+        let level = self.closed_level();
+        // Driver is not needed to drive already configured output
+        Box::new(move || unsafe {
+            gpio_set_level(pin, level);
+        })
+    }
+}
+
+/// Takes the GPIO of the section, so every section in the configuration has to use a different one
+pub fn valve(section: &SectionConfig) -> Result<EspValve> {
+    // GPIOs above 33 are input only
+    if !(0..=33).contains(&section.gpio) || RESERVED_GPIOS.contains(&section.gpio) {
+        bail!("GPIO {} cannot drive {} valve", section.gpio, section.id);
+    }
+
+    // Safety: sections use distinct GPIOs, none of them is used by other peripherals
+    let pin = unsafe { AnyOutputPin::new(section.gpio) };
+    let mut valve = EspValve {
+        pin: PinDriver::output(pin)?,
+        active_low: section.active_low,
+    };
+    // Output starts low, that opens active low valve
+    valve.close()?;
+
+    Ok(valve)
+}
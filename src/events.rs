@@ -0,0 +1,165 @@
+//! Live events of the controller, broadcast to everyone subscribed, e.g. the `/events` stream
+
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use chrono::NaiveTime;
+use log::info;
+use serde::Serialize;
+
+use crate::sections::SectionId;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    ValveOpened {
+        section: SectionId,
+    },
+    ValveClosed {
+        section: SectionId,
+    },
+    SectionAlarmFired,
+    WateringAlarmFired,
+    /// Watering alarm got armed for the program, or disarmed
+    ScheduleChanged {
+        next_program: Option<String>,
+        at: Option<NaiveTime>,
+    },
+    /// Something went wrong and got handled, e.g. valve closed by the watchdog
+    Fault {
+        section: Option<SectionId>,
+        reason: String,
+    },
+    /// Rain sensor got wet, or dried out
+    RainChanged {
+        wet: bool,
+    },
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::ValveOpened { .. } => "valve_opened",
+            Event::ValveClosed { .. } => "valve_closed",
+            Event::SectionAlarmFired => "section_alarm_fired",
+            Event::WateringAlarmFired => "watering_alarm_fired",
+            Event::ScheduleChanged { .. } => "schedule_changed",
+            Event::Fault { .. } => "fault",
+            Event::RainChanged { .. } => "rain_changed",
+        }
+    }
+
+    /// Server-Sent Events frame, the event name and JSON data
+    pub fn to_sse(&self) -> String {
+        let data = serde_json::to_string(self).expect("Event is always valid JSON");
+        format!("event: {}\ndata: {data}\n\n", self.name())
+    }
+}
+
+pub enum EventsServiceMessage {
+    Publish(Event),
+    /// Subscriber gets all the events published from now on, until it drops the receiver
+    Subscribe(Sender<Event>),
+}
+pub type EventsServiceChannel = Sender<EventsServiceMessage>;
+
+/// Services publish the events through it, events go nowhere until the events service is attached
+#[derive(Clone, Default)]
+pub struct Publisher(Option<EventsServiceChannel>);
+
+impl Publisher {
+    pub fn new(events_tx: EventsServiceChannel) -> Self {
+        Self(Some(events_tx))
+    }
+
+    pub fn publish(&self, event: Event) {
+        if let Some(events_tx) = &self.0 {
+            // Events are best effort, nothing depends on them
+            let _ = events_tx.send(EventsServiceMessage::Publish(event));
+        }
+    }
+}
+
+/// Subscribes for the events, None if the events service is gone
+pub fn subscribe(events_tx: &EventsServiceChannel) -> Option<Receiver<Event>> {
+    let (tx, rx) = channel();
+    events_tx.send(EventsServiceMessage::Subscribe(tx)).ok()?;
+    Some(rx)
+}
+
+#[derive(Default)]
+pub struct EventsService {
+    subscribers: Vec<Sender<Event>>,
+}
+
+impl EventsService {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts the Events Service, returns the EventsServiceChannel to communicate with it
+    pub fn start(mut self) -> EventsServiceChannel {
+        let (tx, rx) = channel();
+
+        std::thread::Builder::new()
+            .name("events service".to_string())
+            .spawn(move || {
+                info!("Hello from Events service!");
+
+                while let Ok(msg) = rx.recv() {
+                    match msg {
+                        EventsServiceMessage::Publish(event) => {
+                            // Try to send to the subscribers, if fails, it means rx "unsubscribed", filter such entries
+                            self.subscribers.retain(|tx| tx.send(event.clone()).is_ok());
+                        }
+                        EventsServiceMessage::Subscribe(tx) => self.subscribers.push(tx),
+                    }
+                }
+            })
+            .expect("Cannot spawn Events service");
+
+        tx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn events_are_broadcast_to_subscribers() {
+        let events_tx = EventsService::new().start();
+        let publisher = Publisher::new(events_tx.clone());
+
+        let first = subscribe(&events_tx).unwrap();
+        let second = subscribe(&events_tx).unwrap();
+        publisher.publish(Event::WateringAlarmFired);
+
+        for rx in [&first, &second] {
+            assert_eq!(
+                rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+                Event::WateringAlarmFired
+            );
+        }
+
+        // Subscriber that went away does not stop the others
+        drop(first);
+        publisher.publish(Event::SectionAlarmFired);
+        assert_eq!(
+            second.recv_timeout(Duration::from_secs(1)).unwrap(),
+            Event::SectionAlarmFired
+        );
+    }
+
+    #[test]
+    fn event_is_sse_frame() {
+        let event = Event::ValveOpened {
+            section: SectionId::from("Grass"),
+        };
+        assert_eq!(
+            event.to_sse(),
+            "event: valve_opened\ndata: {\"event\":\"valve_opened\",\"section\":\"Grass\"}\n\n"
+        );
+    }
+}
@@ -0,0 +1,94 @@
+//! Last line of defense: valves get closed when any of the services panics,
+//! and the panic reason survives the reset
+
+use std::{any::Any, panic::Location, sync::Mutex};
+
+use anyhow::Result;
+
+use crate::{sections::ValveCloser, storage::Storage};
+
+const STORAGE_KEY: &str = "panic";
+
+/// Closes all the valves and stores the panic reason, then lets the previous hook report the panic
+pub fn install_panic_hook(closers: Vec<ValveCloser>, storage: Box<dyn Storage>) {
+    let storage = Mutex::new(storage);
+    let previous = std::panic::take_hook();
+
+    std::panic::set_hook(Box::new(move |info| {
+        for close in &closers {
+            close();
+        }
+
+        let reason = panic_reason(info.payload(), info.location());
+        // Do not wait for the storage, panicking thread might be the one holding it
+        if let Ok(mut storage) = storage.try_lock() {
+            let _ = storage.as_mut().store(STORAGE_KEY, &reason);
+        }
+
+        previous(info);
+    }));
+}
+
+/// Panic reason recorded before the reset, it is reported only once
+pub fn take_last_panic(storage: &mut dyn Storage) -> Result<Option<String>> {
+    let reason = storage.load(STORAGE_KEY)?;
+    if reason.is_some() {
+        storage.remove(STORAGE_KEY)?;
+    }
+
+    Ok(reason)
+}
+
+fn panic_reason(payload: &(dyn Any + Send), location: Option<&Location>) -> String {
+    let thread = std::thread::current();
+    let thread = thread.name().unwrap_or("unnamed thread");
+
+    let message = payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown reason");
+
+    match location {
+        Some(location) => format!("panic in {thread} at {location}: {message}"),
+        None => format!("panic in {thread}: {message}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        host::{FakeValve, MemoryStorage},
+        sections::ValveDriver,
+    };
+
+    use super::*;
+
+    #[test]
+    fn panic_closes_valves_and_is_recorded() {
+        let mut valve = FakeValve::default();
+        valve.open().unwrap();
+
+        let mut storage = MemoryStorage::default();
+        install_panic_hook(vec![valve.emergency_closer()], Box::new(storage.clone()));
+
+        let result = std::thread::Builder::new()
+            .name("watering service".to_string())
+            .spawn(|| panic!("valve stuck"))
+            .unwrap()
+            .join();
+        assert!(result.is_err());
+
+        assert!(!valve.is_open());
+
+        let reason = take_last_panic(&mut storage).unwrap().unwrap();
+        assert!(
+            reason.starts_with("panic in watering service at src/fail_safe.rs:"),
+            "{reason}"
+        );
+        assert!(reason.ends_with(": valve stuck"), "{reason}");
+
+        // Reported only once
+        assert_eq!(take_last_panic(&mut storage).unwrap(), None);
+    }
+}
@@ -0,0 +1,218 @@
+//! Log of what the watering actually did, the most recent entries survive the power cut
+
+use std::collections::VecDeque;
+
+use anyhow::Result;
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+
+use crate::{sections::SectionId, storage::Storage};
+
+/// Oldest entries are dropped, the whole log is a single NVS blob
+pub const MAX_ENTRIES: usize = 50;
+
+/// Why the section got closed
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EndReason {
+    /// Section alarm fired, the section got its whole duration
+    Alarm,
+    /// User closed it, or switched to another section
+    Manual,
+    /// Ad-hoc watering gave way to the scheduled one
+    Schedule,
+    /// Section alarm did not arrive in time
+    Watchdog,
+    /// Rain sensor got wet during the run
+    Rain,
+}
+
+/// Why the program did not run when it was due
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    /// Scheduled runs are paused by the user
+    RainDelay,
+    /// Rain sensor is wet
+    Rain,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum HistoryEvent {
+    SectionWatered {
+        section: SectionId,
+        /// None for the ad-hoc watering
+        program: Option<String>,
+        started_at: NaiveDateTime,
+        duration_seconds: i64,
+        ended_by: EndReason,
+    },
+    /// Program was due, but none of its sections got watered
+    RunSkipped { program: String, reason: SkipReason },
+    /// Section's turn in the program got skipped, its soil moisture in percent was above the threshold
+    SectionSkipped {
+        section: SectionId,
+        program: String,
+        moisture: u8,
+    },
+}
+
+impl HistoryEvent {
+    /// None for the events of the whole program
+    pub fn section(&self) -> Option<&SectionId> {
+        match self {
+            HistoryEvent::SectionWatered { section, .. }
+            | HistoryEvent::SectionSkipped { section, .. } => Some(section),
+            HistoryEvent::RunSkipped { .. } => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// Local time the event ended at
+    pub at: NaiveDateTime,
+    #[serde(flatten)]
+    pub event: HistoryEvent,
+}
+
+/// Entries matching all the given criteria
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HistoryQuery {
+    /// Entries at or after that local time
+    pub since: Option<NaiveDateTime>,
+    pub section: Option<SectionId>,
+}
+
+/// Ring buffer of the entries, oldest first
+#[derive(Debug, Default)]
+pub struct History {
+    entries: VecDeque<HistoryEntry>,
+}
+
+impl History {
+    const STORAGE_KEY: &'static str = "history";
+
+    /// Empty history if nothing got stored yet
+    pub fn load(storage: &mut dyn Storage) -> Result<Self> {
+        Ok(Self {
+            entries: storage.load(Self::STORAGE_KEY)?.unwrap_or_default(),
+        })
+    }
+
+    /// Appends the entry and stores the whole log, entry is kept in memory even if storing fails
+    pub fn record(&mut self, entry: HistoryEntry, storage: &mut dyn Storage) -> Result<()> {
+        if self.entries.len() == MAX_ENTRIES {
+            let _ = self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+
+        storage.store(Self::STORAGE_KEY, &self.entries)
+    }
+
+    pub fn query(&self, query: &HistoryQuery) -> Vec<HistoryEntry> {
+        self.entries
+            .iter()
+            .filter(|entry| query.since.map_or(true, |since| entry.at >= since))
+            .filter(|entry| {
+                query
+                    .section
+                    .as_ref()
+                    .map_or(true, |section| entry.event.section() == Some(section))
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use crate::host::MemoryStorage;
+
+    use super::*;
+
+    fn watered(section: &str, hour: u32) -> HistoryEntry {
+        let started_at = NaiveDate::from_ymd_opt(2024, 5, 1)
+            .unwrap()
+            .and_hms_opt(hour, 0, 0)
+            .unwrap();
+        HistoryEntry {
+            at: started_at + chrono::TimeDelta::minutes(20),
+            event: HistoryEvent::SectionWatered {
+                section: SectionId::from(section),
+                program: Some("default".to_string()),
+                started_at,
+                duration_seconds: 20 * 60,
+                ended_by: EndReason::Alarm,
+            },
+        }
+    }
+
+    #[test]
+    fn history_keeps_most_recent_entries_across_reboots() {
+        let mut storage = MemoryStorage::default();
+        let mut history = History::load(&mut storage).unwrap();
+
+        for hour in 0..MAX_ENTRIES as u32 + 2 {
+            history
+                .record(watered("Grass", hour % 24), &mut storage)
+                .unwrap();
+        }
+
+        let entries = History::load(&mut storage)
+            .unwrap()
+            .query(&HistoryQuery::default());
+        assert_eq!(entries.len(), MAX_ENTRIES);
+        // Two oldest are gone
+        assert_eq!(entries[0], watered("Grass", 2));
+    }
+
+    #[test]
+    fn history_is_filtered_by_time_and_section() {
+        let mut storage = MemoryStorage::default();
+        let mut history = History::default();
+        for (section, hour) in [("Vegs", 6), ("Grass", 6), ("Vegs", 20), ("Grass", 20)] {
+            history
+                .record(watered(section, hour), &mut storage)
+                .unwrap();
+        }
+
+        let query = HistoryQuery {
+            since: Some(watered("Grass", 20).at),
+            section: None,
+        };
+        assert_eq!(
+            history.query(&query),
+            vec![watered("Vegs", 20), watered("Grass", 20)]
+        );
+
+        let query = HistoryQuery {
+            since: None,
+            section: Some(SectionId::from("Grass")),
+        };
+        assert_eq!(
+            history.query(&query),
+            vec![watered("Grass", 6), watered("Grass", 20)]
+        );
+    }
+
+    #[test]
+    fn entry_is_flat_json() {
+        let json = serde_json::to_value(watered("Grass", 20)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "at": "2024-05-01T20:20:00",
+                "event": "section_watered",
+                "section": "Grass",
+                "program": "default",
+                "started_at": "2024-05-01T20:00:00",
+                "duration_seconds": 1200,
+                "ended_by": "alarm",
+            })
+        );
+    }
+}
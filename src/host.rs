@@ -0,0 +1,33 @@
+//! Host implementations of the hardware traits, used by the tests and the simulator
+
+use std::time::{Duration, Instant};
+
+pub mod moisture;
+pub mod mqtt;
+pub mod ntp;
+pub mod rain;
+pub mod rtc;
+pub mod storage;
+pub mod valve;
+
+pub use moisture::FakeMoistureProbe;
+pub use mqtt::{FakeBroker, FakeMqttClient};
+pub use ntp::{FakeNtpServer, SntpClient};
+pub use rain::FakeRainSensor;
+pub use rtc::FakeRtc;
+pub use storage::{FileStorage, MemoryStorage};
+pub use valve::FakeValve;
+
+/// Services run on their own threads, poll until `condition` holds or `timeout` elapses
+pub fn wait_until(timeout: Duration, condition: impl Fn() -> bool) -> bool {
+    let deadline = Instant::now() + timeout;
+
+    while Instant::now() < deadline {
+        if condition() {
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(5));
+    }
+
+    condition()
+}
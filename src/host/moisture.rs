@@ -0,0 +1,35 @@
+//! Moisture probe with the output the test sets
+
+use std::sync::{
+    atomic::{AtomicU16, Ordering},
+    Arc,
+};
+
+use anyhow::Result;
+
+use crate::moisture::MoistureProbe;
+
+/// Clones share the same probe, so the test can drive the one moved into the Moisture service
+#[derive(Clone, Debug, Default)]
+pub struct FakeMoistureProbe {
+    mv: Arc<AtomicU16>,
+}
+
+impl FakeMoistureProbe {
+    pub fn new(mv: u16) -> Self {
+        Self {
+            mv: Arc::new(AtomicU16::new(mv)),
+        }
+    }
+
+    /// Soil got wetter or drier, 0 is the disconnected probe
+    pub fn set_mv(&self, mv: u16) {
+        self.mv.store(mv, Ordering::SeqCst);
+    }
+}
+
+impl MoistureProbe for FakeMoistureProbe {
+    fn read_mv(&mut self) -> Result<u16> {
+        Ok(self.mv.load(Ordering::SeqCst))
+    }
+}
@@ -0,0 +1,136 @@
+//! In-process MQTT broker standing in for Mosquitto in the tests
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+
+use crate::mqtt::{topic_matches, MqttClient, MqttServiceChannel, MqttServiceMessage};
+
+#[derive(Default)]
+struct Subscriber {
+    filters: Vec<String>,
+    /// None until the client starts receiving
+    tx: Option<MqttServiceChannel>,
+}
+
+#[derive(Default)]
+struct BrokerState {
+    retained: BTreeMap<String, Vec<u8>>,
+    subscribers: Vec<Subscriber>,
+}
+
+impl BrokerState {
+    fn route(&mut self, topic: &str, payload: &[u8], retain: bool) {
+        if retain {
+            let _ = self.retained.insert(topic.to_string(), payload.to_vec());
+        }
+
+        for subscriber in &self.subscribers {
+            let Some(tx) = &subscriber.tx else {
+                continue;
+            };
+            if subscriber
+                .filters
+                .iter()
+                .any(|filter| topic_matches(filter, topic))
+            {
+                // Client might be gone already, the same as the disconnected one
+                let _ = tx.send(MqttServiceMessage::Received {
+                    topic: topic.to_string(),
+                    payload: payload.to_vec(),
+                });
+            }
+        }
+    }
+}
+
+/// Clones share the same broker, so the test can play Home Assistant on the other side
+#[derive(Clone, Default)]
+pub struct FakeBroker {
+    state: Arc<Mutex<BrokerState>>,
+}
+
+impl FakeBroker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// New client connection
+    pub fn client(&self) -> FakeMqttClient {
+        let mut state = self.state.lock().unwrap();
+        state.subscribers.push(Subscriber::default());
+        FakeMqttClient {
+            broker: self.clone(),
+            id: state.subscribers.len() - 1,
+        }
+    }
+
+    /// Retained message of the topic, as the text
+    pub fn retained(&self, topic: &str) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        let payload = state.retained.get(topic)?;
+        Some(String::from_utf8_lossy(payload).into_owned())
+    }
+
+    /// Topics with the retained messages matching the filter
+    pub fn retained_topics(&self, filter: &str) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state
+            .retained
+            .keys()
+            .filter(|topic| topic_matches(filter, topic))
+            .cloned()
+            .collect()
+    }
+
+    /// As if some other client published it, e.g. Home Assistant sending a command
+    pub fn publish(&self, topic: &str, payload: &[u8]) {
+        self.state.lock().unwrap().route(topic, payload, false);
+    }
+
+    /// Broker went away and came back empty: retained messages and subscriptions are gone,
+    /// clients connect again
+    pub fn restart(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.retained.clear();
+        for subscriber in &mut state.subscribers {
+            subscriber.filters.clear();
+            if let Some(tx) = &subscriber.tx {
+                let _ = tx.send(MqttServiceMessage::Connected);
+            }
+        }
+    }
+}
+
+pub struct FakeMqttClient {
+    broker: FakeBroker,
+    id: usize,
+}
+
+impl FakeMqttClient {
+    fn with_state<T>(&mut self, f: impl FnOnce(&mut BrokerState) -> T) -> Result<T> {
+        Ok(f(&mut self.broker.state.lock().unwrap()))
+    }
+}
+
+impl MqttClient for FakeMqttClient {
+    fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) -> Result<()> {
+        self.with_state(|state| state.route(topic, payload, retain))
+    }
+
+    fn subscribe(&mut self, topic: &str) -> Result<()> {
+        let id = self.id;
+        self.with_state(|state| state.subscribers[id].filters.push(topic.to_string()))
+    }
+
+    fn start_receiving(&mut self, tx: MqttServiceChannel) -> Result<()> {
+        let id = self.id;
+        self.with_state(|state| {
+            let _ = tx.send(MqttServiceMessage::Connected);
+            state.subscribers[id].tx = Some(tx);
+        })
+    }
+}
@@ -0,0 +1,152 @@
+//! Minimal SNTP client and a local NTP server standing in for the real one in the tests
+
+use std::{
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, NaiveDateTime, TimeDelta};
+
+use crate::clock::NetworkTime;
+
+const PACKET_LEN: usize = 48;
+/// Seconds between NTP era (1900) and UNIX epoch (1970)
+const NTP_UNIX_OFFSET: i64 = 2_208_988_800;
+/// LI 0, version 4, mode 3 - client
+const CLIENT_REQUEST: u8 = 0b00_100_011;
+/// LI 0, version 4, mode 4 - server
+const SERVER_RESPONSE: u8 = 0b00_100_100;
+const MODE_MASK: u8 = 0b111;
+
+fn ntp_timestamp(time: NaiveDateTime) -> [u8; 8] {
+    let utc = time.and_utc();
+    let seconds = (utc.timestamp() + NTP_UNIX_OFFSET) as u32;
+    let fraction = ((utc.timestamp_subsec_nanos() as u64) << 32) / 1_000_000_000;
+
+    let mut timestamp = [0; 8];
+    timestamp[..4].copy_from_slice(&seconds.to_be_bytes());
+    timestamp[4..].copy_from_slice(&(fraction as u32).to_be_bytes());
+    timestamp
+}
+
+fn from_ntp_timestamp(timestamp: &[u8]) -> Option<NaiveDateTime> {
+    let seconds = u32::from_be_bytes(timestamp[..4].try_into().ok()?) as i64;
+    let fraction = u32::from_be_bytes(timestamp[4..8].try_into().ok()?) as u64;
+    let nanos = (fraction * 1_000_000_000) >> 32;
+
+    DateTime::from_timestamp(seconds - NTP_UNIX_OFFSET, nanos as u32).map(|utc| utc.naive_utc())
+}
+
+/// Asks the NTP server for the UTC time on every call
+pub struct SntpClient {
+    server: SocketAddr,
+    timeout: Duration,
+}
+
+impl SntpClient {
+    pub fn new(server: SocketAddr, timeout: Duration) -> Self {
+        Self { server, timeout }
+    }
+}
+
+impl NetworkTime for SntpClient {
+    fn now(&mut self) -> Result<NaiveDateTime> {
+        let socket = UdpSocket::bind(("0.0.0.0", 0)).context("Cannot bind NTP socket")?;
+        socket.set_read_timeout(Some(self.timeout))?;
+
+        let mut request = [0; PACKET_LEN];
+        request[0] = CLIENT_REQUEST;
+
+        let sent_at = Instant::now();
+        socket
+            .send_to(&request, self.server)
+            .with_context(|| format!("Cannot send NTP request to {}", self.server))?;
+
+        let mut response = [0; PACKET_LEN];
+        let (len, _) = socket
+            .recv_from(&mut response)
+            .with_context(|| format!("No NTP response from {}", self.server))?;
+        let round_trip = sent_at.elapsed();
+
+        if len < PACKET_LEN || response[0] & MODE_MASK != SERVER_RESPONSE & MODE_MASK {
+            bail!("Invalid NTP response");
+        }
+        // Stratum 0 is a "kiss of death", server refuses to tell the time
+        if response[1] == 0 {
+            bail!("NTP server refused the request");
+        }
+
+        let transmit = from_ntp_timestamp(&response[40..48]).context("Invalid NTP timestamp")?;
+        // The answer is already half of the round trip old
+        Ok(transmit + TimeDelta::from_std(round_trip / 2)?)
+    }
+}
+
+/// Answers SNTP requests on the localhost with the time set by the test, until dropped
+pub struct FakeNtpServer {
+    addr: SocketAddr,
+    online: Arc<AtomicBool>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl FakeNtpServer {
+    pub fn start(now: NaiveDateTime) -> Result<Self> {
+        let socket = UdpSocket::bind(("127.0.0.1", 0)).context("Cannot bind NTP server socket")?;
+        // Wake up now and then to notice the server got dropped
+        socket.set_read_timeout(Some(Duration::from_millis(50)))?;
+
+        let server = Self {
+            addr: socket.local_addr()?,
+            online: Arc::new(AtomicBool::new(true)),
+            stopped: Arc::new(AtomicBool::new(false)),
+        };
+
+        let started = Instant::now();
+        let online = server.online.clone();
+        let stopped = server.stopped.clone();
+        std::thread::spawn(move || {
+            let mut request = [0; PACKET_LEN];
+
+            while !stopped.load(Ordering::SeqCst) {
+                let Ok((_, client)) = socket.recv_from(&mut request) else {
+                    continue;
+                };
+                // Offline server is the same as the unreachable one
+                if !online.load(Ordering::SeqCst) {
+                    continue;
+                }
+
+                // Time keeps running from the given one
+                let now = now + TimeDelta::from_std(started.elapsed()).unwrap();
+
+                let mut response = [0; PACKET_LEN];
+                response[0] = SERVER_RESPONSE;
+                // Stratum 1, as if it had a reference clock
+                response[1] = 1;
+                response[40..48].copy_from_slice(&ntp_timestamp(now));
+                let _ = socket.send_to(&response, client);
+            }
+        });
+
+        Ok(server)
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn set_online(&self, online: bool) {
+        self.online.store(online, Ordering::SeqCst);
+    }
+}
+
+impl Drop for FakeNtpServer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+}
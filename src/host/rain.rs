@@ -0,0 +1,63 @@
+//! Rain switch the test wets and dries
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+
+use crate::rain::{RainSensor, RainServiceChannel, RainServiceMessage};
+
+#[derive(Default)]
+struct FakeRainState {
+    wet: bool,
+    /// Where interrupts go, set once the interrupt service is started
+    interrupt_tx: Option<RainServiceChannel>,
+    /// Mimics GPIO interrupt, that gets disabled after it fires
+    interrupt_enabled: bool,
+    interrupt_count: u32,
+}
+
+/// Clones share the same sensor, so the test can drive the one moved into the Rain service
+#[derive(Clone, Default)]
+pub struct FakeRainSensor {
+    state: Arc<Mutex<FakeRainState>>,
+}
+
+impl FakeRainSensor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pin changes, interrupt fires if it is enabled
+    pub fn set_wet(&self, wet: bool) {
+        let mut state = self.state.lock().unwrap();
+        if state.wet == wet {
+            return;
+        }
+        state.wet = wet;
+
+        if state.interrupt_enabled {
+            if let Some(tx) = &state.interrupt_tx {
+                // Rain service might be gone already, nothing to notify then
+                let _ = tx.send(RainServiceMessage::InterruptArrived(state.interrupt_count));
+                state.interrupt_enabled = false;
+                state.interrupt_count += 1;
+            }
+        }
+    }
+}
+
+impl RainSensor for FakeRainSensor {
+    fn is_wet(&mut self) -> Result<bool> {
+        Ok(self.state.lock().unwrap().wet)
+    }
+
+    fn start_interrupt_service(&mut self, tx: RainServiceChannel) -> Result<()> {
+        self.state.lock().unwrap().interrupt_tx = Some(tx);
+        Ok(())
+    }
+
+    fn enable_interrupt(&mut self) -> Result<()> {
+        self.state.lock().unwrap().interrupt_enabled = true;
+        Ok(())
+    }
+}
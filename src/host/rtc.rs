@@ -0,0 +1,204 @@
+//! RTC keeping the virtual time, the time moves only when the test says so
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use chrono::{NaiveDateTime, NaiveTime, TimeDelta, Timelike};
+
+use crate::clock::{ClockServiceChannel, ClockServiceMessage, RealTimeClock};
+
+#[derive(Default)]
+struct Alarm {
+    when: Option<NaiveTime>,
+    interrupt_enabled: bool,
+    matched: bool,
+}
+
+struct FakeRtcState {
+    now: NaiveDateTime,
+    temperature: f32,
+    /// H:M:S match
+    alarm1: Alarm,
+    /// H:M match
+    alarm2: Alarm,
+    /// Where interrupts go, set once the interrupt service is started
+    interrupt_tx: Option<ClockServiceChannel>,
+    /// Mimics GPIO interrupt, that gets disabled after it fires
+    interrupt_enabled: bool,
+    interrupt_count: u32,
+    /// Oscillator-stop flag, set when the RTC lost the power
+    stopped: bool,
+}
+
+impl FakeRtcState {
+    fn int_line_asserted(&self) -> bool {
+        (self.alarm1.matched && self.alarm1.interrupt_enabled)
+            || (self.alarm2.matched && self.alarm2.interrupt_enabled)
+    }
+
+    /// Apply `change` to the registers, fire the interrupt if INT line got asserted
+    fn update(&mut self, change: impl FnOnce(&mut Self)) {
+        let was_asserted = self.int_line_asserted();
+
+        change(self);
+
+        if !was_asserted && self.int_line_asserted() && self.interrupt_enabled {
+            if let Some(tx) = &self.interrupt_tx {
+                self.interrupt_enabled = false;
+                // Clock service might be gone already, nothing to notify then
+                let _ = tx.send(ClockServiceMessage::InterruptArrived(self.interrupt_count));
+                self.interrupt_count += 1;
+            }
+        }
+    }
+
+    fn tick(&mut self) {
+        self.update(|state| {
+            state.now += TimeDelta::seconds(1);
+            let time = state.now.time();
+
+            if state.alarm1.when == Some(time) {
+                state.alarm1.matched = true;
+            }
+
+            if state.alarm2.when == Some(time) {
+                state.alarm2.matched = true;
+            }
+        });
+    }
+}
+
+/// Clones share the same clock, so the test can drive the one moved into the Clock service
+#[derive(Clone)]
+pub struct FakeRtc {
+    state: Arc<Mutex<FakeRtcState>>,
+}
+
+impl FakeRtc {
+    pub fn new(now: NaiveDateTime) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(FakeRtcState {
+                now,
+                temperature: 21.5,
+                alarm1: Alarm::default(),
+                alarm2: Alarm::default(),
+                interrupt_tx: None,
+                interrupt_enabled: false,
+                interrupt_count: 1,
+                stopped: false,
+            })),
+        }
+    }
+
+    pub fn now(&self) -> NaiveDateTime {
+        self.state.lock().unwrap().now
+    }
+
+    pub fn set_temperature(&self, temperature: f32) {
+        self.state.lock().unwrap().temperature = temperature;
+    }
+
+    /// As if the backup battery ran out, the time is not to be trusted
+    pub fn stop_oscillator(&self) {
+        self.state.lock().unwrap().stopped = true;
+    }
+
+    /// Moves the time forward second by second, alarms match the same way they do on DS3231
+    pub fn advance(&self, delta: TimeDelta) {
+        for _ in 0..delta.num_seconds() {
+            self.state.lock().unwrap().tick();
+        }
+    }
+
+    /// Time of the alarm1, if its interrupt is enabled
+    pub fn alarm1(&self) -> Option<NaiveTime> {
+        let state = self.state.lock().unwrap();
+        state.alarm1.when.filter(|_| state.alarm1.interrupt_enabled)
+    }
+
+    /// Time of the alarm2, if its interrupt is enabled
+    pub fn alarm2(&self) -> Option<NaiveTime> {
+        let state = self.state.lock().unwrap();
+        state.alarm2.when.filter(|_| state.alarm2.interrupt_enabled)
+    }
+
+    fn update(&mut self, change: impl FnOnce(&mut FakeRtcState)) -> Result<()> {
+        self.state.lock().unwrap().update(change);
+        Ok(())
+    }
+}
+
+impl RealTimeClock for FakeRtc {
+    fn datetime(&mut self) -> Result<NaiveDateTime> {
+        Ok(self.now())
+    }
+
+    fn set_datetime(&mut self, datetime: NaiveDateTime) -> Result<()> {
+        // Alarms do not match the skipped seconds, the same as on DS3231
+        self.update(|state| state.now = datetime)
+    }
+
+    fn has_been_stopped(&mut self) -> Result<bool> {
+        Ok(self.state.lock().unwrap().stopped)
+    }
+
+    fn clear_has_been_stopped_flag(&mut self) -> Result<()> {
+        self.update(|state| state.stopped = false)
+    }
+
+    fn temperature(&mut self) -> Result<f32> {
+        Ok(self.state.lock().unwrap().temperature)
+    }
+
+    fn set_alarm1_hms(&mut self, when: NaiveTime) -> Result<()> {
+        // Register has a second resolution
+        let when = when.with_nanosecond(0).unwrap();
+        self.update(|state| state.alarm1.when = Some(when))
+    }
+
+    fn enable_alarm1_interrupts(&mut self) -> Result<()> {
+        self.update(|state| state.alarm1.interrupt_enabled = true)
+    }
+
+    fn disable_alarm1_interrupts(&mut self) -> Result<()> {
+        self.update(|state| state.alarm1.interrupt_enabled = false)
+    }
+
+    fn has_alarm1_matched(&mut self) -> Result<bool> {
+        Ok(self.state.lock().unwrap().alarm1.matched)
+    }
+
+    fn clear_alarm1_matched_flag(&mut self) -> Result<()> {
+        self.update(|state| state.alarm1.matched = false)
+    }
+
+    fn set_alarm2_hm(&mut self, when: NaiveTime) -> Result<()> {
+        // Register has a minute resolution
+        let when = NaiveTime::from_hms_opt(when.hour(), when.minute(), 0).unwrap();
+        self.update(|state| state.alarm2.when = Some(when))
+    }
+
+    fn enable_alarm2_interrupts(&mut self) -> Result<()> {
+        self.update(|state| state.alarm2.interrupt_enabled = true)
+    }
+
+    fn disable_alarm2_interrupts(&mut self) -> Result<()> {
+        self.update(|state| state.alarm2.interrupt_enabled = false)
+    }
+
+    fn has_alarm2_matched(&mut self) -> Result<bool> {
+        Ok(self.state.lock().unwrap().alarm2.matched)
+    }
+
+    fn clear_alarm2_matched_flag(&mut self) -> Result<()> {
+        self.update(|state| state.alarm2.matched = false)
+    }
+
+    fn start_interrupt_service(&mut self, tx: ClockServiceChannel) -> Result<()> {
+        self.update(|state| state.interrupt_tx = Some(tx))
+    }
+
+    fn enable_interrupt(&mut self) -> Result<()> {
+        self.update(|state| state.interrupt_enabled = true)
+    }
+}
@@ -0,0 +1,97 @@
+//! Storage kept in files on the host, one file per key, or in memory for the tests
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result};
+
+use crate::storage::Storage;
+
+pub struct FileStorage {
+    dir: PathBuf,
+}
+
+impl FileStorage {
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Cannot create storage dir {}", dir.display()))?;
+
+        Ok(Self { dir })
+    }
+}
+
+impl Storage for FileStorage {
+    fn get(&mut self, key: &str) -> Result<Option<Vec<u8>>> {
+        match std::fs::read(self.dir.join(key)) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn set(&mut self, key: &str, value: &[u8]) -> Result<()> {
+        std::fs::write(self.dir.join(key), value)?;
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> Result<()> {
+        match std::fs::remove_file(self.dir.join(key)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Clones share the same content, so the test can inspect what the service stored
+#[derive(Clone, Default)]
+pub struct MemoryStorage {
+    values: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+}
+
+impl Storage for MemoryStorage {
+    fn get(&mut self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.values.lock().unwrap().get(key).cloned())
+    }
+
+    fn set(&mut self, key: &str, value: &[u8]) -> Result<()> {
+        self.values
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), value.to_vec());
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> Result<()> {
+        self.values.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_storage_survives_reopening() {
+        let dir = std::env::temp_dir().join(format!("wmg-storage-{}", std::process::id()));
+
+        let mut storage = FileStorage::new(&dir).unwrap();
+        assert_eq!(storage.get("config").unwrap(), None);
+
+        storage.set("config", b"{}").unwrap();
+
+        let mut storage = FileStorage::new(&dir).unwrap();
+        assert_eq!(storage.get("config").unwrap(), Some(b"{}".to_vec()));
+
+        storage.remove("config").unwrap();
+        assert_eq!(storage.get("config").unwrap(), None);
+        // Removing missing key is fine
+        storage.remove("config").unwrap();
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,43 @@
+//! Valve that only remembers whether it is open
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use anyhow::Result;
+
+use crate::sections::{ValveCloser, ValveDriver};
+
+/// Clones share the same valve, so the test can observe the one moved into the Sections service
+#[derive(Clone, Debug, Default)]
+pub struct FakeValve {
+    open: Arc<AtomicBool>,
+}
+
+impl FakeValve {
+    pub fn is_open(&self) -> bool {
+        self.open.load(Ordering::SeqCst)
+    }
+}
+
+impl ValveDriver for FakeValve {
+    fn open(&mut self) -> Result<()> {
+        self.open.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.open.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn is_open(&self) -> bool {
+        self.open.load(Ordering::SeqCst)
+    }
+
+    fn emergency_closer(&self) -> ValveCloser {
+        let open = self.open.clone();
+        Box::new(move || open.store(false, Ordering::SeqCst))
+    }
+}
@@ -1,265 +1,350 @@
-use std::time::Duration;
+use std::{
+    ffi::{c_int, c_void, CString},
+    sync::{mpsc::RecvTimeoutError, Mutex},
+};
 
-use chrono::NaiveTime;
 use embedded_svc::{
     http::{Headers, Method},
     io::{Read, Write},
 };
-use esp_idf_svc::http::server::EspHttpConnection;
-use esp_idf_svc::http::server::Request;
 use esp_idf_svc::{
+    handle::RawHandle,
     http::server::{Configuration, EspHttpServer},
-    io::Write as _,
+    sys::{
+        esp, esp_err_t, http_method_HTTP_GET, httpd_handle_t, httpd_queue_work,
+        httpd_register_uri_handler, httpd_req_get_hdr_value_len, httpd_req_get_hdr_value_str,
+        httpd_req_t, httpd_req_to_sockfd, httpd_resp_send, httpd_resp_set_hdr,
+        httpd_resp_set_status, httpd_send, httpd_sess_trigger_close, httpd_socket_send,
+        httpd_uri_t, ESP_FAIL, ESP_OK,
+    },
+    tls::X509,
 };
-use log::info;
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use serde_json::json;
-
-use crate::{
-    clock::{ClockServiceChannel, ClockStatus},
-    sections::{Section, SectionDuration},
-    watering::{WateringServiceChannel, WateringServiceMessage, WateringStatus},
+use log::{debug, error, info, warn};
+use water_my_garden_rs::{
+    api::{
+        self, Api, ApiError, Request, Response, EVENTS_KEEP_ALIVE, EVENTS_KEEP_ALIVE_PERIOD,
+        EVENTS_PATH, EVENTS_RESPONSE_HEAD, MAX_LEN, ROUTES,
+    },
+    tls::{https_location, TlsIdentity},
 };
-use anyhow::{anyhow, Context};
 
-#[derive(Debug, Serialize)]
-pub struct SystemStatus {
-    watering: WateringStatus,
-    clock: ClockStatus,
-}
+use anyhow::Context;
+
+/// Each stream keeps one of the server sockets, leave the rest for the requests
+const MAX_EVENT_STREAMS: usize = 2;
 
+/// Sockets of the `/events` clients, a socket is forgotten when the server closes its session
+static EVENT_STREAMS: Mutex<Vec<c_int>> = Mutex::new(Vec::new());
+
+/// Plain HTTP server, HTTPS one if the identity is given
 pub fn setup_http_server(
-    clock_service_channel: ClockServiceChannel,
-    watering_service_channel: WateringServiceChannel,
+    api: Api,
+    tls_identity: Option<&TlsIdentity>,
 ) -> anyhow::Result<EspHttpServer<'static>> {
-    let mut server =
-        EspHttpServer::new(&Configuration::default()).expect("Cannot create the http server");
-    // http://<sta ip>/ handler
-    server
-        .fn_handler("/", Method::Get, |request| -> anyhow::Result<()> {
-            let html = "It works!";
-
-            let mut response = request.into_ok_response()?;
-            response.write_all(html.as_bytes())?;
-            Ok(())
-        })
-        .context("handler /")?;
-
-    {
-        let watering_tx = watering_service_channel.clone();
-        let clock_tx = clock_service_channel.clone();
-        server
-            .fn_handler("/status", Method::Get, move |req| {
-                status(req, &watering_tx, &clock_tx)
-            })
-            .context("handler /status")?;
+    let mut configuration = Configuration::default();
+    if let Some(identity) = tls_identity {
+        configuration.server_certificate =
+            Some(X509::pem_until_nul(leak_pem(&identity.certificate)));
+        configuration.private_key = Some(X509::pem_until_nul(leak_pem(&identity.private_key)));
     }
+    // Requests none of the routes takes end up in the `/*` fallbacks
+    configuration.uri_match_wildcard = true;
+    let mut server = EspHttpServer::new(&configuration).expect("Cannot create the http server");
+
+    for (method, path, handler) in ROUTES {
+        let api = api.clone();
+        let handler = *handler;
+        let api_method = *method;
+        let method = match method {
+            api::Method::Get => Method::Get,
+            api::Method::Post => Method::Post,
+            api::Method::Put => Method::Put,
+        };
 
-    {
-        let watering_tx = watering_service_channel.clone();
         server
-            .fn_handler("/start_watering_at", Method::Post, move |req| {
-                handle_start_watering_at(req, &watering_tx)
+            .fn_handler(path, method, move |mut req| -> anyhow::Result<()> {
+                // Routes match the path alone, the query string is up to the handler
+                let query = req
+                    .uri()
+                    .split_once('?')
+                    .map(|(_, query)| query.to_string())
+                    .unwrap_or_default();
+                let authorized = api.authorize(api_method, req.header("Authorization"));
+                let len = req.content_len().unwrap_or(0) as usize;
+                debug!("Content len {len}");
+
+                let response = if let Err(err) = authorized {
+                    Err(err)
+                } else if len > MAX_LEN {
+                    Err(ApiError::RequestTooBig)
+                } else {
+                    let mut body = vec![0; len];
+                    match req.read_exact(&mut body) {
+                        Ok(()) => handler(
+                            &api,
+                            &Request {
+                                query: &query,
+                                body: &body,
+                            },
+                        ),
+                        Err(err) => Err(ApiError::BadRequest(format!(
+                            "Cannot read the body: {err:?}"
+                        ))),
+                    }
+                };
+                let response = response.unwrap_or_else(Response::from);
+
+                req.into_response(response.status, None, &response.headers)?
+                    .write_all(&response.body)?;
+                Ok(())
             })
-            .context("handler /start_watering_at")?;
+            .with_context(|| format!("handler {path}"))?;
     }
 
-    {
-        let watering_tx = watering_service_channel.clone();
-        server
-            .fn_handler("/disable_watering", Method::Post, move |req| {
-                disable_watering(req, &watering_tx)
-            })
-            .context("handler /disable_watering")?;
-    }
+    setup_events(&server, api.clone()).context("handler /events")?;
+    // Handlers are matched in the order they got registered, fallbacks go last
+    setup_fallbacks(&mut server, api).context("fallback handlers")?;
 
-    {
-        let watering_tx = watering_service_channel.clone();
-        server
-            .fn_handler("/set_section_duration", Method::Post, move |req| {
-                set_section_duration(req, &watering_tx)
-            })
-            .context("handler /set_section_duration")?;
-    }
+    Ok(server)
+}
 
-    {
-        let watering_tx = watering_service_channel.clone();
-        server
-            .fn_handler("/close_all_valves", Method::Post, move |req| {
-                close_all_valves(req, &watering_tx)
-            })
-            .context("handler /close_all_valves")?;
+/// Answers the requests none of the routes takes in the error envelope, the way the simulator does:
+/// 405 for the known path asked with another method, 404 for the unknown one
+fn setup_fallbacks(server: &mut EspHttpServer<'static>, api: Api) -> anyhow::Result<()> {
+    for method in [
+        Method::Get,
+        Method::Post,
+        Method::Put,
+        Method::Delete,
+        Method::Patch,
+    ] {
+        let api = api.clone();
+        let api_method = match method {
+            Method::Get => Some(api::Method::Get),
+            Method::Post => Some(api::Method::Post),
+            Method::Put => Some(api::Method::Put),
+            _ => None,
+        };
+
+        server.fn_handler("/*", method, move |req| -> anyhow::Result<()> {
+            let response = match api_method {
+                Some(method) => api.handle(method, req.uri(), req.header("Authorization"), &[]),
+                None => ApiError::MethodNotAllowed.into(),
+            };
+
+            req.into_response(response.status, None, &response.headers)?
+                .write_all(&response.body)?;
+            Ok(())
+        })?;
     }
 
-    {
-        let watering_tx = watering_service_channel.clone();
-        server
-            .fn_handler("/enable_section_for", Method::Post, move |req| {
-                enable_section_for(req, &watering_tx)
-            })
-            .context("handler /enable_section_for")?;
-    }
+    Ok(())
+}
 
-    Ok(server)
+/// Server refers to the certificate for its whole life, that is the life of the board
+fn leak_pem(pem: &str) -> &'static [u8] {
+    Box::leak(format!("{pem}\0").into_bytes().into_boxed_slice())
 }
 
-// Max payload length
-const MAX_LEN: usize = 128;
+/// Plain HTTP server sending every request to the HTTPS one
+pub fn setup_https_redirect() -> anyhow::Result<EspHttpServer<'static>> {
+    let default = Configuration::default();
+    let mut server = EspHttpServer::new(&Configuration {
+        // Both servers run at once, each needs its own control socket
+        ctrl_port: default.ctrl_port + 1,
+        max_open_sockets: 2,
+        uri_match_wildcard: true,
+        ..default
+    })
+    .context("Cannot create the redirect server")?;
+
+    for method in [Method::Get, Method::Post, Method::Put] {
+        server.fn_handler("/*", method, move |req| -> anyhow::Result<()> {
+            let location = https_location(req.header("Host"), req.uri(), default.https_port);
+            match location {
+                Some(location) => {
+                    // 308 keeps the method and the body, unlike 301
+                    req.into_response(308, None, &[("Location", &location)])?;
+                }
+                None => {
+                    req.into_status_response(400)?.write_all(b"Use HTTPS")?;
+                }
+            }
+            Ok(())
+        })?;
+    }
 
-fn status(
-    req: Request<&mut EspHttpConnection<'_>>,
-    watering_tx: &WateringServiceChannel,
-    clock_tx: &ClockServiceChannel,
-) -> anyhow::Result<()> {
-    match get_system_status(watering_tx, clock_tx) {
-        Ok(status) => {
-            let system_json = serde_json::to_string_pretty(&status)?;
+    Ok(server)
+}
 
-            req.into_ok_response()?.write_all(system_json.as_bytes())?;
-        }
-        Err(err) => req
-            .into_status_response(500)?
-            .write_all(err.to_string().as_bytes())?,
+/// Server runs the handlers one by one on a single task, a handler streaming the events would
+/// block all the others. Instead, `/events` handler sends the response head and returns
+/// leaving the socket open, the events are written to it later on, from the server task.
+fn setup_events(server: &EspHttpServer<'static>, api: Api) -> anyhow::Result<()> {
+    // Server keeps its own copy of the uri
+    let uri = CString::new(EVENTS_PATH)?;
+    let conf = httpd_uri_t {
+        uri: uri.as_ptr(),
+        method: http_method_HTTP_GET,
+        handler: Some(events_handler),
+        // Server is never stopped, neither is the handler freed
+        user_ctx: Box::into_raw(Box::new(api.clone())) as _,
+        ..Default::default()
     };
+    esp!(unsafe { httpd_register_uri_handler(server.handle(), &conf) })?;
+
+    let server = ServerHandle(server.handle());
+    std::thread::Builder::new()
+        .name("events stream".to_string())
+        .spawn(move || forward_events(server, api))?;
 
     Ok(())
 }
 
-fn get_system_status(
-    watering_tx: &WateringServiceChannel,
-    clock_tx: &ClockServiceChannel,
-) -> anyhow::Result<SystemStatus> {
-    let (tx, rx) = std::sync::mpsc::channel();
-    watering_tx
-        .send(WateringServiceMessage::GetStatus(tx))
-        .context("while sending get status to watering service")?;
-
-    let watering_status = rx
-        .recv_timeout(Duration::from_secs(10))
-        .context("while receiving status from watering service")?;
-
-    let (tx, rx) = std::sync::mpsc::channel();
-    clock_tx
-        .send(crate::clock::ClockServiceMessage::GetStatus(tx))
-        .context("while sending get status to clock service")?;
-    let clock_status = rx
-        .recv_timeout(Duration::from_secs(10))
-        .context("while receiving status from clock service")?;
-
-    Ok(SystemStatus {
-        watering: watering_status,
-        clock: clock_status,
-    })
-}
+struct ServerHandle(httpd_handle_t);
 
-#[derive(Deserialize)]
-struct StartWateringAtReq {
-    time: NaiveTime,
+// Handle is only passed back to the server, which is never stopped
+unsafe impl Send for ServerHandle {}
+
+/// Frame to send to one of the streams
+struct SendFrame {
+    server: httpd_handle_t,
+    fd: c_int,
+    frame: String,
 }
 
-fn handle_start_watering_at(
-    mut req: Request<&mut EspHttpConnection<'_>>,
-    watering_tx: &WateringServiceChannel,
-) -> anyhow::Result<()> {
-    match get_body::<StartWateringAtReq>(&mut req) {
-        Ok(body) => {
-            watering_tx.send(WateringServiceMessage::StartWateringAt(body.time))?;
-            req.into_ok_response()?.write_all("OK!".as_bytes())?;
-        }
-        Err(err) => {
-            req.into_status_response(400)?
-                .write_all(err.to_string().as_bytes())?;
-        }
+fn forward_events(server: ServerHandle, api: Api) {
+    let Some(events) = api.subscribe_events() else {
+        error!("Events service is gone, /events stays silent");
+        return;
     };
 
-    Ok(())
+    loop {
+        let frame = match events.recv_timeout(EVENTS_KEEP_ALIVE_PERIOD) {
+            Ok(event) => event.to_sse(),
+            Err(RecvTimeoutError::Timeout) => EVENTS_KEEP_ALIVE.to_string(),
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+
+        let streams = EVENT_STREAMS.lock().unwrap().clone();
+        for fd in streams {
+            let arg = Box::into_raw(Box::new(SendFrame {
+                server: server.0,
+                fd,
+                frame: frame.clone(),
+            }));
+
+            if let Err(err) =
+                esp!(unsafe { httpd_queue_work(server.0, Some(send_frame), arg as _) })
+            {
+                warn!("Cannot queue the event for socket {fd}: {err}");
+                drop(unsafe { Box::from_raw(arg) });
+            }
+        }
+    }
 }
 
-#[derive(Deserialize)]
-struct SetSectionDurationReq {
-    section: Section,
-    duration: SectionDuration,
-}
+/// Runs on the server task, the same one that closes the sessions
+extern "C" fn send_frame(arg: *mut c_void) {
+    let send = unsafe { Box::from_raw(arg as *mut SendFrame) };
 
-fn set_section_duration(
-    mut req: Request<&mut EspHttpConnection<'_>>,
-    watering_tx: &WateringServiceChannel,
-) -> anyhow::Result<()> {
-    match get_body::<SetSectionDurationReq>(&mut req) {
-        Ok(body) => {
-            watering_tx.send(WateringServiceMessage::SetSectionDuration(
-                body.section,
-                body.duration,
-            ))?;
-
-            req.into_ok_response()?.write_all("OK!".as_bytes())?;
-        }
-        Err(err) => {
-            req.into_status_response(400)?
-                .write_all(err.to_string().as_bytes())?;
-        }
-    };
+    // Socket might got closed since the frame was queued
+    if !EVENT_STREAMS.lock().unwrap().contains(&send.fd) {
+        return;
+    }
 
-    Ok(())
+    let mut frame = send.frame.as_bytes();
+    while !frame.is_empty() {
+        let sent =
+            unsafe { httpd_socket_send(send.server, send.fd, frame.as_ptr() as _, frame.len(), 0) };
+        if sent < 0 {
+            info!("Events stream on socket {} is gone", send.fd);
+            unsafe { httpd_sess_trigger_close(send.server, send.fd) };
+            return;
+        }
+        frame = &frame[sent as usize..];
+    }
 }
 
-fn disable_watering(
-    req: Request<&mut EspHttpConnection<'_>>,
-    watering_tx: &WateringServiceChannel,
-) -> anyhow::Result<()> {
-    watering_tx.send(WateringServiceMessage::DisableWatering)?;
-    req.into_ok_response()?.write_all("OK!".as_bytes())?;
+extern "C" fn events_handler(req: *mut httpd_req_t) -> esp_err_t {
+    let api = unsafe { &*((*req).user_ctx as *const Api) };
+    if let Err(err) = api.authorize(api::Method::Get, authorization(req).as_deref()) {
+        return send_response(req, &err.into());
+    }
 
-    Ok(())
-}
+    let fd = unsafe { httpd_req_to_sockfd(req) };
+    let mut streams = EVENT_STREAMS.lock().unwrap();
 
-fn close_all_valves(
-    req: Request<&mut EspHttpConnection<'_>>,
-    watering_tx: &WateringServiceChannel,
-) -> anyhow::Result<()> {
-    watering_tx.send(WateringServiceMessage::CloseAllValves)?;
-    req.into_ok_response()?.write_all("OK!".as_bytes())?;
+    if streams.len() >= MAX_EVENT_STREAMS {
+        let err = ApiError::Unavailable("Too many event streams".to_string());
+        return send_response(req, &err.into());
+    }
 
-    Ok(())
-}
+    let sent = unsafe {
+        httpd_send(
+            req,
+            EVENTS_RESPONSE_HEAD.as_ptr() as _,
+            EVENTS_RESPONSE_HEAD.len(),
+        )
+    };
+    if sent < 0 {
+        return ESP_FAIL as _;
+    }
+
+    // Server frees the session context when it closes the session, that's when the socket is forgotten
+    unsafe {
+        (*req).sess_ctx = Box::into_raw(Box::new(fd)) as _;
+        (*req).free_ctx = Some(forget_stream);
+    }
+    streams.push(fd);
+    info!("Events stream opened on socket {fd}");
 
-#[derive(Deserialize)]
-struct EnableSectionForReq {
-    section: Section,
-    duration: SectionDuration,
+    ESP_OK as _
 }
 
-fn enable_section_for(
-    mut req: Request<&mut EspHttpConnection<'_>>,
-    watering_tx: &WateringServiceChannel,
-) -> anyhow::Result<()> {
-    match get_body::<EnableSectionForReq>(&mut req) {
-        Ok(body) => {
-            watering_tx.send(WateringServiceMessage::EnableSectionFor(
-                body.section,
-                body.duration,
-            ))?;
-            req.into_ok_response()?.write_all("OK!".as_bytes())?;
-        }
-        Err(err) => {
-            req.into_status_response(400)?
-                .write_all(err.to_string().as_bytes())?;
-        }
-    };
+/// Value of the Authorization header of the raw request
+fn authorization(req: *mut httpd_req_t) -> Option<String> {
+    let field = b"Authorization\0".as_ptr() as _;
+    let len = unsafe { httpd_req_get_hdr_value_len(req, field) };
+    if len == 0 {
+        return None;
+    }
 
-    Ok(())
+    let mut value = vec![0u8; len + 1];
+    esp!(unsafe { httpd_req_get_hdr_value_str(req, field, value.as_mut_ptr() as _, value.len()) })
+        .ok()?;
+    value.truncate(len);
+    String::from_utf8(value).ok()
 }
 
-fn get_body<T: DeserializeOwned>(
-    req: &mut Request<&mut EspHttpConnection>,
-) -> Result<T, anyhow::Error> {
-    let len = req.content_len().unwrap_or(0) as usize;
-    info!("Content len {len}");
-    if len > MAX_LEN {
-        return Err(anyhow!("Request too big"));
+/// Sends the response to the raw request, the way `fn_handler` does
+fn send_response(req: *mut httpd_req_t, response: &Response) -> esp_err_t {
+    let reason = match response.status {
+        401 => "Unauthorized",
+        503 => "Service Unavailable",
+        _ => "",
+    };
+    let status = CString::new(format!("{} {reason}", response.status)).unwrap();
+    let headers = response
+        .headers
+        .iter()
+        .map(|(name, value)| (CString::new(*name).unwrap(), CString::new(*value).unwrap()))
+        .collect::<Vec<_>>();
+
+    // Server keeps the pointers until the response is sent
+    unsafe {
+        httpd_resp_set_status(req, status.as_ptr());
+        for (name, value) in &headers {
+            httpd_resp_set_hdr(req, name.as_ptr(), value.as_ptr());
+        }
+        httpd_resp_send(req, response.body.as_ptr() as _, response.body.len() as _)
     }
-    let mut buf = vec![0; len];
-    req.read_exact(&mut buf)?;
-    let body = serde_json::from_slice(&buf)?;
-    Ok(body)
+}
+
+extern "C" fn forget_stream(ctx: *mut c_void) {
+    let fd = unsafe { Box::from_raw(ctx as *mut c_int) };
+    EVENT_STREAMS
+        .lock()
+        .unwrap()
+        .retain(|stream| *stream != *fd);
 }
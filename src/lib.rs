@@ -0,0 +1,24 @@
+//! Watering logic, agnostic to the underlying hardware.
+//! Hardware is hidden behind the traits, implemented for the ESP32 in the `esp` module
+//! and in-memory for the host in the `host` module.
+
+pub mod api;
+pub mod auth;
+pub mod clock;
+#[cfg(feature = "esp")]
+pub mod esp;
+pub mod events;
+pub mod fail_safe;
+pub mod history;
+#[cfg(any(test, feature = "host"))]
+pub mod host;
+pub mod moisture;
+pub mod mqtt;
+pub mod rain;
+pub mod recovery;
+pub mod schedule;
+pub mod sections;
+pub mod storage;
+pub mod time_zone;
+pub mod tls;
+pub mod watering;
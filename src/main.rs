@@ -1,19 +1,45 @@
-mod clock;
 mod http_server;
-mod sections;
 mod wifi;
-mod watering;
 
-use clock::ClockService;
-
-use esp_idf_svc::{eventloop::EspSystemEventLoop, hal::prelude::*};
-use http_server::setup_http_server;
-use sections::Sections;
-use watering::OnScheduleWatering;
+use chrono::TimeDelta;
+use esp_idf_svc::{
+    eventloop::EspSystemEventLoop,
+    hal::{prelude::*, reset::ResetReason},
+    nvs::EspDefaultNvsPartition,
+};
+use http_server::{setup_http_server, setup_https_redirect};
+use water_my_garden_rs::{
+    api::Api,
+    auth::Auth,
+    clock::ClockService,
+    esp::{
+        moisture::{moisture_adc, moisture_probe},
+        mqtt::EspMqtt,
+        ntp::EspNtp,
+        rain::rain_sensor,
+        rtc::EspRtc,
+        storage::EspStorage,
+        tls::self_signed_identity,
+        valve::valve,
+    },
+    events::EventsService,
+    fail_safe::{install_panic_hook, take_last_panic},
+    moisture::MoistureService,
+    mqtt::MqttService,
+    rain::RainService,
+    recovery::RecoveryPolicy,
+    sections::{SectionDuration, Sections, SectionsConfig},
+    time_zone::TimeZone,
+    tls::TlsIdentity,
+    watering::{OnScheduleWatering, WateringConfig},
+};
 use wifi::connect_to_wifi;
 
 use std::{thread::sleep, time::Duration};
 
+/// Sections of the garden, edit the file to add, remove or rewire them
+const SECTIONS: &str = include_str!("../sections.json");
+
 #[derive(Debug)]
 #[toml_cfg::toml_config]
 pub struct Config {
@@ -21,6 +47,53 @@ pub struct Config {
     wifi_ssid: &'static str,
     #[default("NOT SET")]
     wifi_psk: &'static str,
+    /// Sections service closes any valve that is open for longer than that
+    #[default(135)]
+    max_valve_open_minutes: u64,
+    /// RTC is set from this server, it keeps the time on its own when the server is not reachable
+    #[default("pool.ntp.org")]
+    ntp_server: &'static str,
+    #[default(360)]
+    ntp_sync_minutes: u64,
+    /// POSIX TZ string, schedule and status are in this time zone
+    #[default("UTC0")]
+    time_zone: &'static str,
+    /// Program run missed or cut short by the power cut: skip, run_now or run_remaining
+    #[default("skip")]
+    recovery_policy: &'static str,
+    /// Hex SHA-256 of the API token, empty lets everyone change everything
+    #[default("")]
+    api_token_sha256: &'static str,
+    /// GET requests, e.g. `/status`, need the token as well
+    #[default(false)]
+    api_protect_reads: bool,
+    /// Serve the API over HTTPS, instead of the plain HTTP
+    #[default(false)]
+    https: bool,
+    /// PEM, if not given the board generates the self-signed certificate on the first boot
+    #[default("")]
+    tls_certificate: &'static str,
+    #[default("")]
+    tls_private_key: &'static str,
+    /// With HTTPS on, plain HTTP requests are redirected to it
+    #[default(true)]
+    http_redirect: bool,
+    /// Broker Home Assistant listens to, e.g. `mqtt://192.168.68.2:1883`, empty turns MQTT off
+    #[default("")]
+    mqtt_url: &'static str,
+    #[default("")]
+    mqtt_user: &'static str,
+    #[default("")]
+    mqtt_password: &'static str,
+    /// Root of the topics and the device id in Home Assistant
+    #[default("water_my_garden")]
+    mqtt_node_id: &'static str,
+    /// How long the section switched on from Home Assistant is watered
+    #[default(10)]
+    mqtt_ad_hoc_minutes: i64,
+    /// Input of the normally-closed rain switch, -1 if there is none
+    #[default(-1)]
+    rain_sensor_gpio: i32,
 }
 
 fn main() {
@@ -32,10 +105,6 @@ fn main() {
     esp_idf_svc::log::EspLogger::initialize_default();
     esp_idf_svc::log::set_target_level("water_my_garden_rs", log::LevelFilter::Debug).unwrap();
 
-    // TODO: for now quick and dirty, figure something clever
-    #[cfg(test)]
-    execute_tests();
-    #[cfg(not(test))]
     run();
 
     loop {
@@ -43,15 +112,6 @@ fn main() {
     }
 }
 
-#[cfg(test)]
-fn execute_tests() {
-    log::info!("Executing tests");
-    watering::tests::example_valid_configuration_works();
-    watering::tests::can_skip_a_section();
-    watering::tests::can_skip_all_sections();
-    log::info!("All tests passed!");
-}
-
 fn run() {
     say_hello();
 
@@ -59,6 +119,7 @@ fn run() {
 
     let sysloop = EspSystemEventLoop::take().expect("Cannot take SystemEventLoop");
     let peripherals = Peripherals::take().expect("Cannot take peripherals");
+    let nvs = EspDefaultNvsPartition::take().expect("Cannot take NVS partition");
 
     // Connect to the Wi-Fi network
     let wifi = connect_to_wifi(
@@ -72,33 +133,185 @@ fn run() {
     // Don't call dtor over lifespan of the board
     core::mem::forget(wifi);
 
-    let sections_service = Sections::new(
-        peripherals.pins.gpio14,
-        peripherals.pins.gpio26,
-        peripherals.pins.gpio27,
-        peripherals.pins.gpio33,
-    )
-    .expect("Failed to setup Sections");
+    let sections = SectionsConfig::from_json(SECTIONS).expect("Invalid sections configuration");
+    let valves = sections
+        .sections()
+        .iter()
+        .map(|section| {
+            let valve = valve(section)
+                .unwrap_or_else(|e| panic!("Failed to setup {} valve {e:?}", section.id));
+            (section.id.clone(), valve)
+        })
+        .collect();
+
+    let events_service_channel = EventsService::new().start();
+
+    let sections_service = Sections::new(valves)
+        .expect("Failed to setup Sections")
+        .with_max_open_time(Duration::from_secs(app_config.max_valve_open_minutes * 60))
+        .with_events(events_service_channel.clone());
 
-    let clock_service = ClockService::new(
+    let mut system_storage =
+        EspStorage::new(nvs.clone(), "system").expect("Failed to open system storage");
+    let last_reset_cause = match take_last_panic(&mut system_storage) {
+        Ok(Some(panic)) => panic,
+        Ok(None) => format!("{:?}", ResetReason::get()),
+        Err(e) => {
+            log::error!("Failed to read the last panic {e:?}");
+            format!("{:?}", ResetReason::get())
+        }
+    };
+    log::info!("Last reset cause: {last_reset_cause}");
+
+    install_panic_hook(
+        sections_service.emergency_closers(),
+        Box::new(system_storage),
+    );
+    // Services are not restarted when they panic, restart the whole board instead
+    let panic_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        panic_hook(info);
+        esp_idf_svc::hal::reset::restart();
+    }));
+
+    let rtc = EspRtc::new(
         peripherals.pins.gpio21,
         peripherals.pins.gpio22,
         peripherals.pins.gpio23,
         peripherals.i2c0,
     )
-    .expect("Failed to setup Clock");
+    .expect("Failed to setup RTC");
+    let ntp = EspNtp::new(app_config.ntp_server).expect("Failed to setup SNTP");
+    let time_zone = TimeZone::from_posix(app_config.time_zone).expect("Invalid time zone");
+    let clock_service = ClockService::new(rtc)
+        .expect("Failed to setup Clock")
+        .with_network_time(ntp, Duration::from_secs(app_config.ntp_sync_minutes * 60))
+        .with_time_zone(time_zone)
+        .with_events(events_service_channel.clone());
 
     let clock_service_channel = clock_service.start();
     let sections_service_channel = sections_service.start();
 
-    let watering_service =
-        OnScheduleWatering::new(clock_service_channel.clone(), sections_service_channel);
+    let mut watering_storage =
+        EspStorage::new(nvs.clone(), "watering").expect("Failed to open watering storage");
+    let watering_config = WateringConfig::load(&mut watering_storage).unwrap_or_else(|e| {
+        log::error!("Failed to load watering configuration, using default one {e:?}");
+        WateringConfig::default()
+    });
+    log::info!("Loaded watering configuration {watering_config:?}");
+
+    let recovery_policy: RecoveryPolicy = app_config
+        .recovery_policy
+        .parse()
+        .expect("Invalid recovery policy");
+    let watering_service = OnScheduleWatering::new(
+        clock_service_channel.clone(),
+        sections_service_channel,
+        sections.ids(),
+        watering_config,
+        Box::new(watering_storage),
+    )
+    .with_recovery_policy(recovery_policy)
+    .with_events(events_service_channel.clone());
+    let watering_service = if app_config.rain_sensor_gpio >= 0 {
+        let sensor = rain_sensor(app_config.rain_sensor_gpio, &sections)
+            .expect("Failed to setup rain sensor");
+        let rain_service_channel = RainService::new(sensor)
+            .expect("Failed to read rain sensor")
+            .with_events(events_service_channel.clone())
+            .start();
+        watering_service.with_rain_sensor(rain_service_channel)
+    } else {
+        watering_service
+    };
+    let probes = sections
+        .sections()
+        .iter()
+        .filter_map(|section| Some((section, section.moisture_probe?)))
+        .collect::<Vec<_>>();
+    let watering_service = if probes.is_empty() {
+        watering_service
+    } else {
+        let adc = moisture_adc(peripherals.adc1).expect("Failed to setup ADC");
+        let probes = probes
+            .into_iter()
+            .map(|(section, config)| {
+                let probe = moisture_probe(&adc, config.gpio).unwrap_or_else(|e| {
+                    panic!("Failed to setup {} moisture probe {e:?}", section.id)
+                });
+                (section.id.clone(), config, probe)
+            })
+            .collect();
+        let moisture_service_channel = MoistureService::new(probes).start();
+        watering_service.with_moisture_sensors(moisture_service_channel)
+    };
     let watering_service_channel = watering_service.start();
 
+    if !app_config.mqtt_url.is_empty() {
+        let client = EspMqtt::new(
+            app_config.mqtt_url,
+            app_config.mqtt_user,
+            app_config.mqtt_password,
+            app_config.mqtt_node_id,
+        )
+        .expect("Failed to setup MQTT");
+        let ad_hoc_duration =
+            SectionDuration::new(TimeDelta::minutes(app_config.mqtt_ad_hoc_minutes))
+                .expect("Invalid MQTT ad hoc duration");
+        MqttService::new(
+            client,
+            app_config.mqtt_node_id,
+            sections.clone(),
+            watering_service_channel.clone(),
+            clock_service_channel.clone(),
+        )
+        .with_ad_hoc_duration(ad_hoc_duration)
+        .with_events(events_service_channel.clone())
+        .start();
+    }
+
     // Set the HTTP server
-    let http_server = setup_http_server(clock_service_channel, watering_service_channel);
+    let api = Api::new(
+        clock_service_channel,
+        watering_service_channel,
+        events_service_channel,
+        sections,
+        last_reset_cause,
+    )
+    .with_auth(auth(&app_config));
+    let tls_identity = app_config
+        .https
+        .then(|| tls_identity(&app_config, nvs).expect("Failed to setup TLS"));
+    let http_server =
+        setup_http_server(api, tls_identity.as_ref()).expect("Failed to setup HTTP server");
     // Never call dtor of the server
     core::mem::forget(http_server);
+
+    if app_config.https && app_config.http_redirect {
+        let redirect_server = setup_https_redirect().expect("Failed to setup HTTP redirect");
+        core::mem::forget(redirect_server);
+    }
+}
+
+/// Provisioned through the config, or the self-signed one, generated on the first boot
+fn tls_identity(app_config: &Config, nvs: EspDefaultNvsPartition) -> anyhow::Result<TlsIdentity> {
+    if !app_config.tls_certificate.is_empty() || !app_config.tls_private_key.is_empty() {
+        return TlsIdentity::from_pem(app_config.tls_certificate, app_config.tls_private_key);
+    }
+
+    let mut storage = EspStorage::new(nvs, "tls")?;
+    TlsIdentity::load_or_generate(&mut storage, || self_signed_identity("water-my-garden"))
+}
+
+fn auth(app_config: &Config) -> Auth {
+    if app_config.api_token_sha256.is_empty() {
+        log::warn!("API token is not set, everyone in the network can control the watering");
+        return Auth::disabled();
+    }
+
+    Auth::new(app_config.api_token_sha256)
+        .expect("Invalid API token hash")
+        .with_protected_reads(app_config.api_protect_reads)
 }
 
 fn say_hello() {
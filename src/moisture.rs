@@ -0,0 +1,260 @@
+//! Capacitive soil moisture probes, one per section that has it.
+//! Probes are sampled periodically for the status, and right before the section's turn in the scheduled run,
+//! moist soil skips it.
+
+use std::{
+    collections::BTreeMap,
+    sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use log::{error, info};
+use serde::Serialize;
+
+use crate::sections::{MoistureProbeConfig, SectionId};
+
+/// Soil moisture changes slowly, it is enough for the status
+pub const DEFAULT_SAMPLE_PERIOD: Duration = Duration::from_secs(10 * 60);
+/// ADC is noisy, the reading is the average of that many samples
+const SAMPLES_PER_READING: u32 = 16;
+
+/// Output of the probe, e.g. ADC input with the calibrated voltage
+pub trait MoistureProbe: Send + 'static {
+    fn read_mv(&mut self) -> Result<u16>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct MoistureReading {
+    /// Soil moisture in percent
+    pub moisture: u8,
+    /// Averaged output of the probe
+    pub millivolts: u16,
+    /// Scheduled watering of the section is skipped above that moisture
+    pub skip_above: u8,
+}
+
+impl MoistureReading {
+    /// Soil is moist enough to skip the section
+    pub fn is_moist(&self) -> bool {
+        self.moisture > self.skip_above
+    }
+}
+
+#[derive(Debug)]
+pub enum MoistureServiceMessage {
+    /// Samples the probe of the section right away, None if there is no probe or it cannot be read
+    Sample(SectionId, Sender<Option<MoistureReading>>),
+    /// Latest readings of all the probes that could be read
+    GetReadings(Sender<BTreeMap<SectionId, MoistureReading>>),
+}
+pub type MoistureServiceChannel = Sender<MoistureServiceMessage>;
+
+struct Probe<P: MoistureProbe> {
+    section: SectionId,
+    config: MoistureProbeConfig,
+    probe: P,
+}
+
+pub struct MoistureService<P: MoistureProbe> {
+    probes: Vec<Probe<P>>,
+    sample_period: Duration,
+    readings: BTreeMap<SectionId, MoistureReading>,
+}
+
+impl<P: MoistureProbe> MoistureService<P> {
+    /// Probe with its calibration for every section that has one
+    pub fn new(probes: Vec<(SectionId, MoistureProbeConfig, P)>) -> Self {
+        Self {
+            probes: probes
+                .into_iter()
+                .map(|(section, config, probe)| Probe {
+                    section,
+                    config,
+                    probe,
+                })
+                .collect(),
+            sample_period: DEFAULT_SAMPLE_PERIOD,
+            readings: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_sample_period(mut self, sample_period: Duration) -> Self {
+        self.sample_period = sample_period;
+        self
+    }
+
+    /// Starts the Moisture Service, returns the MoistureServiceChannel to communicate with it
+    pub fn start(mut self) -> MoistureServiceChannel {
+        let (tx, rx) = channel();
+
+        // Status has the readings from the start
+        self.sample_all();
+
+        std::thread::Builder::new()
+            .name("moisture service".to_string())
+            .spawn(move || self.moisture_service(rx))
+            .expect("Cannot spawn Moisture service");
+
+        tx
+    }
+
+    fn moisture_service(mut self, rx: Receiver<MoistureServiceMessage>) {
+        info!("Hello from Moisture service!");
+        // Requests do not postpone the sampling
+        let mut next_sample_at = Instant::now() + self.sample_period;
+
+        loop {
+            match rx.recv_timeout(next_sample_at.saturating_duration_since(Instant::now())) {
+                Ok(MoistureServiceMessage::Sample(section, tx)) => {
+                    let _ = tx.send(self.sample(&section));
+                }
+                Ok(MoistureServiceMessage::GetReadings(tx)) => {
+                    let _ = tx.send(self.readings.clone());
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    self.sample_all();
+                    next_sample_at = Instant::now() + self.sample_period;
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+    }
+
+    fn sample_all(&mut self) {
+        let sections = self
+            .probes
+            .iter()
+            .map(|probe| probe.section.clone())
+            .collect::<Vec<_>>();
+        for section in sections {
+            self.sample(&section);
+        }
+    }
+
+    /// Reading is kept for the status, probe that cannot be read has none
+    fn sample(&mut self, section: &SectionId) -> Option<MoistureReading> {
+        let probe = self
+            .probes
+            .iter_mut()
+            .find(|probe| probe.section == *section)?;
+
+        let reading = match average_mv(&mut probe.probe) {
+            Ok(millivolts) => Some(MoistureReading {
+                moisture: probe.config.moisture(millivolts),
+                millivolts,
+                skip_above: probe.config.skip_above,
+            }),
+            Err(e) => {
+                error!("Cannot read {section} moisture probe {e:?}");
+                None
+            }
+        };
+        log::debug!("{section} moisture {reading:?}");
+
+        match reading {
+            Some(reading) => self.readings.insert(section.clone(), reading),
+            None => self.readings.remove(section),
+        };
+        reading
+    }
+}
+
+fn average_mv(probe: &mut impl MoistureProbe) -> Result<u16> {
+    let mut sum = 0;
+    for _ in 0..SAMPLES_PER_READING {
+        sum += u32::from(probe.read_mv()?);
+    }
+
+    let average = sum / SAMPLES_PER_READING;
+    if average == 0 {
+        // Probe output never gets that low, the wire is cut
+        bail!("probe is not connected");
+    }
+    Ok(average as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::host::FakeMoistureProbe;
+
+    use super::*;
+
+    fn probe_config(gpio: i32, skip_above: u8) -> MoistureProbeConfig {
+        MoistureProbeConfig {
+            gpio,
+            dry_mv: 2600,
+            wet_mv: 1100,
+            skip_above,
+        }
+    }
+
+    #[test]
+    fn probes_are_sampled_on_request_and_periodically() {
+        let vegs = FakeMoistureProbe::new(1850);
+        let grass = FakeMoistureProbe::new(2600);
+        let moisture_tx = MoistureService::new(vec![
+            ("Vegs".into(), probe_config(36, 40), vegs.clone()),
+            ("Grass".into(), probe_config(39, 60), grass.clone()),
+        ])
+        .with_sample_period(Duration::from_millis(100))
+        .start();
+
+        let sample = |section: &str| {
+            let (tx, rx) = channel();
+            moisture_tx
+                .send(MoistureServiceMessage::Sample(section.into(), tx))
+                .unwrap();
+            rx.recv_timeout(Duration::from_secs(1)).unwrap()
+        };
+        let vegs_reading = sample("Vegs").unwrap();
+        assert_eq!(
+            vegs_reading,
+            MoistureReading {
+                moisture: 50,
+                millivolts: 1850,
+                skip_above: 40,
+            }
+        );
+        assert!(vegs_reading.is_moist());
+        assert!(!sample("Grass").unwrap().is_moist());
+        assert_eq!(sample("Flowers"), None);
+
+        vegs.set_mv(2600);
+        grass.set_mv(0);
+        std::thread::sleep(Duration::from_millis(300));
+
+        let (tx, rx) = channel();
+        moisture_tx
+            .send(MoistureServiceMessage::GetReadings(tx))
+            .unwrap();
+        let readings = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        // Disconnected probe has no reading
+        assert_eq!(
+            readings.keys().collect::<Vec<_>>(),
+            [&SectionId::from("Vegs")]
+        );
+        assert_eq!(readings[&SectionId::from("Vegs")].moisture, 0);
+    }
+
+    #[test]
+    fn probes_are_sampled_while_the_readings_are_polled() {
+        let vegs = FakeMoistureProbe::new(1850);
+        let moisture_tx =
+            MoistureService::new(vec![("Vegs".into(), probe_config(36, 40), vegs.clone())])
+                .with_sample_period(Duration::from_millis(200))
+                .start();
+
+        // Status polls the readings more often than the probes are sampled
+        vegs.set_mv(2600);
+        let refreshed = (0..20).any(|_| {
+            std::thread::sleep(Duration::from_millis(50));
+            let (tx, rx) = channel();
+            moisture_tx
+                .send(MoistureServiceMessage::GetReadings(tx))
+                .unwrap();
+            rx.recv_timeout(Duration::from_secs(1)).unwrap()[&SectionId::from("Vegs")].moisture == 0
+        });
+        assert!(refreshed);
+    }
+}
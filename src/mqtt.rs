@@ -0,0 +1,453 @@
+//! Home Assistant integration over MQTT: entities announced with the discovery, their state and the commands.
+//! Every section is a switch, the schedule is a switch, closing all valves is a button,
+//! RTC temperature and the next run are sensors.
+
+use std::{
+    sync::mpsc::{channel, RecvTimeoutError, Sender},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use chrono::TimeDelta;
+use log::{info, warn};
+use serde_json::{json, Value};
+
+use crate::{
+    clock::{ClockServiceChannel, ClockServiceMessage},
+    events::{self, Event, EventsServiceChannel},
+    sections::{SectionDuration, SectionId, SectionsConfig},
+    watering::{WateringServiceChannel, WateringServiceMessage, WateringStatus, DEFAULT_PROGRAM},
+};
+
+pub const DEFAULT_DISCOVERY_PREFIX: &str = "homeassistant";
+/// State is published on every event, and this often for the temperature to stay fresh
+const STATE_PERIOD: Duration = Duration::from_secs(60);
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+const ON: &[u8] = b"ON";
+const OFF: &[u8] = b"OFF";
+/// Home Assistant shows the sensor as unknown
+const NONE: &[u8] = b"None";
+
+/// Connection to the MQTT broker, messages are published with QoS 1
+pub trait MqttClient: Send + 'static {
+    fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) -> Result<()>;
+    fn subscribe(&mut self, topic: &str) -> Result<()>;
+
+    /// Start passing the (re)connections and the messages arriving on the subscribed topics
+    /// to the MQTT service
+    fn start_receiving(&mut self, tx: MqttServiceChannel) -> Result<()>;
+}
+
+#[derive(Debug)]
+pub enum MqttServiceMessage {
+    /// Client got (re)connected, the broker might have forgotten the subscriptions
+    Connected,
+    Received {
+        topic: String,
+        payload: Vec<u8>,
+    },
+    /// Something happened, state is published again
+    Event(Event),
+}
+pub type MqttServiceChannel = Sender<MqttServiceMessage>;
+
+/// Topic the availability is published to, the client sets it as the last will to `offline`
+pub fn availability_topic(node_id: &str) -> String {
+    format!("{node_id}/availability")
+}
+
+/// Whether the topic matches the subscription filter, with `+` and `#` wildcards
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut filter = filter.split('/');
+    let mut topic = topic.split('/');
+
+    loop {
+        match (filter.next(), topic.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(filter), Some(topic)) if filter == topic => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// Topic level of the section, Home Assistant allows only `[a-zA-Z0-9_-]` in the object id
+fn object_id(section: &SectionId) -> String {
+    section
+        .as_str()
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+pub struct MqttService<Client: MqttClient> {
+    client: Client,
+    node_id: String,
+    discovery_prefix: String,
+    sections: SectionsConfig,
+    watering_tx: WateringServiceChannel,
+    clock_tx: ClockServiceChannel,
+    events_tx: Option<EventsServiceChannel>,
+    /// How long the section is watered when switched on
+    ad_hoc_duration: SectionDuration,
+}
+
+impl<Client: MqttClient> MqttService<Client> {
+    /// `node_id` is the root of the topics and identifies the device in Home Assistant
+    pub fn new(
+        client: Client,
+        node_id: &str,
+        sections: SectionsConfig,
+        watering_tx: WateringServiceChannel,
+        clock_tx: ClockServiceChannel,
+    ) -> Self {
+        Self {
+            client,
+            node_id: node_id.to_string(),
+            discovery_prefix: DEFAULT_DISCOVERY_PREFIX.to_string(),
+            sections,
+            watering_tx,
+            clock_tx,
+            events_tx: None,
+            ad_hoc_duration: TimeDelta::minutes(10)
+                .try_into()
+                .expect("10 minutes is a valid duration"),
+        }
+    }
+
+    /// State is published as soon as something happens, not only periodically
+    pub fn with_events(mut self, events_tx: EventsServiceChannel) -> Self {
+        self.events_tx = Some(events_tx);
+        self
+    }
+
+    pub fn with_discovery_prefix(mut self, discovery_prefix: &str) -> Self {
+        self.discovery_prefix = discovery_prefix.to_string();
+        self
+    }
+
+    /// 10 minutes by default
+    pub fn with_ad_hoc_duration(mut self, duration: SectionDuration) -> Self {
+        self.ad_hoc_duration = duration;
+        self
+    }
+
+    /// Starts the MQTT Service, returns the MqttServiceChannel to communicate with it
+    pub fn start(mut self) -> MqttServiceChannel {
+        let (tx, rx) = channel();
+
+        self.client
+            .start_receiving(tx.clone())
+            .expect("Cannot start receiving MQTT messages");
+
+        if let Some(events_tx) = &self.events_tx {
+            match events::subscribe(events_tx) {
+                Some(events) => {
+                    let tx = tx.clone();
+                    std::thread::Builder::new()
+                        .name("mqtt events".to_string())
+                        .spawn(move || {
+                            while let Ok(event) = events.recv() {
+                                if tx.send(MqttServiceMessage::Event(event)).is_err() {
+                                    break;
+                                }
+                            }
+                        })
+                        .expect("Cannot spawn MQTT events forwarder");
+                }
+                None => warn!("Events service is gone, MQTT state is published periodically"),
+            }
+        }
+
+        std::thread::Builder::new()
+            .name("mqtt service".to_string())
+            .spawn(move || {
+                info!("Hello from MQTT service!");
+
+                loop {
+                    match rx.recv_timeout(STATE_PERIOD) {
+                        Ok(MqttServiceMessage::Connected) => {
+                            info!("Connected to the MQTT broker");
+                            if let Err(e) = self.announce() {
+                                warn!("Cannot announce the entities {e:?}");
+                            }
+                        }
+                        Ok(MqttServiceMessage::Received { topic, payload }) => {
+                            self.handle_command(&topic, &payload)
+                        }
+                        Ok(MqttServiceMessage::Event(_)) | Err(RecvTimeoutError::Timeout) => {}
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+
+                    if let Err(e) = self.publish_state() {
+                        warn!("Cannot publish the state {e:?}");
+                    }
+                }
+            })
+            .expect("Cannot spawn MQTT service");
+
+        tx
+    }
+
+    fn topic(&self, suffix: &str) -> String {
+        format!("{}/{suffix}", self.node_id)
+    }
+
+    fn section_topic(&self, section: &SectionId, suffix: &str) -> String {
+        self.topic(&format!("section/{}/{suffix}", object_id(section)))
+    }
+
+    /// Discovery configs, retained so Home Assistant finds them after its restart,
+    /// and the subscriptions for the commands
+    fn announce(&mut self) -> Result<()> {
+        let device = json!({
+            "identifiers": [self.node_id],
+            "name": "Water my garden",
+            "manufacturer": "water_my_garden_rs",
+            "model": "ESP32",
+            "sw_version": env!("CARGO_PKG_VERSION"),
+        });
+        let availability = availability_topic(&self.node_id);
+
+        let mut entities = vec![];
+        for section in self.sections.sections() {
+            entities.push((
+                "switch",
+                format!("section_{}", object_id(&section.id)),
+                json!({
+                    "name": section.name,
+                    "icon": "mdi:sprinkler-variant",
+                    "command_topic": self.section_topic(&section.id, "set"),
+                    "state_topic": self.section_topic(&section.id, "state"),
+                }),
+            ));
+        }
+        entities.push((
+            "switch",
+            "watering".to_string(),
+            json!({
+                "name": "Schedule",
+                "icon": "mdi:calendar-clock",
+                "command_topic": self.topic("watering/set"),
+                "state_topic": self.topic("watering/state"),
+            }),
+        ));
+        entities.push((
+            "button",
+            "close_all_valves".to_string(),
+            json!({
+                "name": "Close all valves",
+                "icon": "mdi:water-off",
+                "command_topic": self.topic("close_all_valves/press"),
+            }),
+        ));
+        entities.push((
+            "sensor",
+            "temperature".to_string(),
+            json!({
+                "name": "RTC temperature",
+                "device_class": "temperature",
+                "unit_of_measurement": "°C",
+                "state_topic": self.topic("temperature"),
+            }),
+        ));
+        entities.push((
+            "sensor",
+            "next_run".to_string(),
+            json!({
+                "name": "Next run",
+                "icon": "mdi:timer-outline",
+                "state_topic": self.topic("next_run"),
+            }),
+        ));
+
+        for (component, object_id, mut config) in entities {
+            let unique_id = format!("{}_{object_id}", self.node_id);
+            if let Value::Object(config) = &mut config {
+                config.insert("unique_id".to_string(), unique_id.clone().into());
+                config.insert("object_id".to_string(), unique_id.into());
+                config.insert(
+                    "availability_topic".to_string(),
+                    availability.clone().into(),
+                );
+                config.insert("device".to_string(), device.clone());
+            }
+
+            let topic = format!(
+                "{}/{component}/{}/{object_id}/config",
+                self.discovery_prefix, self.node_id
+            );
+            self.client
+                .publish(&topic, config.to_string().as_bytes(), true)?;
+        }
+
+        for filter in [
+            self.topic("section/+/set"),
+            self.topic("watering/set"),
+            self.topic("close_all_valves/press"),
+        ] {
+            self.client.subscribe(&filter)?;
+        }
+
+        self.client.publish(&availability, b"online", true)
+    }
+
+    /// Command from Home Assistant, mapped onto the watering service message
+    fn handle_command(&mut self, topic: &str, payload: &[u8]) {
+        info!("MQTT command {topic}: {}", String::from_utf8_lossy(payload));
+
+        let command = topic
+            .strip_prefix(&self.node_id)
+            .and_then(|topic| topic.strip_prefix('/'))
+            .unwrap_or_default();
+        let result = match command.split('/').collect::<Vec<_>>()[..] {
+            ["section", object, "set"] => self.switch_section(object, payload),
+            ["watering", "set"] => self.switch_watering(payload),
+            ["close_all_valves", "press"] => self.send(WateringServiceMessage::CloseAllValves),
+            _ => {
+                warn!("Unknown MQTT command {topic}, ignoring");
+                Ok(())
+            }
+        };
+
+        if let Err(e) = result {
+            warn!("MQTT command {topic} failed {e:#}");
+        }
+    }
+
+    fn switch_section(&mut self, object: &str, payload: &[u8]) -> Result<()> {
+        let section = self
+            .sections
+            .ids()
+            .into_iter()
+            .find(|section| object_id(section) == object)
+            .with_context(|| format!("There is no {object} section"))?;
+
+        match payload {
+            ON => {
+                let (tx, rx) = channel();
+                self.send(WateringServiceMessage::EnableSectionFor(
+                    section,
+                    self.ad_hoc_duration,
+                    tx,
+                ))?;
+                rx.recv_timeout(REPLY_TIMEOUT)
+                    .context("Watering service does not respond")??;
+                Ok(())
+            }
+            // Only one section is open at a time
+            OFF => self.send(WateringServiceMessage::CloseAllValves),
+            _ => anyhow::bail!("Payload is neither ON nor OFF"),
+        }
+    }
+
+    fn switch_watering(&mut self, payload: &[u8]) -> Result<()> {
+        match payload {
+            // There is no message enabling the schedule as it was, the default program is armed again
+            ON => {
+                let start_at = self
+                    .watering_status()?
+                    .programs
+                    .get(DEFAULT_PROGRAM)
+                    .and_then(|program| program.start_at)
+                    .context("Default program has no start time, set it through the API")?;
+                let (tx, rx) = channel();
+                self.send(WateringServiceMessage::StartWateringAt(start_at, tx))?;
+                rx.recv_timeout(REPLY_TIMEOUT)
+                    .context("Watering service does not respond")??;
+                Ok(())
+            }
+            OFF => self.send(WateringServiceMessage::DisableWatering),
+            _ => anyhow::bail!("Payload is neither ON nor OFF"),
+        }
+    }
+
+    fn send(&self, msg: WateringServiceMessage) -> Result<()> {
+        self.watering_tx
+            .send(msg)
+            .context("Watering service is not running")
+    }
+
+    fn watering_status(&self) -> Result<WateringStatus> {
+        let (tx, rx) = channel();
+        self.send(WateringServiceMessage::GetStatus(tx))?;
+        rx.recv_timeout(REPLY_TIMEOUT)
+            .context("Watering service does not respond")
+    }
+
+    /// Retained, so Home Assistant gets it after its restart
+    fn publish_state(&mut self) -> Result<()> {
+        let watering = self.watering_status()?;
+
+        for section in self.sections.ids() {
+            let state = match watering.current_section == Some(section.clone()) {
+                true => ON,
+                false => OFF,
+            };
+            let topic = self.section_topic(&section, "state");
+            self.client.publish(&topic, state, true)?;
+        }
+
+        let enabled = if watering.enabled { ON } else { OFF };
+        let topic = self.topic("watering/state");
+        self.client.publish(&topic, enabled, true)?;
+
+        let next_run = watering
+            .next_run
+            .map(|run| format!("{} {}", run.at.format("%Y-%m-%d %H:%M"), run.program));
+        let topic = self.topic("next_run");
+        self.client.publish(
+            &topic,
+            next_run
+                .as_ref()
+                .map_or(NONE, |next_run| next_run.as_bytes()),
+            true,
+        )?;
+
+        let (tx, rx) = channel();
+        self.clock_tx
+            .send(ClockServiceMessage::GetStatus(tx))
+            .context("Clock service is not running")?;
+        let clock = rx
+            .recv_timeout(REPLY_TIMEOUT)
+            .context("Clock service does not respond")?;
+        let topic = self.topic("temperature");
+        self.client
+            .publish(&topic, clock.temperature().to_string().as_bytes(), true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards_match_topic_levels() {
+        assert!(topic_matches(
+            "garden/section/+/set",
+            "garden/section/Grass/set"
+        ));
+        assert!(!topic_matches("garden/section/+/set", "garden/section/set"));
+        assert!(!topic_matches(
+            "garden/section/+/set",
+            "garden/section/Grass/state"
+        ));
+        assert!(topic_matches("garden/#", "garden/section/Grass/state"));
+        assert!(topic_matches("garden/watering/set", "garden/watering/set"));
+        assert!(!topic_matches(
+            "garden/watering/set",
+            "garden/watering/set/now"
+        ));
+    }
+
+    #[test]
+    fn object_id_is_safe_for_home_assistant() {
+        assert_eq!(object_id(&SectionId::from("Grass")), "Grass");
+        assert_eq!(object_id(&SectionId::from("Front yard/2")), "Front_yard_2");
+    }
+}
@@ -0,0 +1,215 @@
+//! Normally-closed rain switch on an input pin: closed while dry, open once the sensor gets wet.
+//! Watering service subscribes for the changes, wet sensor skips the scheduled runs and aborts the one in progress.
+
+use std::{
+    sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use log::{error, info};
+
+use crate::{
+    events::{Event, EventsServiceChannel, Publisher},
+    watering::{WateringServiceChannel, WateringServiceMessage},
+};
+
+/// Switch bounces, and the sensor dries out unevenly, the state is read once it settles
+pub const DEFAULT_DEBOUNCE: Duration = Duration::from_secs(5);
+
+pub trait RainSensor: Send + 'static {
+    /// Switch is open: the sensor is wet, or the wire is cut - watering waits either way
+    fn is_wet(&mut self) -> Result<bool>;
+
+    /// Start listening on the input pin, every change is passed to the Rain service
+    /// as `RainServiceMessage::InterruptArrived`
+    fn start_interrupt_service(&mut self, tx: RainServiceChannel) -> Result<()>;
+    /// Interrupt gets disabled after it fires, re-enable it
+    fn enable_interrupt(&mut self) -> Result<()>;
+}
+
+#[derive(Debug)]
+pub enum RainServiceMessage {
+    InterruptArrived(u32),
+    /// Subscriber gets the current state right away, then `WateringServiceMessage::RainChanged` on every change
+    Subscribe(WateringServiceChannel),
+    IsWet(Sender<bool>),
+}
+pub type RainServiceChannel = Sender<RainServiceMessage>;
+
+pub struct RainService<Sensor: RainSensor> {
+    sensor: Sensor,
+    wet: bool,
+    debounce: Duration,
+    /// Sensor is read at that point, None if nothing changed
+    settles_at: Option<Instant>,
+    subscribers: Vec<WateringServiceChannel>,
+    events: Publisher,
+}
+
+impl<Sensor: RainSensor> RainService<Sensor> {
+    pub fn new(mut sensor: Sensor) -> Result<Self> {
+        let wet = sensor.is_wet()?;
+        info!("Rain sensor is {}", describe(wet));
+
+        Ok(Self {
+            sensor,
+            wet,
+            debounce: DEFAULT_DEBOUNCE,
+            settles_at: None,
+            subscribers: vec![],
+            events: Publisher::default(),
+        })
+    }
+
+    /// How long the sensor has to stay still before its state is taken
+    pub fn with_debounce(mut self, debounce: Duration) -> Self {
+        self.debounce = debounce;
+        self
+    }
+
+    /// Changes of the sensor are published as the events
+    pub fn with_events(mut self, events_tx: EventsServiceChannel) -> Self {
+        self.events = Publisher::new(events_tx);
+        self
+    }
+
+    /// Starts the Rain Service, returns the RainServiceChannel to communicate with it
+    pub fn start(mut self) -> RainServiceChannel {
+        let (tx, rx) = channel();
+
+        self.sensor
+            .start_interrupt_service(tx.clone())
+            .expect("Cannot start rain sensor interrupt service");
+        self.enable_interrupt();
+
+        std::thread::Builder::new()
+            .name("rain service".to_string())
+            .spawn(move || self.rain_service(rx))
+            .expect("Cannot spawn Rain service");
+
+        tx
+    }
+
+    fn rain_service(mut self, rx: Receiver<RainServiceMessage>) {
+        info!("Hello from Rain service!");
+
+        loop {
+            let msg = match self.settles_at {
+                Some(settles_at) => {
+                    match rx.recv_timeout(settles_at.saturating_duration_since(Instant::now())) {
+                        Ok(msg) => msg,
+                        Err(RecvTimeoutError::Timeout) => {
+                            self.settled();
+                            continue;
+                        }
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+                None => match rx.recv() {
+                    Ok(msg) => msg,
+                    Err(_) => break,
+                },
+            };
+
+            match msg {
+                RainServiceMessage::InterruptArrived(int_count) => {
+                    log::debug!("Rain sensor changed #{int_count}");
+                    // Every bounce postpones the read
+                    self.settles_at = Some(Instant::now() + self.debounce);
+                    self.enable_interrupt();
+                }
+                RainServiceMessage::Subscribe(tx) => {
+                    if tx
+                        .send(WateringServiceMessage::RainChanged(self.wet))
+                        .is_ok()
+                    {
+                        self.subscribers.push(tx);
+                    }
+                }
+                RainServiceMessage::IsWet(tx) => {
+                    let _ = tx.send(self.wet);
+                }
+            }
+        }
+    }
+
+    /// Sensor stayed still for long enough, subscribers are told if it changed
+    fn settled(&mut self) {
+        self.settles_at = None;
+
+        let wet = match self.sensor.is_wet() {
+            Ok(wet) => wet,
+            Err(e) => {
+                error!(
+                    "Cannot read the rain sensor, keeping it {} {e:?}",
+                    describe(self.wet)
+                );
+                return;
+            }
+        };
+        if wet == self.wet {
+            return;
+        }
+
+        info!("Rain sensor got {}", describe(wet));
+        self.wet = wet;
+        self.events.publish(Event::RainChanged { wet });
+        // Try to send to the subscribers, if fails, it means rx "unsubscribed", filter such entries
+        self.subscribers
+            .retain(|tx| tx.send(WateringServiceMessage::RainChanged(wet)).is_ok());
+    }
+
+    fn enable_interrupt(&mut self) {
+        // Changes are still noticed on the next interrupt, or never if it keeps failing
+        if let Err(e) = self.sensor.enable_interrupt() {
+            error!("Cannot enable rain sensor interrupt {e:?}");
+        }
+    }
+}
+
+fn describe(wet: bool) -> &'static str {
+    if wet {
+        "wet"
+    } else {
+        "dry"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::host::FakeRainSensor;
+
+    use super::*;
+
+    #[test]
+    fn subscribers_get_the_settled_state() {
+        let sensor = FakeRainSensor::new();
+        let rain_tx = RainService::new(sensor.clone())
+            .unwrap()
+            .with_debounce(Duration::from_millis(100))
+            .start();
+
+        let (tx, rx) = channel();
+        rain_tx.send(RainServiceMessage::Subscribe(tx)).unwrap();
+        let changed = || match rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(WateringServiceMessage::RainChanged(wet)) => wet,
+            msg => panic!("Unexpected {msg:?}"),
+        };
+        assert!(!changed());
+
+        // Bounces are not passed on
+        sensor.set_wet(true);
+        sensor.set_wet(false);
+        sensor.set_wet(true);
+        assert!(changed());
+        assert!(rx.recv_timeout(Duration::from_millis(300)).is_err());
+
+        let (tx, wet) = channel();
+        rain_tx.send(RainServiceMessage::IsWet(tx)).unwrap();
+        assert!(wet.recv_timeout(Duration::from_secs(1)).unwrap());
+
+        sensor.set_wet(false);
+        assert!(!changed());
+    }
+}
@@ -0,0 +1,272 @@
+//! Catching up on the watering that did not happen, or got cut short, because the board was off
+
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+use chrono::{NaiveDateTime, TimeDelta};
+use serde::{Deserialize, Serialize};
+
+use crate::{sections::SectionId, storage::Storage, watering::WateringConfig};
+
+/// Runs that were due longer ago than that are not worth catching up on
+pub const MAX_RECOVERY_DELAY: TimeDelta = TimeDelta::hours(12);
+
+/// What to do on boot about the run that got missed or interrupted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecoveryPolicy {
+    /// Wait for the next scheduled run
+    #[default]
+    Skip,
+    /// Run the whole program right away
+    RunNow,
+    /// Water only the sections the run did not get to
+    RunRemaining,
+}
+
+impl FromStr for RecoveryPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "skip" => Ok(RecoveryPolicy::Skip),
+            "run_now" => Ok(RecoveryPolicy::RunNow),
+            "run_remaining" => Ok(RecoveryPolicy::RunRemaining),
+            _ => bail!("{s:?} is not one of skip, run_now, run_remaining"),
+        }
+    }
+}
+
+/// Progress of the latest program run, stored as the run goes
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LastRun {
+    pub program: String,
+    /// Local time the run was due at, sections are checked against its date
+    pub started_at: NaiveDateTime,
+    /// Sections watered to the end
+    pub completed: Vec<SectionId>,
+    /// Run got to the end, or was aborted on purpose
+    pub finished: bool,
+}
+
+impl LastRun {
+    const STORAGE_KEY: &'static str = "last_run";
+
+    /// None if no program ever ran
+    pub fn load(storage: &mut dyn Storage) -> Result<Option<Self>> {
+        storage.load(Self::STORAGE_KEY)
+    }
+
+    pub fn store(&self, storage: &mut dyn Storage) -> Result<()> {
+        storage.store(Self::STORAGE_KEY, self)
+    }
+}
+
+/// Run the board catches up on
+#[derive(Debug, PartialEq)]
+pub struct Recovery {
+    pub program: String,
+    /// Local time the run was due at
+    pub started_at: NaiveDateTime,
+    /// Sections that are not watered again
+    pub completed: Vec<SectionId>,
+}
+
+/// Decides what to catch up on when the board boots at `now` (local time).
+/// Only the most recent run is considered: it was missed if it started after the `last_run`,
+/// and interrupted if it is the `last_run` that did not finish.
+pub fn recovery(
+    policy: RecoveryPolicy,
+    config: &WateringConfig,
+    last_run: Option<&LastRun>,
+    now: NaiveDateTime,
+) -> Option<Recovery> {
+    // Nothing ever ran, the board is fresh - there is nothing it could have missed
+    let last_run = last_run?;
+    if policy == RecoveryPolicy::Skip || !config.enabled {
+        return None;
+    }
+
+    let (program, due_at) = config
+        .programs
+        .iter()
+        .filter_map(|(name, program)| {
+            let start_at = program.start_at?;
+            let today = now.date().and_time(start_at);
+            let due_at = if today <= now {
+                today
+            } else {
+                today - TimeDelta::days(1)
+            };
+            program.rule.is_due(due_at.date()).then_some((name, due_at))
+        })
+        .max_by_key(|(_, due_at)| *due_at)?;
+
+    if now - due_at > MAX_RECOVERY_DELAY {
+        return None;
+    }
+
+    let completed = if last_run.started_at < due_at {
+        // Board was off when the run was due
+        vec![]
+    } else if last_run.finished || last_run.program != *program {
+        return None;
+    } else if policy == RecoveryPolicy::RunRemaining {
+        last_run.completed.clone()
+    } else {
+        vec![]
+    };
+
+    Some(Recovery {
+        program: program.clone(),
+        started_at: due_at,
+        completed,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDate, NaiveTime, Weekday};
+
+    use crate::{schedule::ScheduleRule, watering::Program};
+
+    use super::*;
+
+    fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 5, day)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+    }
+
+    /// Morning program at 6:00 and evening one at 20:00, evening runs on Wednesdays only
+    fn config() -> WateringConfig {
+        WateringConfig {
+            enabled: true,
+            paused_until: None,
+            programs: [
+                (
+                    "morning".to_string(),
+                    Program {
+                        start_at: NaiveTime::from_hms_opt(6, 0, 0),
+                        ..Default::default()
+                    },
+                ),
+                (
+                    "evening".to_string(),
+                    Program {
+                        start_at: NaiveTime::from_hms_opt(20, 0, 0),
+                        rule: ScheduleRule::Weekdays(vec![Weekday::Wed]),
+                        ..Default::default()
+                    },
+                ),
+            ]
+            .into(),
+        }
+    }
+
+    fn last_run(program: &str, started_at: NaiveDateTime, finished: bool) -> LastRun {
+        LastRun {
+            program: program.to_string(),
+            started_at,
+            completed: vec![SectionId::from("Vegs")],
+            finished,
+        }
+    }
+
+    #[test]
+    fn missed_run_is_caught_up() {
+        // 2024-05-01 is Wednesday, the last run was the evening one a week before
+        let last = last_run("evening", at(1, 20, 0) - TimeDelta::days(7), true);
+
+        for policy in [RecoveryPolicy::RunNow, RecoveryPolicy::RunRemaining] {
+            assert_eq!(
+                recovery(policy, &config(), Some(&last), at(1, 7, 30)),
+                Some(Recovery {
+                    program: "morning".to_string(),
+                    started_at: at(1, 6, 0),
+                    completed: vec![],
+                })
+            );
+        }
+
+        // Evening program is the latest one due on Wednesday
+        assert_eq!(
+            recovery(RecoveryPolicy::RunNow, &config(), Some(&last), at(1, 21, 0))
+                .map(|recovery| recovery.program),
+            Some("evening".to_string())
+        );
+
+        assert_eq!(
+            recovery(RecoveryPolicy::Skip, &config(), Some(&last), at(1, 7, 30)),
+            None
+        );
+    }
+
+    #[test]
+    fn interrupted_run_is_resumed_according_to_policy() {
+        let last = last_run("morning", at(1, 6, 0), false);
+
+        assert_eq!(
+            recovery(
+                RecoveryPolicy::RunRemaining,
+                &config(),
+                Some(&last),
+                at(1, 6, 20)
+            ),
+            Some(Recovery {
+                program: "morning".to_string(),
+                started_at: at(1, 6, 0),
+                completed: vec![SectionId::from("Vegs")],
+            })
+        );
+        assert_eq!(
+            recovery(RecoveryPolicy::RunNow, &config(), Some(&last), at(1, 6, 20))
+                .map(|recovery| recovery.completed),
+            Some(vec![])
+        );
+
+        let finished = last_run("morning", at(1, 6, 0), true);
+        assert_eq!(
+            recovery(
+                RecoveryPolicy::RunRemaining,
+                &config(),
+                Some(&finished),
+                at(1, 6, 20)
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn nothing_is_recovered_when_it_is_too_late_or_never_ran() {
+        let last = last_run("morning", at(1, 6, 0), false);
+        assert_eq!(
+            recovery(RecoveryPolicy::RunNow, &config(), Some(&last), at(1, 19, 0)),
+            None
+        );
+
+        assert_eq!(
+            recovery(RecoveryPolicy::RunNow, &config(), None, at(1, 7, 0)),
+            None
+        );
+
+        let disabled = WateringConfig {
+            enabled: false,
+            ..config()
+        };
+        assert_eq!(
+            recovery(RecoveryPolicy::RunNow, &disabled, Some(&last), at(1, 6, 20)),
+            None
+        );
+    }
+
+    #[test]
+    fn policy_is_parsed_from_config() {
+        assert_eq!(
+            "run_remaining".parse::<RecoveryPolicy>().unwrap(),
+            RecoveryPolicy::RunRemaining
+        );
+        assert!("later".parse::<RecoveryPolicy>().is_err());
+    }
+}
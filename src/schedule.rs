@@ -0,0 +1,140 @@
+//! Rules deciding on which days the watering happens
+
+use std::num::NonZeroU32;
+
+use chrono::{Datelike, NaiveDate, TimeDelta, Weekday};
+use serde::{Deserialize, Serialize};
+
+/// Days on which a program, or a section within it, is watered
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ScheduleRule {
+    #[default]
+    Daily,
+    /// Given days of week, like `["Mon", "Wed", "Fri"]`
+    Weekdays(Vec<Weekday>),
+    /// Every `days` days, counting from the `anchor` date
+    EveryNDays { days: NonZeroU32, anchor: NaiveDate },
+    /// Odd days of month
+    OddDays,
+    /// Even days of month
+    EvenDays,
+}
+
+impl ScheduleRule {
+    pub fn is_due(&self, date: NaiveDate) -> bool {
+        match self {
+            ScheduleRule::Daily => true,
+            ScheduleRule::Weekdays(weekdays) => weekdays.contains(&date.weekday()),
+            ScheduleRule::EveryNDays { days, anchor } => {
+                // Works for the dates before the anchor as well
+                (date - *anchor).num_days().rem_euclid(days.get() as i64) == 0
+            }
+            ScheduleRule::OddDays => date.day() % 2 == 1,
+            ScheduleRule::EvenDays => date.day() % 2 == 0,
+        }
+    }
+
+    /// First day the rule is due on, `from` included. None if it is not due within a year,
+    /// e.g. the weekdays are empty.
+    pub fn next_due(&self, from: NaiveDate) -> Option<NaiveDate> {
+        match self {
+            ScheduleRule::EveryNDays { days, anchor } => {
+                let days = days.get() as i64;
+                let since_due = (from - *anchor).num_days().rem_euclid(days);
+                from.checked_add_signed(TimeDelta::days((days - since_due) % days))
+            }
+            _ => from.iter_days().take(366).find(|date| self.is_due(*date)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 5, day).unwrap()
+    }
+
+    #[test]
+    fn weekdays_rule_is_due_on_given_days() {
+        let rule = ScheduleRule::Weekdays(vec![Weekday::Mon, Weekday::Wed, Weekday::Fri]);
+
+        // 2024-05-06 is Monday
+        let due = (6..=12)
+            .filter(|day| rule.is_due(date(*day)))
+            .collect::<Vec<_>>();
+        assert_eq!(due, vec![6, 8, 10]);
+    }
+
+    #[test]
+    fn every_n_days_rule_counts_from_anchor() {
+        let rule = ScheduleRule::EveryNDays {
+            days: NonZeroU32::new(3).unwrap(),
+            anchor: date(10),
+        };
+
+        let due = (1..=16)
+            .filter(|day| rule.is_due(date(*day)))
+            .collect::<Vec<_>>();
+        assert_eq!(due, vec![1, 4, 7, 10, 13, 16]);
+    }
+
+    #[test]
+    fn odd_and_even_days_rules() {
+        assert!(ScheduleRule::OddDays.is_due(date(31)));
+        assert!(!ScheduleRule::OddDays.is_due(date(30)));
+        assert!(ScheduleRule::EvenDays.is_due(date(30)));
+        assert!(!ScheduleRule::EvenDays.is_due(date(31)));
+        assert!(ScheduleRule::Daily.is_due(date(31)));
+    }
+
+    #[test]
+    fn next_due_day_is_found() {
+        // 2024-05-01 is Wednesday
+        let rule = ScheduleRule::Weekdays(vec![Weekday::Mon]);
+        assert_eq!(rule.next_due(date(1)), Some(date(6)));
+        assert_eq!(rule.next_due(date(6)), Some(date(6)));
+        assert_eq!(ScheduleRule::Weekdays(vec![]).next_due(date(1)), None);
+
+        let rule = ScheduleRule::EveryNDays {
+            days: NonZeroU32::new(3).unwrap(),
+            anchor: date(10),
+        };
+        assert_eq!(rule.next_due(date(2)), Some(date(4)));
+        assert_eq!(rule.next_due(date(13)), Some(date(13)));
+        // 31st is followed by the 1st, both odd
+        assert_eq!(
+            ScheduleRule::EvenDays.next_due(date(31)),
+            NaiveDate::from_ymd_opt(2024, 6, 2)
+        );
+    }
+
+    #[test]
+    fn rules_are_read_from_json() {
+        let rules: Vec<ScheduleRule> = serde_json::from_str(
+            r#"["daily", {"weekdays": ["Mon", "fri"]}, {"every_n_days": {"days": 3, "anchor": "2024-05-01"}}, "odd_days", "even_days"]"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            rules,
+            vec![
+                ScheduleRule::Daily,
+                ScheduleRule::Weekdays(vec![Weekday::Mon, Weekday::Fri]),
+                ScheduleRule::EveryNDays {
+                    days: NonZeroU32::new(3).unwrap(),
+                    anchor: date(1)
+                },
+                ScheduleRule::OddDays,
+                ScheduleRule::EvenDays,
+            ]
+        );
+
+        assert!(serde_json::from_str::<ScheduleRule>(
+            r#"{"every_n_days": {"days": 0, "anchor": "2024-05-01"}}"#
+        )
+        .is_err());
+    }
+}
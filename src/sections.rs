@@ -1,28 +1,195 @@
 //! Abstraction over hardware for enabling/disabling sections
 
 use std::{
+    collections::{hash_map::Entry, HashMap},
     fmt::{Debug, Display},
-    sync::mpsc::{Receiver, Sender},
+    sync::mpsc::{Receiver, RecvTimeoutError, Sender},
+    time::{Duration, Instant},
 };
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use chrono::TimeDelta;
-use enum_iterator::Sequence;
-use esp_idf_svc::hal::{
-    gpio::{Output, OutputPin, PinDriver},
-    peripheral::Peripheral,
-};
 use serde::{Deserialize, Deserializer, Serialize};
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Sequence, Hash, Eq, Copy, Clone)]
-pub enum Section {
-    Vegs,
-    Flowers,
-    Grass,
-    Terrace,
-    None,
+use crate::events::{Event, EventsServiceChannel, Publisher};
+
+/// Identifies the section in the configuration and in the HTTP API
+#[derive(Serialize, Deserialize, Debug, PartialEq, Hash, Eq, PartialOrd, Ord, Clone)]
+#[serde(transparent)]
+pub struct SectionId(String);
+
+impl SectionId {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for SectionId {
+    fn from(id: &str) -> Self {
+        Self(id.to_string())
+    }
+}
+
+impl Display for SectionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SectionConfig {
+    pub id: SectionId,
+    /// Name shown to the user
+    pub name: String,
+    /// Output driving the valve relay
+    pub gpio: i32,
+    /// Scheduled watering goes through the sections in that order
+    pub order: u32,
+    /// Relay opens the valve on the low output
+    #[serde(default)]
+    pub active_low: bool,
+    /// Soil moisture probe in the bed, if there is one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub moisture_probe: Option<MoistureProbeConfig>,
+}
+
+/// Capacitive probe on the ADC input, its output drops as the soil gets wetter
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct MoistureProbeConfig {
+    /// ADC1 input, ADC2 is taken by Wi-Fi
+    pub gpio: i32,
+    /// Output of the probe in the air
+    pub dry_mv: u16,
+    /// Output of the probe in the water
+    pub wet_mv: u16,
+    /// Scheduled watering of the section is skipped when the soil moisture is above that percentage
+    pub skip_above: u8,
+}
+
+impl MoistureProbeConfig {
+    /// Soil moisture in percent, the output is clamped between the dry and the wet one
+    pub fn moisture(&self, mv: u16) -> u8 {
+        let (dry, wet, mv) = (
+            i32::from(self.dry_mv),
+            i32::from(self.wet_mv),
+            i32::from(mv),
+        );
+        let percent = (dry - mv) * 100 / (dry - wet);
+        percent.clamp(0, 100) as u8
+    }
+}
+
+/// Sections of the garden, sorted by the watering order
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(transparent)]
+pub struct SectionsConfig(Vec<SectionConfig>);
+
+impl SectionsConfig {
+    pub fn new(mut sections: Vec<SectionConfig>) -> Result<Self> {
+        if sections.is_empty() {
+            bail!("there are no sections");
+        }
+
+        for (i, section) in sections.iter().enumerate() {
+            if section.id.as_str().is_empty() {
+                bail!("section id is empty");
+            }
+
+            for other in &sections[..i] {
+                if other.id == section.id {
+                    bail!("section {} is defined twice", section.id);
+                }
+                if other.gpio == section.gpio {
+                    bail!(
+                        "sections {} and {} share GPIO {}",
+                        other.id,
+                        section.id,
+                        section.gpio
+                    );
+                }
+            }
+
+            if let Some(probe) = &section.moisture_probe {
+                if probe.dry_mv <= probe.wet_mv {
+                    bail!("{} moisture probe has to read less when wet", section.id);
+                }
+                if probe.skip_above > 100 {
+                    bail!("{} moisture threshold is above 100%", section.id);
+                }
+
+                let taken = sections.iter().enumerate().any(|(j, other)| {
+                    other.gpio == probe.gpio
+                        || (j < i
+                            && other
+                                .moisture_probe
+                                .is_some_and(|other| other.gpio == probe.gpio))
+                });
+                if taken {
+                    bail!(
+                        "GPIO {} of {} moisture probe is used already",
+                        probe.gpio,
+                        section.id
+                    );
+                }
+            }
+        }
+
+        sections.sort_by_key(|section| section.order);
+        Ok(Self(sections))
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        let sections = serde_json::from_str(json).context("while parsing sections")?;
+        Self::new(sections)
+    }
+
+    pub fn sections(&self) -> &[SectionConfig] {
+        &self.0
+    }
+
+    /// Section ids in the watering order
+    pub fn ids(&self) -> Vec<SectionId> {
+        self.0.iter().map(|section| section.id.clone()).collect()
+    }
+
+    pub fn contains(&self, id: &SectionId) -> bool {
+        self.0.iter().any(|section| section.id == *id)
+    }
+
+    /// Fails if the section is not configured
+    pub fn check(&self, id: &SectionId) -> Result<(), SectionsError> {
+        if self.contains(id) {
+            Ok(())
+        } else {
+            Err(SectionsError::UnknownSection(id.clone()))
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SectionsError {
+    UnknownSection(SectionId),
+}
+
+impl SectionsError {
+    /// Machine readable, stays the same when the message changes
+    pub fn code(&self) -> &'static str {
+        match self {
+            SectionsError::UnknownSection(_) => "SECTION_NOT_FOUND",
+        }
+    }
 }
 
+impl Display for SectionsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SectionsError::UnknownSection(id) => write!(f, "There is no {id} section"),
+        }
+    }
+}
+
+impl std::error::Error for SectionsError {}
+
 // TODO: tests
 /// Newtype that gets reasonable values for section watering duration - non negative and less than 2 hours
 #[derive(Clone, Copy, Default, PartialEq)]
@@ -100,54 +267,85 @@ impl Display for SectionDuration {
 }
 
 pub enum SectionsServiceMessage {
-    Enable(Section),
-    Disable(Section),
+    Enable(SectionId),
+    Disable(SectionId),
 }
 pub type SectionsServiceChannel = Sender<SectionsServiceMessage>;
 
-pub struct Sections<
-    VegsGPIO: OutputPin,
-    TerraceGPIO: OutputPin,
-    FlowersGPIO: OutputPin,
-    GrassGPIO: OutputPin,
-> {
-    vegs: PinDriver<'static, VegsGPIO, Output>,
-    terrace: PinDriver<'static, TerraceGPIO, Output>,
-    flowers: PinDriver<'static, FlowersGPIO, Output>,
-    grass: PinDriver<'static, GrassGPIO, Output>,
-}
-
-impl<VegsGPIO: OutputPin, TerraceGPIO: OutputPin, FlowersGPIO: OutputPin, GrassGPIO: OutputPin>
-    Sections<VegsGPIO, TerraceGPIO, FlowersGPIO, GrassGPIO>
-{
-    pub fn new(
-        vegs_pin: impl Peripheral<P = VegsGPIO> + 'static,
-        terrace_pin: impl Peripheral<P = TerraceGPIO> + 'static,
-        flowers_pin: impl Peripheral<P = FlowersGPIO> + 'static,
-        grass_pin: impl Peripheral<P = GrassGPIO> + 'static,
-    ) -> Result<Self> {
+/// Closes the valve from any thread, bypassing the driver
+pub type ValveCloser = Box<dyn Fn() + Send + Sync>;
+
+/// Output controlling the valve of a single section, e.g. GPIO driving the relay
+pub trait ValveDriver: Send + 'static {
+    fn open(&mut self) -> Result<()>;
+    fn close(&mut self) -> Result<()>;
+    /// Whether the output is set to open the valve
+    fn is_open(&self) -> bool;
+    /// Called from the panic hook, when the driver might be owned by the panicking thread
+    fn emergency_closer(&self) -> ValveCloser;
+}
+
+/// Longer than any valid section duration, plus the watering watchdog grace period
+pub const DEFAULT_MAX_OPEN_TIME: Duration = Duration::from_secs(135 * 60);
+
+pub struct Sections<Valve: ValveDriver> {
+    valves: Vec<(SectionId, Valve)>,
+    /// Valve gets closed after that time, no matter what other services say
+    max_open_time: Duration,
+    /// When the valves got opened
+    open_since: HashMap<SectionId, Instant>,
+    events: Publisher,
+}
+
+impl<Valve: ValveDriver> Sections<Valve> {
+    pub fn new(valves: Vec<(SectionId, Valve)>) -> Result<Self> {
         let mut sections = Self {
-            vegs: PinDriver::output(vegs_pin)?,
-            terrace: PinDriver::output(terrace_pin)?,
-            flowers: PinDriver::output(flowers_pin)?,
-            grass: PinDriver::output(grass_pin)?,
+            valves,
+            max_open_time: DEFAULT_MAX_OPEN_TIME,
+            open_since: HashMap::new(),
+            events: Publisher::default(),
         };
 
-        sections.vegs.set_low()?;
-        sections.flowers.set_low()?;
-        sections.terrace.set_low()?;
-        sections.grass.set_low()?;
+        for (section, valve) in &mut sections.valves {
+            valve.close()?;
+
+            if valve.is_open() {
+                bail!("{section} valve is still open after closing it");
+            }
+        }
 
         Ok(sections)
     }
 
+    /// Closers of all the valves, for the panic hook
+    pub fn emergency_closers(&self) -> Vec<ValveCloser> {
+        self.valves
+            .iter()
+            .map(|(_, valve)| valve.emergency_closer())
+            .collect()
+    }
+
+    pub fn with_max_open_time(mut self, max_open_time: Duration) -> Self {
+        self.max_open_time = max_open_time;
+        self
+    }
+
+    /// Valves that get opened or closed are published as the events
+    pub fn with_events(mut self, events_tx: EventsServiceChannel) -> Self {
+        self.events = Publisher::new(events_tx);
+        self
+    }
+
     /// Starts the Sections Service, returns the SectionsServiceChannel to communicate with it
     pub fn start(self) -> SectionsServiceChannel {
         // Create channel that is used to communicate with this service
         let (tx, rx) = std::sync::mpsc::channel();
 
         // Create Sections service
-        std::thread::spawn(move || self.sections_service(rx));
+        std::thread::Builder::new()
+            .name("sections service".to_string())
+            .spawn(move || self.sections_service(rx))
+            .expect("Cannot spawn Sections service");
 
         tx
     }
@@ -155,31 +353,348 @@ impl<VegsGPIO: OutputPin, TerraceGPIO: OutputPin, FlowersGPIO: OutputPin, GrassG
     fn sections_service(mut self, rx: Receiver<SectionsServiceMessage>) {
         log::info!("Hello from Sections service!");
 
-        while let Ok(msg) = rx.recv() {
+        loop {
+            // Wake up when the first of the open valves runs out of time
+            let deadline = self
+                .open_since
+                .values()
+                .min()
+                .map(|since| *since + self.max_open_time);
+
+            let msg = match deadline {
+                Some(deadline) => {
+                    match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                        Ok(msg) => msg,
+                        Err(RecvTimeoutError::Timeout) => {
+                            self.close_expired();
+                            continue;
+                        }
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+                None => match rx.recv() {
+                    Ok(msg) => msg,
+                    Err(_) => break,
+                },
+            };
+
             match msg {
                 SectionsServiceMessage::Enable(section) => {
-                    log::info!("{section:?} GPIO UP");
-                    match section {
-                        Section::Vegs => self.vegs.set_high(),
-                        Section::Flowers => self.flowers.set_high(),
-                        Section::Grass => self.grass.set_high(),
-                        Section::Terrace => self.terrace.set_high(),
-                        Section::None => Ok(()),
+                    log::info!("{section} GPIO UP");
+                    match self.valve(&section) {
+                        Some(valve) => {
+                            valve.open().unwrap();
+                            // Opening already open valve does not give it more time
+                            if let Entry::Vacant(entry) = self.open_since.entry(section.clone()) {
+                                entry.insert(Instant::now());
+                                self.events.publish(Event::ValveOpened { section });
+                            }
+                        }
+                        None => log::warn!("There is no {section} section"),
                     }
-                    .unwrap();
                 }
                 SectionsServiceMessage::Disable(section) => {
-                    log::info!("{section:?} GPIO DOWN");
-                    match section {
-                        Section::Vegs => self.vegs.set_low(),
-                        Section::Flowers => self.flowers.set_low(),
-                        Section::Grass => self.grass.set_low(),
-                        Section::Terrace => self.terrace.set_low(),
-                        Section::None => Ok(()),
-                    }
-                    .unwrap();
+                    log::info!("{section} GPIO DOWN");
+                    self.close(&section);
                 }
             }
         }
     }
+
+    /// Closes valves that are open for longer than allowed
+    fn close_expired(&mut self) {
+        let expired = self
+            .open_since
+            .iter()
+            .filter(|(_, since)| since.elapsed() >= self.max_open_time)
+            .map(|(section, _)| section.clone())
+            .collect::<Vec<_>>();
+
+        for section in expired {
+            log::error!(
+                "{section} is open for longer than {:?}, GPIO DOWN",
+                self.max_open_time
+            );
+            self.close(&section);
+            self.events.publish(Event::Fault {
+                section: Some(section),
+                reason: format!("Open for longer than {:?}", self.max_open_time),
+            });
+        }
+    }
+
+    fn close(&mut self, section: &SectionId) {
+        if let Some(valve) = self.valve(section) {
+            valve.close().unwrap();
+        }
+        if self.open_since.remove(section).is_some() {
+            self.events.publish(Event::ValveClosed {
+                section: section.clone(),
+            });
+        }
+    }
+
+    fn valve(&mut self, section: &SectionId) -> Option<&mut Valve> {
+        self.valves
+            .iter_mut()
+            .find(|(id, _)| id == section)
+            .map(|(_, valve)| valve)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::host::{wait_until, FakeValve};
+
+    use super::*;
+
+    /// Clones of the valves stay with the test, to observe them
+    fn fake_valves(ids: &[&str]) -> Vec<(SectionId, FakeValve)> {
+        ids.iter()
+            .map(|id| (SectionId::from(*id), FakeValve::default()))
+            .collect()
+    }
+
+    fn section(id: &str, gpio: i32, order: u32) -> SectionConfig {
+        SectionConfig {
+            id: id.into(),
+            name: id.to_string(),
+            gpio,
+            order,
+            active_low: false,
+            moisture_probe: None,
+        }
+    }
+
+    #[test]
+    fn sections_are_sorted_by_order() {
+        let config = SectionsConfig::new(vec![
+            section("Grass", 33, 3),
+            section("Vegs", 14, 1),
+            section("Terrace", 26, 4),
+            section("Flowers", 27, 2),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            config.ids(),
+            ["Vegs", "Flowers", "Grass", "Terrace"].map(SectionId::from)
+        );
+        assert!(config.contains(&"Grass".into()));
+        assert!(!config.contains(&"Lawn".into()));
+        assert_eq!(
+            config.check(&"Lawn".into()),
+            Err(SectionsError::UnknownSection("Lawn".into()))
+        );
+    }
+
+    #[test]
+    fn invalid_sections_are_rejected() {
+        assert!(SectionsConfig::new(vec![]).is_err());
+        assert!(SectionsConfig::new(vec![section("", 14, 1)]).is_err());
+        assert!(SectionsConfig::new(vec![section("Vegs", 14, 1), section("Vegs", 27, 2)]).is_err());
+        assert!(
+            SectionsConfig::new(vec![section("Vegs", 14, 1), section("Grass", 14, 2)]).is_err()
+        );
+    }
+
+    #[test]
+    fn moisture_probes_need_own_gpio_and_calibration() {
+        let probe = MoistureProbeConfig {
+            gpio: 36,
+            dry_mv: 2600,
+            wet_mv: 1100,
+            skip_above: 60,
+        };
+        let with_probe = |id, gpio, order, probe| SectionConfig {
+            moisture_probe: Some(probe),
+            ..section(id, gpio, order)
+        };
+
+        assert!(SectionsConfig::new(vec![
+            with_probe("Vegs", 14, 1, probe),
+            with_probe("Grass", 33, 2, MoistureProbeConfig { gpio: 39, ..probe }),
+        ])
+        .is_ok());
+        assert!(SectionsConfig::new(vec![
+            with_probe("Vegs", 14, 1, probe),
+            with_probe("Grass", 33, 2, probe),
+        ])
+        .is_err());
+        assert!(SectionsConfig::new(vec![
+            with_probe("Vegs", 14, 1, MoistureProbeConfig { gpio: 33, ..probe }),
+            section("Grass", 33, 2),
+        ])
+        .is_err());
+        assert!(SectionsConfig::new(vec![with_probe(
+            "Vegs",
+            14,
+            1,
+            MoistureProbeConfig {
+                wet_mv: 2600,
+                ..probe
+            }
+        )])
+        .is_err());
+        assert!(SectionsConfig::new(vec![with_probe(
+            "Vegs",
+            14,
+            1,
+            MoistureProbeConfig {
+                skip_above: 101,
+                ..probe
+            }
+        )])
+        .is_err());
+
+        assert_eq!(probe.moisture(2600), 0);
+        assert_eq!(probe.moisture(1850), 50);
+        assert_eq!(probe.moisture(1100), 100);
+        // Out of the calibrated range
+        assert_eq!(probe.moisture(3000), 0);
+        assert_eq!(probe.moisture(900), 100);
+    }
+
+    #[test]
+    fn sections_are_read_from_json() {
+        let config = SectionsConfig::from_json(
+            r#"[
+                {"id": "Vegs", "name": "Vegetables", "gpio": 14, "order": 1,
+                 "moisture_probe": {"gpio": 36, "dry_mv": 2600, "wet_mv": 1100, "skip_above": 60}},
+                {"id": "Pond", "name": "Pond", "gpio": 4, "order": 0, "active_low": true}
+            ]"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.sections(),
+            [
+                SectionConfig {
+                    id: "Pond".into(),
+                    name: "Pond".to_string(),
+                    gpio: 4,
+                    order: 0,
+                    active_low: true,
+                    moisture_probe: None,
+                },
+                SectionConfig {
+                    id: "Vegs".into(),
+                    name: "Vegetables".to_string(),
+                    gpio: 14,
+                    order: 1,
+                    active_low: false,
+                    moisture_probe: Some(MoistureProbeConfig {
+                        gpio: 36,
+                        dry_mv: 2600,
+                        wet_mv: 1100,
+                        skip_above: 60,
+                    }),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn all_valves_are_closed_on_start() {
+        let valves = fake_valves(&["Vegs", "Terrace", "Flowers", "Grass", "Pond"]);
+        for (_, valve) in &valves {
+            valve.clone().open().unwrap();
+        }
+
+        let _sections = Sections::new(valves.clone()).unwrap();
+
+        assert!(valves.iter().all(|(_, valve)| !valve.is_open()));
+    }
+
+    /// Valve driver that cannot close the valve
+    struct StuckValve;
+
+    impl ValveDriver for StuckValve {
+        fn open(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn close(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn is_open(&self) -> bool {
+            true
+        }
+
+        fn emergency_closer(&self) -> ValveCloser {
+            Box::new(|| {})
+        }
+    }
+
+    #[test]
+    fn stuck_valve_fails_the_start() {
+        let result = Sections::new(vec![("Vegs".into(), StuckValve)]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn enables_and_disables_given_section() {
+        let valves = fake_valves(&["Vegs", "Terrace", "Flowers"]);
+        let [(_, vegs), (_, terrace), (_, flowers)] = <[_; 3]>::try_from(valves.clone()).unwrap();
+
+        let sections_tx = Sections::new(valves).unwrap().start();
+
+        sections_tx
+            .send(SectionsServiceMessage::Enable("Terrace".into()))
+            .unwrap();
+        assert!(wait_until(Duration::from_secs(1), || terrace.is_open()));
+        assert!(!vegs.is_open());
+        assert!(!flowers.is_open());
+
+        sections_tx
+            .send(SectionsServiceMessage::Enable("Vegs".into()))
+            .unwrap();
+        sections_tx
+            .send(SectionsServiceMessage::Disable("Terrace".into()))
+            .unwrap();
+        assert!(wait_until(Duration::from_secs(1), || !terrace.is_open()));
+        assert!(vegs.is_open());
+
+        // Unknown section is not backed by any valve
+        sections_tx
+            .send(SectionsServiceMessage::Enable("Lawn".into()))
+            .unwrap();
+        sections_tx
+            .send(SectionsServiceMessage::Disable("Vegs".into()))
+            .unwrap();
+        assert!(wait_until(Duration::from_secs(1), || !vegs.is_open()));
+    }
+
+    #[test]
+    fn valve_is_closed_after_max_open_time() {
+        let valves = fake_valves(&["Vegs", "Flowers"]);
+        let [(_, vegs), (_, flowers)] = <[_; 2]>::try_from(valves.clone()).unwrap();
+
+        let sections_tx = Sections::new(valves)
+            .unwrap()
+            .with_max_open_time(Duration::from_millis(300))
+            .start();
+
+        sections_tx
+            .send(SectionsServiceMessage::Enable("Vegs".into()))
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(150));
+        sections_tx
+            .send(SectionsServiceMessage::Enable("Flowers".into()))
+            .unwrap();
+        // Enabling again does not extend the time
+        sections_tx
+            .send(SectionsServiceMessage::Enable("Vegs".into()))
+            .unwrap();
+        assert!(wait_until(Duration::from_secs(1), || flowers.is_open()));
+        assert!(vegs.is_open());
+
+        // Vegs goes first, flowers follow
+        assert!(wait_until(Duration::from_secs(1), || !vegs.is_open()));
+        assert!(flowers.is_open());
+        assert!(wait_until(Duration::from_secs(1), || !flowers.is_open()));
+    }
 }
@@ -0,0 +1,28 @@
+//! Abstraction over persistent key-value storage, survives the power cut
+
+use anyhow::{Context, Result};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Raw values stored under the keys, on ESP32 backed by NVS - keys cannot be longer than 15 characters
+pub trait Storage: Send + 'static {
+    fn get(&mut self, key: &str) -> Result<Option<Vec<u8>>>;
+    fn set(&mut self, key: &str, value: &[u8]) -> Result<()>;
+    fn remove(&mut self, key: &str) -> Result<()>;
+}
+
+impl dyn Storage {
+    /// Reads the value stored as JSON under the key
+    pub fn load<T: DeserializeOwned>(&mut self, key: &str) -> Result<Option<T>> {
+        self.get(key)?
+            .map(|value| serde_json::from_slice(&value))
+            .transpose()
+            .with_context(|| format!("while parsing {key}"))
+    }
+
+    /// Stores the value as JSON under the key
+    pub fn store<T: Serialize>(&mut self, key: &str, value: &T) -> Result<()> {
+        let value = serde_json::to_vec(value)?;
+        self.set(key, &value)
+            .with_context(|| format!("while storing {key}"))
+    }
+}
@@ -0,0 +1,432 @@
+//! Local time of the garden, RTC keeps UTC and everything the user sees is in the local time
+
+use std::fmt::Display;
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{Datelike, Days, LocalResult, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta};
+
+/// Used when the DST rule is not given, the same as glibc does
+const DEFAULT_DST_RULE: &str = "M3.2.0,M11.1.0";
+/// Transition happens at 02:00 local time, if not given otherwise
+const DEFAULT_TRANSITION_TIME: i32 = 2 * 60 * 60;
+
+/// Time zone given by the POSIX TZ string, like `CET-1CEST,M3.5.0,M10.5.0/3` for Central Europe
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimeZone {
+    spec: String,
+    /// Seconds east of UTC
+    std_offset: i32,
+    dst: Option<Dst>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Dst {
+    /// Seconds east of UTC
+    offset: i32,
+    /// In the standard time
+    start: Transition,
+    /// In the daylight saving time
+    end: Transition,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Transition {
+    day: TransitionDay,
+    /// Seconds since the local midnight, might be negative or over a day
+    time: i32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TransitionDay {
+    /// `Jn` - 1 to 365, February 29 is never counted
+    Julian(u32),
+    /// `n` - 0 to 365, February 29 is counted in leap years
+    ZeroBased(u32),
+    /// `Mm.w.d` - day `d` (0 is Sunday) of week `w` (5 is the last one) of month `m`
+    MonthWeekDay { month: u32, week: u32, weekday: u32 },
+}
+
+impl TransitionDay {
+    fn date(&self, year: i32) -> Option<NaiveDate> {
+        match *self {
+            TransitionDay::Julian(day) => {
+                let date = NaiveDate::from_yo_opt(year, day)?;
+                // Day after February 28 is always March 1
+                if date.leap_year() && date.ordinal() > 59 {
+                    date.succ_opt()
+                } else {
+                    Some(date)
+                }
+            }
+            TransitionDay::ZeroBased(day) => NaiveDate::from_yo_opt(year, day + 1),
+            TransitionDay::MonthWeekDay {
+                month,
+                week,
+                weekday,
+            } => {
+                let first = NaiveDate::from_ymd_opt(year, month, 1)?;
+                let first_weekday = first.weekday().num_days_from_sunday();
+                let mut day = 1 + (weekday + 7 - first_weekday) % 7 + (week - 1) * 7;
+
+                // Fifth week means the last one, it might be the fourth one in a given month
+                while NaiveDate::from_ymd_opt(year, month, day).is_none() {
+                    day -= 7;
+                }
+                NaiveDate::from_ymd_opt(year, month, day)
+            }
+        }
+    }
+
+    /// UTC time of the transition in given year, `offset` is the one in effect before it
+    fn utc(&self, year: i32, time: i32, offset: i32) -> Option<NaiveDateTime> {
+        let midnight = self.date(year)?.and_hms_opt(0, 0, 0)?;
+        Some(midnight + TimeDelta::seconds((time - offset) as i64))
+    }
+}
+
+impl TimeZone {
+    pub fn utc() -> Self {
+        Self {
+            spec: "UTC0".to_string(),
+            std_offset: 0,
+            dst: None,
+        }
+    }
+
+    pub fn from_posix(spec: &str) -> Result<Self> {
+        Parser { rest: spec }
+            .time_zone()
+            .with_context(|| format!("Invalid TZ string {spec:?}"))
+            .map(|(std_offset, dst)| Self {
+                spec: spec.to_string(),
+                std_offset,
+                dst,
+            })
+    }
+
+    /// Offset from UTC in seconds, at given UTC time
+    pub fn offset_at(&self, utc: NaiveDateTime) -> i32 {
+        let Some(dst) = &self.dst else {
+            return self.std_offset;
+        };
+
+        let year = (utc + TimeDelta::seconds(self.std_offset as i64)).year();
+        let start = dst.start.day.utc(year, dst.start.time, self.std_offset);
+        let end = dst.end.day.utc(year, dst.end.time, dst.offset);
+
+        let in_dst = match (start, end) {
+            (Some(start), Some(end)) if start < end => start <= utc && utc < end,
+            // Southern hemisphere, DST spans over the new year
+            (Some(start), Some(end)) => !(end <= utc && utc < start),
+            _ => false,
+        };
+
+        if in_dst {
+            dst.offset
+        } else {
+            self.std_offset
+        }
+    }
+
+    pub fn to_local(&self, utc: NaiveDateTime) -> NaiveDateTime {
+        utc + TimeDelta::seconds(self.offset_at(utc) as i64)
+    }
+
+    /// None if the local time is skipped when the clock goes forward,
+    /// ambiguous if it is repeated when the clock goes back
+    pub fn to_utc(&self, local: NaiveDateTime) -> LocalResult<NaiveDateTime> {
+        let mut offsets = vec![self.std_offset];
+        if let Some(dst) = &self.dst {
+            offsets.push(dst.offset);
+        }
+
+        let mut candidates = offsets
+            .into_iter()
+            .map(|offset| local - TimeDelta::seconds(offset as i64))
+            .filter(|utc| self.to_local(*utc) == local)
+            .collect::<Vec<_>>();
+        candidates.sort();
+        candidates.dedup();
+
+        match candidates[..] {
+            [] => LocalResult::None,
+            [utc] => LocalResult::Single(utc),
+            [earliest, latest, ..] => LocalResult::Ambiguous(earliest, latest),
+        }
+    }
+
+    /// Always gives an answer, for the schedule: time skipped by the DST change
+    /// moves forward by the skipped hour, repeated time is taken the first time it happens
+    pub fn to_utc_lenient(&self, local: NaiveDateTime) -> NaiveDateTime {
+        match self.to_utc(local) {
+            LocalResult::Single(utc) | LocalResult::Ambiguous(utc, _) => utc,
+            // Offset from before the gap
+            LocalResult::None => local - TimeDelta::seconds(self.std_offset as i64),
+        }
+    }
+
+    /// First UTC time after `now` when it is `time` in the local time
+    pub fn next_utc_at(&self, time: NaiveTime, now: NaiveDateTime) -> NaiveDateTime {
+        let today = self.to_local(now).date();
+
+        // The same local time might come up to 25 hours later, when the clock goes back
+        (0..3)
+            .filter_map(|days| today.checked_add_days(Days::new(days)))
+            .map(|date| self.to_utc_lenient(date.and_time(time)))
+            .find(|utc| *utc > now)
+            .expect("Local time repeats every day")
+    }
+}
+
+impl Default for TimeZone {
+    fn default() -> Self {
+        Self::utc()
+    }
+}
+
+impl Display for TimeZone {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.spec)
+    }
+}
+
+struct Parser<'a> {
+    rest: &'a str,
+}
+
+impl Parser<'_> {
+    fn time_zone(&mut self) -> Result<(i32, Option<Dst>)> {
+        self.name()?;
+        // POSIX offset is positive west of Greenwich
+        let std_offset = -self.time()?;
+
+        if self.rest.is_empty() {
+            return Ok((std_offset, None));
+        }
+
+        self.name()?;
+        // DST is an hour ahead, if not given otherwise
+        let dst_offset = if self.rest.is_empty() || self.rest.starts_with(',') {
+            std_offset + 60 * 60
+        } else {
+            -self.time()?
+        };
+
+        let rule = match self.rest.strip_prefix(',') {
+            Some(rule) => rule,
+            None if self.rest.is_empty() => DEFAULT_DST_RULE,
+            None => bail!("Unexpected {:?}", self.rest),
+        };
+        let (start, end) = rule
+            .split_once(',')
+            .ok_or_else(|| anyhow!("DST end is missing"))?;
+
+        let dst = Dst {
+            offset: dst_offset,
+            start: Parser { rest: start }.transition()?,
+            end: Parser { rest: end }.transition()?,
+        };
+
+        Ok((std_offset, Some(dst)))
+    }
+
+    /// `CET` or `<+03>`, only the offset matters
+    fn name(&mut self) -> Result<()> {
+        let len = match self.rest.strip_prefix('<') {
+            Some(quoted) => quoted.find('>').ok_or_else(|| anyhow!("Unclosed <"))? + 2,
+            None => self
+                .rest
+                .find(|c: char| !c.is_ascii_alphabetic())
+                .unwrap_or(self.rest.len()),
+        };
+
+        if len < 3 {
+            bail!("Zone name is too short");
+        }
+        self.rest = &self.rest[len..];
+        Ok(())
+    }
+
+    /// `[+-]hh[:mm[:ss]]` in seconds
+    fn time(&mut self) -> Result<i32> {
+        let len = self
+            .rest
+            .find(|c: char| !(c.is_ascii_digit() || c == ':' || c == '+' || c == '-'))
+            .unwrap_or(self.rest.len());
+        let (time, rest) = self.rest.split_at(len);
+        self.rest = rest;
+
+        let (sign, time) = match time.strip_prefix('-') {
+            Some(time) => (-1, time),
+            None => (1, time.strip_prefix('+').unwrap_or(time)),
+        };
+
+        let mut parts = time.split(':').map(|part| part.parse::<i32>());
+        let hours = parts.next().ok_or_else(|| anyhow!("Missing hours"))??;
+        let minutes = parts.next().transpose()?.unwrap_or(0);
+        let seconds = parts.next().transpose()?.unwrap_or(0);
+
+        if parts.next().is_some() || hours > 167 || minutes > 59 || seconds > 59 {
+            bail!("Invalid time {time:?}");
+        }
+
+        Ok(sign * (hours * 60 * 60 + minutes * 60 + seconds))
+    }
+
+    /// `date[/time]`
+    fn transition(&mut self) -> Result<Transition> {
+        let (day, time) = match self.rest.split_once('/') {
+            Some((day, time)) => (day, Parser { rest: time }.time()?),
+            None => (self.rest, DEFAULT_TRANSITION_TIME),
+        };
+
+        let day = if let Some(day) = day.strip_prefix('J') {
+            let day = day.parse()?;
+            if !(1..=365).contains(&day) {
+                bail!("Julian day out of range");
+            }
+            TransitionDay::Julian(day)
+        } else if let Some(date) = day.strip_prefix('M') {
+            let mut parts = date.split('.').map(|part| part.parse::<u32>());
+            let mut next =
+                || -> Result<u32> { Ok(parts.next().ok_or_else(|| anyhow!("Expected Mm.w.d"))??) };
+            let (month, week, weekday) = (next()?, next()?, next()?);
+
+            if !(1..=12).contains(&month) || !(1..=5).contains(&week) || weekday > 6 {
+                bail!("Invalid date {day:?}");
+            }
+            TransitionDay::MonthWeekDay {
+                month,
+                week,
+                weekday,
+            }
+        } else {
+            let day = day.parse()?;
+            if day > 365 {
+                bail!("Day out of range");
+            }
+            TransitionDay::ZeroBased(day)
+        };
+
+        Ok(Transition { day, time })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CENTRAL_EUROPE: &str = "CET-1CEST,M3.5.0,M10.5.0/3";
+
+    fn datetime(s: &str) -> NaiveDateTime {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_posix_tz_strings() {
+        let tz = TimeZone::from_posix(CENTRAL_EUROPE).unwrap();
+        assert_eq!(tz.std_offset, 3600);
+        assert_eq!(tz.dst.unwrap().offset, 7200);
+
+        let tz = TimeZone::from_posix("<+0330>-3:30").unwrap();
+        assert_eq!(tz.std_offset, 3 * 3600 + 30 * 60);
+        assert!(tz.dst.is_none());
+
+        // US rules are the default ones
+        let tz = TimeZone::from_posix("EST5EDT").unwrap();
+        assert_eq!(tz.std_offset, -5 * 3600);
+        assert_eq!(tz.offset_at(datetime("2024-07-01T12:00:00")), -4 * 3600);
+
+        for invalid in [
+            "",
+            "C-1",
+            "CET",
+            "CET-1CEST,M3.5.0",
+            "CET-1CEST,M13.5.0,M10.5.0",
+        ] {
+            assert!(TimeZone::from_posix(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn local_time_follows_dst() {
+        let tz = TimeZone::from_posix(CENTRAL_EUROPE).unwrap();
+
+        // 2024-03-31 02:00 CET clock goes forward to 03:00 CEST
+        assert_eq!(
+            tz.to_local(datetime("2024-03-31T00:59:59")),
+            datetime("2024-03-31T01:59:59")
+        );
+        assert_eq!(
+            tz.to_local(datetime("2024-03-31T01:00:00")),
+            datetime("2024-03-31T03:00:00")
+        );
+
+        // 2024-10-27 03:00 CEST clock goes back to 02:00 CET
+        assert_eq!(
+            tz.to_local(datetime("2024-10-27T00:59:59")),
+            datetime("2024-10-27T02:59:59")
+        );
+        assert_eq!(
+            tz.to_local(datetime("2024-10-27T01:00:00")),
+            datetime("2024-10-27T02:00:00")
+        );
+    }
+
+    #[test]
+    fn skipped_and_repeated_local_time() {
+        let tz = TimeZone::from_posix(CENTRAL_EUROPE).unwrap();
+
+        assert_eq!(
+            tz.to_utc(datetime("2024-03-31T02:30:00")),
+            LocalResult::None
+        );
+        assert_eq!(
+            tz.to_utc_lenient(datetime("2024-03-31T02:30:00")),
+            datetime("2024-03-31T01:30:00")
+        );
+
+        assert_eq!(
+            tz.to_utc(datetime("2024-10-27T02:30:00")),
+            LocalResult::Ambiguous(
+                datetime("2024-10-27T00:30:00"),
+                datetime("2024-10-27T01:30:00")
+            )
+        );
+        assert_eq!(
+            tz.to_utc_lenient(datetime("2024-10-27T02:30:00")),
+            datetime("2024-10-27T00:30:00")
+        );
+
+        assert_eq!(
+            tz.to_utc(datetime("2024-06-01T06:00:00")),
+            LocalResult::Single(datetime("2024-06-01T04:00:00"))
+        );
+    }
+
+    #[test]
+    fn southern_hemisphere_dst_spans_new_year() {
+        let tz = TimeZone::from_posix("AEST-10AEDT,M10.1.0,M4.1.0/3").unwrap();
+
+        assert_eq!(tz.offset_at(datetime("2024-01-15T00:00:00")), 11 * 3600);
+        assert_eq!(tz.offset_at(datetime("2024-07-15T00:00:00")), 10 * 3600);
+        assert_eq!(tz.offset_at(datetime("2024-12-15T00:00:00")), 11 * 3600);
+    }
+
+    #[test]
+    fn next_local_time_across_dst_change() {
+        let tz = TimeZone::from_posix(CENTRAL_EUROPE).unwrap();
+        let six = NaiveTime::from_hms_opt(6, 0, 0).unwrap();
+
+        // 23 hours day
+        assert_eq!(
+            tz.next_utc_at(six, datetime("2024-03-30T05:00:00")),
+            datetime("2024-03-31T04:00:00")
+        );
+        // 25 hours day
+        assert_eq!(
+            tz.next_utc_at(six, datetime("2024-10-26T04:30:00")),
+            datetime("2024-10-27T05:00:00")
+        );
+    }
+}
@@ -1,57 +1,371 @@
 //! Actual logic of watering, agnostic to underlying hardware
 
 use std::{
-    collections::HashMap,
-    sync::mpsc::{channel, Sender},
+    collections::{BTreeMap, HashMap},
+    sync::mpsc::{channel, RecvTimeoutError, Sender},
+    time::{Duration, Instant},
 };
 
-use chrono::NaiveTime;
+use anyhow::{Context, Result};
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime, TimeDelta};
 
-use log::{debug, info};
-use serde::Serialize;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
 
 use crate::{
     clock::{ClockServiceChannel, ClockServiceMessage},
-    sections::{Section, SectionDuration, SectionsServiceChannel},
+    events::{Event, EventsServiceChannel, Publisher},
+    history::{EndReason, History, HistoryEntry, HistoryEvent, HistoryQuery, SkipReason},
+    moisture::{MoistureReading, MoistureServiceChannel, MoistureServiceMessage},
+    rain::{RainServiceChannel, RainServiceMessage},
+    recovery::{recovery, LastRun, RecoveryPolicy},
+    schedule::ScheduleRule,
+    sections::{
+        SectionDuration, SectionId, SectionsError, SectionsServiceChannel, SectionsServiceMessage,
+    },
+    storage::Storage,
 };
 
+/// Program managed by `StartWateringAt` and `SetSectionDuration`
+pub const DEFAULT_PROGRAM: &str = "default";
+
+/// How long after the section alarm is due the watchdog waits before closing the section
+pub const WATCHDOG_GRACE: Duration = Duration::from_secs(60);
+
+/// Only the most recent faults are kept
+const MAX_FAULTS: usize = 10;
+
+/// Section that had to be closed by the watchdog, because the section alarm did not arrive
+#[derive(Debug, Clone, Serialize)]
+pub struct WatchdogFault {
+    pub section: SectionId,
+    /// Time of closing the section, None if clock did not respond
+    pub at: Option<NaiveDateTime>,
+}
+
+/// What the watering is busy with
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WateringMode {
+    Idle,
+    /// Program is being watered
+    Scheduled,
+    /// Section is watered on the user request
+    AdHoc,
+}
+
+/// Program run that was due, but did not happen
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SkippedRun {
+    pub program: String,
+    /// Local time
+    pub at: NaiveDateTime,
+    pub reason: SkipReason,
+}
+
+/// Program run the schedule has next
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ScheduledRun {
+    pub program: String,
+    /// Local time
+    pub at: NaiveDateTime,
+}
+
 #[derive(Debug, Serialize)]
 pub struct WateringStatus {
-    pub section_durations: HashMap<Section, SectionDuration>,
+    pub mode: WateringMode,
+    pub enabled: bool,
+    pub programs: BTreeMap<String, Program>,
+    /// Program the watering alarm is armed for
+    pub next_program: Option<String>,
+    /// First run of any program on the day it is due, None during the run or if nothing is scheduled
+    pub next_run: Option<ScheduledRun>,
+    /// Local time the rain delay ends at, runs due before are skipped
+    pub paused_until: Option<NaiveDateTime>,
+    /// Whether the rain sensor is wet, None without the sensor
+    pub raining: Option<bool>,
+    /// The most recent run that got skipped, and why
+    pub last_skipped: Option<SkippedRun>,
+    /// Latest readings of the soil moisture probes, empty without them
+    pub soil_moisture: BTreeMap<SectionId, MoistureReading>,
+    /// Program being watered right now
+    pub current_program: Option<String>,
+    /// Section being watered right now, by the program or ad-hoc
+    pub current_section: Option<SectionId>,
+    /// Local time the current section got opened at
+    pub section_started_at: Option<NaiveDateTime>,
+    /// Local time the section alarm closes the current section at
+    pub section_ends_at: Option<NaiveDateTime>,
+    pub faults: Vec<WatchdogFault>,
+}
+
+/// Sections watered one after another, every day at the same time
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Program {
+    /// Time of the daily watering, program without it never runs
+    pub start_at: Option<NaiveTime>,
+    /// Sections that are missing or have zero duration are skipped
+    pub section_durations: HashMap<SectionId, SectionDuration>,
+    /// Days the program runs on
+    #[serde(default)]
+    pub rule: ScheduleRule,
+    /// Sections without the rule are watered whenever the program runs
+    #[serde(default)]
+    pub section_rules: HashMap<SectionId, ScheduleRule>,
+}
+
+impl Program {
+    fn duration(&self, section: &SectionId) -> SectionDuration {
+        self.section_durations
+            .get(section)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Longest the run can take, as if every section was due
+    fn run_time(&self) -> TimeDelta {
+        self.section_durations
+            .values()
+            .map(|duration| duration.into_inner())
+            .sum()
+    }
+
+    /// Duration of the section on given day, zero if the section is not due
+    fn duration_on(&self, section: &SectionId, date: NaiveDate) -> SectionDuration {
+        match self.section_rules.get(section) {
+            Some(rule) if !rule.is_due(date) => SectionDuration::default(),
+            _ => self.duration(section),
+        }
+    }
+}
+
+/// Watering configuration that survives the power cut
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WateringConfig {
+    /// Whether the watering alarm is armed
+    pub enabled: bool,
+    /// Programs by name
+    #[serde(default)]
+    pub programs: BTreeMap<String, Program>,
+    /// Rain delay, local time up to which the scheduled runs are skipped
+    #[serde(default)]
+    pub paused_until: Option<NaiveDateTime>,
+}
+
+impl WateringConfig {
+    const STORAGE_KEY: &'static str = "config";
+
+    /// Reads the configuration from the storage, default one if nothing got stored yet
+    pub fn load(storage: &mut dyn Storage) -> Result<Self> {
+        Ok(storage.load(Self::STORAGE_KEY)?.unwrap_or_default())
+    }
+
+    fn store(&self, storage: &mut dyn Storage) -> Result<()> {
+        storage.store(Self::STORAGE_KEY, self)
+    }
+
+    /// First program that starts after given time, or the earliest one on the next day
+    pub fn next_program(&self, after: NaiveTime) -> Option<(&str, NaiveTime)> {
+        let scheduled = self
+            .programs
+            .iter()
+            .filter_map(|(name, program)| program.start_at.map(|at| (name.as_str(), at)));
+
+        scheduled
+            .clone()
+            .filter(|(_, at)| *at > after)
+            .min_by_key(|(_, at)| *at)
+            .or_else(|| scheduled.min_by_key(|(_, at)| *at))
+    }
+
+    /// Program that runs next after given local time, on the day its rule is due
+    pub fn next_run(&self, after: NaiveDateTime) -> Option<ScheduledRun> {
+        self.programs
+            .iter()
+            .filter_map(|(name, program)| {
+                let start_at = program.start_at?;
+                // Program that is starting right now is due the next day at the earliest
+                let from = match start_at > after.time() {
+                    true => after.date(),
+                    false => after.date().succ_opt()?,
+                };
+                let date = program.rule.next_due(from)?;
+                Some(ScheduledRun {
+                    program: name.clone(),
+                    at: date.and_time(start_at),
+                })
+            })
+            .min_by_key(|run| run.at)
+    }
+
+    /// Other program that starts at the same time as given one, or while one of them runs.
+    /// Watering alarm is armed for one program at a time, and not at all during the run,
+    /// such a program would never run. Rules are not taken into account, the alarm does not know them
+    pub fn overlapping_program(&self, name: &str, program: &Program) -> Option<&str> {
+        let start_at = program.start_at?;
+
+        self.programs
+            .iter()
+            .filter(|(other_name, _)| other_name.as_str() != name)
+            .find(|(_, other)| {
+                other.start_at.is_some_and(|other_start_at| {
+                    runs_overlap(
+                        (start_at, program.run_time()),
+                        (other_start_at, other.run_time()),
+                    )
+                })
+            })
+            .map(|(other_name, _)| other_name.as_str())
+    }
+
+    /// Whether the scheduled runs are skipped at given local time
+    pub fn is_paused(&self, now: NaiveDateTime) -> bool {
+        self.paused_until.is_some_and(|until| now <= until)
+    }
+}
+
+/// Daily runs given by the start time and the run time, the late one goes past midnight
+fn runs_overlap(a: (NaiveTime, TimeDelta), b: (NaiveTime, TimeDelta)) -> bool {
+    // How long after `from` the `to` starts
+    let after = |from: NaiveTime, to: NaiveTime| (to - from).num_seconds().rem_euclid(24 * 60 * 60);
+
+    a.0 == b.0 || after(a.0, b.0) < a.1.num_seconds() || after(b.0, a.0) < b.1.num_seconds()
 }
 
 #[derive(Debug)]
 pub enum WateringServiceMessage {
-    /// Comes from the RTC, section watering should be ended
-    SectionAlarmFired,
+    /// Comes from the RTC, section watering should be ended. Carries the id the alarm got armed with
+    SectionAlarmFired(u32),
     /// Comes from the RTC, watering of all sections should start
     WateringAlarmFired,
-    /// Schedule watering of all sections
-    StartWateringAt(NaiveTime),
-    /// Set section duration for daily schedule
-    SetSectionDuration(Section, SectionDuration),
+    /// Section alarm did not arrive in time, section has to be closed
+    WatchdogExpired,
+    /// Schedule watering of the default program
+    StartWateringAt(NaiveTime, WateringReply),
+    /// Set section duration in the default program
+    SetSectionDuration(SectionId, SectionDuration, WateringReply),
+    /// Add a program, or replace the one with the same name
+    SetProgram(String, Program, WateringReply),
+    RemoveProgram(String, WateringReply),
     /// Enable section right now, for given duration
-    EnableSectionFor(Section, SectionDuration),
+    EnableSectionFor(SectionId, SectionDuration, WateringReply),
     /// Close valves for all sections
     CloseAllValves,
     // Disable Watering Alarm
     DisableWatering,
+    /// Skip the scheduled runs for that long from now, the schedule stays as it is.
+    /// Zero lifts the delay.
+    SetRainDelay(TimeDelta),
+    /// Comes from the rain sensor, true when it is wet
+    RainChanged(bool),
     GetStatus(Sender<WateringStatus>),
+    GetHistory(HistoryQuery, Sender<Vec<HistoryEntry>>),
 }
 pub type WateringServiceChannel = Sender<WateringServiceMessage>;
 
+/// Whether the request got carried out, sent back to the one who made it
+pub type WateringReply = Sender<Result<(), WateringError>>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WateringError {
+    Sections(SectionsError),
+    /// Scheduled watering of the section is in progress, it goes first
+    SectionBusy(SectionId),
+    UnknownProgram(String),
+    /// Program would start together with the given one, or while one of them runs
+    ProgramOverlaps(String),
+}
+
+impl WateringError {
+    /// Machine readable, stays the same when the message changes
+    pub fn code(&self) -> &'static str {
+        match self {
+            WateringError::Sections(err) => err.code(),
+            WateringError::SectionBusy(_) => "SECTION_BUSY",
+            WateringError::UnknownProgram(_) => "PROGRAM_NOT_FOUND",
+            WateringError::ProgramOverlaps(_) => "PROGRAM_OVERLAPS",
+        }
+    }
+}
+
+impl std::fmt::Display for WateringError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WateringError::Sections(err) => err.fmt(f),
+            WateringError::SectionBusy(section) => {
+                write!(f, "Scheduled watering of {section} is in progress")
+            }
+            WateringError::UnknownProgram(name) => write!(f, "There is no program {name}"),
+            WateringError::ProgramOverlaps(name) => {
+                write!(f, "Program would run at the same time as {name}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for WateringError {}
+
+impl From<SectionsError> for WateringError {
+    fn from(err: SectionsError) -> Self {
+        WateringError::Sections(err)
+    }
+}
+
+/// Nobody might wait for the reply any more, e.g. the HTTP request timed out
+fn reply(tx: WateringReply, result: Result<(), WateringError>) {
+    if let Err(err) = &result {
+        warn!("{err}, rejecting");
+    }
+    if tx.send(result).is_err() {
+        debug!("Nobody waits for the reply");
+    }
+}
+
 trait HandleMessage {
     /// This is pure runtime dispatch, we don't know what state comes in to handle the message (Scheduled or AdHoc),
     /// and we don't know what is state transition. Hence both, self return value are boxed.
     fn handle_message(self: Box<Self>, msg: WateringServiceMessage) -> Box<dyn HandleMessage>;
+
+    /// State shared by all the watering modes
+    fn state(&self) -> &WateringState;
 }
 
 struct WateringState {
     clock_tx: ClockServiceChannel,
     sections_tx: SectionsServiceChannel,
-    // TODO: watchdog for section opening
-    current_section: Section,
-    section_durations: HashMap<Section, SectionDuration>,
+    rain_tx: Option<RainServiceChannel>,
+    moisture_tx: Option<MoistureServiceChannel>,
+    storage: Box<dyn Storage>,
+    /// All the sections, in the watering order
+    sections: Vec<SectionId>,
+    current_section: Option<SectionId>,
+    /// Local time the current section got opened at, None if it was not opened
+    section_started_at: Option<NaiveDateTime>,
+    /// Local time the current section is due to be closed at
+    section_ends_at: Option<NaiveDateTime>,
+    /// Section alarm armed last, the one armed before might be still in the queue
+    section_alarm_id: u32,
+    /// Open section gets closed at this point, even if the section alarm does not arrive
+    watchdog_deadline: Option<Instant>,
+    watchdog_grace: Duration,
+    faults: Vec<WatchdogFault>,
+    /// Program being watered, None during ad-hoc watering
+    current_program: Option<String>,
+    /// Local time the current program was due at, sections are checked against its date
+    run_started_at: NaiveDateTime,
+    /// Sections of the current program watered to the end
+    completed_sections: Vec<SectionId>,
+    /// What to do on boot about the program run that got missed or interrupted
+    recovery_policy: RecoveryPolicy,
+    /// Program the watering alarm is armed for
+    next_program: Option<String>,
+    next_run: Option<ScheduledRun>,
+    /// None without the rain sensor
+    raining: Option<bool>,
+    last_skipped: Option<SkippedRun>,
+    config: WateringConfig,
+    history: History,
+    events: Publisher,
 }
 /// Watering that gets triggered by the armed WateringClock, will go through all enabled sections
 pub struct OnScheduleWatering {
@@ -65,94 +379,266 @@ pub struct AdHocSectionWatering {
 }
 
 impl HandleMessage for AdHocSectionWatering {
-    fn handle_message(self: Box<Self>, msg: WateringServiceMessage) -> Box<dyn HandleMessage> {
+    fn handle_message(mut self: Box<Self>, msg: WateringServiceMessage) -> Box<dyn HandleMessage> {
+        match msg {
+            WateringServiceMessage::SectionAlarmFired(id) => {
+                // Alarm of the section switched from
+                if self.state.section_alarm_replaced(id) {
+                    return self;
+                }
+
+                info!(
+                    "Ad-hoc watering of {:?} complete",
+                    self.state.current_section
+                );
+
+                self.state.stop_current_section(EndReason::Alarm);
+                return Box::new(OnScheduleWatering { state: self.state });
+            }
+            WateringServiceMessage::WateringAlarmFired => {
+                // Schedule has precedence over the user request, finish ad-hoc watering and let the
+                // scheduled one handle the alarm
+                info!(
+                    "Watering alarm fired during ad-hoc watering of {:?}, stopping it",
+                    self.state.current_section
+                );
+
+                self.state.stop_current_section(EndReason::Schedule);
+                return Box::new(OnScheduleWatering { state: self.state }).handle_message(msg);
+            }
+            WateringServiceMessage::WatchdogExpired => {
+                self.state.watchdog_expired();
+                return Box::new(OnScheduleWatering { state: self.state });
+            }
+            WateringServiceMessage::StartWateringAt(when, tx) => {
+                reply(tx, self.state.start_watering_at(when))
+            }
+            WateringServiceMessage::SetSectionDuration(section, duration, tx) => {
+                reply(tx, self.state.set_section_duration(section, duration))
+            }
+            WateringServiceMessage::SetProgram(name, program, tx) => {
+                reply(tx, self.state.set_program(name, program))
+            }
+            WateringServiceMessage::RemoveProgram(name, tx) => {
+                reply(tx, self.state.remove_program(&name))
+            }
+            WateringServiceMessage::EnableSectionFor(section, duration, tx) => {
+                if let Err(err) = self.state.check_section(&section) {
+                    reply(tx, Err(err));
+                } else if duration.is_zero() {
+                    info!("Ad-hoc watering of {section} for {duration} requested, stopping ad-hoc watering");
+
+                    self.state.stop_current_section(EndReason::Manual);
+                    reply(tx, Ok(()));
+                    return Box::new(OnScheduleWatering { state: self.state });
+                } else {
+                    info!(
+                        "Ad-hoc watering switches from {:?} to {section}",
+                        self.state.current_section
+                    );
+
+                    if let Some(current_section) = self.state.current_section.take() {
+                        self.state.disable_section(&current_section);
+                        self.state
+                            .record_section_end(&current_section, EndReason::Manual);
+                    }
+                    self.state.start_section(section, &duration);
+                    reply(tx, Ok(()));
+                }
+            }
+            WateringServiceMessage::CloseAllValves => {
+                self.state.close_all_valves();
+                self.state.disable_section_alarm();
+                if let Some(section) = self.state.current_section.take() {
+                    self.state.record_section_end(&section, EndReason::Manual);
+                }
+
+                return Box::new(OnScheduleWatering { state: self.state });
+            }
+            WateringServiceMessage::DisableWatering => self.state.disable_watering(),
+            WateringServiceMessage::SetRainDelay(delay) => self.state.set_rain_delay(delay),
+            WateringServiceMessage::RainChanged(wet) => self.state.rain_changed(wet),
+            WateringServiceMessage::GetStatus(tx) => {
+                self.state.report_status(WateringMode::AdHoc, tx)
+            }
+            WateringServiceMessage::GetHistory(query, tx) => self.state.report_history(&query, tx),
+        }
+
         self
     }
+
+    fn state(&self) -> &WateringState {
+        &self.state
+    }
 }
 
 impl HandleMessage for OnScheduleWatering {
     fn handle_message(mut self: Box<Self>, msg: WateringServiceMessage) -> Box<dyn HandleMessage> {
         match msg {
-            WateringServiceMessage::SectionAlarmFired => {
-                info!("Got notification about section alarm");
+            WateringServiceMessage::SectionAlarmFired(id) => {
+                info!("Got notification about section alarm #{id}");
 
-                // This alarm should be assigned to some section
-                assert_ne!(self.state.current_section, Section::None);
-                self.water_next_section()
+                // Alarm that fired before the section got closed by other means, is still in the queue
+                if self.state.current_section.is_none() {
+                    warn!("No section is open, ignoring");
+                    return self;
+                }
+                if self.state.section_alarm_replaced(id) {
+                    return self;
+                }
+                self.state.water_next_section()
             }
             WateringServiceMessage::WateringAlarmFired => {
                 info!("Got notification about watering alarm");
                 // TODO: Sanity call
                 // self.close_all_valves();
-                // start watchdog
 
                 // There should be no watering in progress
-                assert_eq!(self.state.current_section, Section::None);
+                if let Some(section) = &self.state.current_section {
+                    warn!("{section} is being watered, ignoring");
+                    return self;
+                }
 
-                self.water_next_section()
+                match self.state.next_program.clone() {
+                    Some(program) => self.state.start_program(program),
+                    None => warn!("No program is armed, ignoring"),
+                }
             }
-            WateringServiceMessage::StartWateringAt(when) => {
-                info!("Setting up watering on {when}");
-                self.state
-                    .clock_tx
-                    .send(ClockServiceMessage::SetWateringAlarmAt(when))
-                    .unwrap();
+            WateringServiceMessage::WatchdogExpired => self.state.watchdog_expired(),
+            WateringServiceMessage::StartWateringAt(when, tx) => {
+                reply(tx, self.state.start_watering_at(when))
             }
-            WateringServiceMessage::SetSectionDuration(section, duration) => {
-                info!("Setting up section {section:?} for {duration}");
-                let _ = self.state.section_durations.insert(section, duration);
+            WateringServiceMessage::SetSectionDuration(section, duration, tx) => {
+                reply(tx, self.state.set_section_duration(section, duration))
             }
-            WateringServiceMessage::EnableSectionFor(section, duration) => {
-                info!("Ad-hoc watering of {section:?}");
-                // TODO: reject if watering in progress
-                // TODO: handle that in AdHoc state
-                // if self.out_of_schedule_watering != Section::None {
-                //     self.close_all_valves();
-                // }
-
-                // if duration.is_zero() {
-                //     self.close_all_valves();
-                //     self.disable_section_alarm();
-                // }
-
-                // self.set_section_alarm(&duration);
-
-                return Box::new(AdHocSectionWatering { state: self.state });
+            WateringServiceMessage::SetProgram(name, program, tx) => {
+                reply(tx, self.state.set_program(name, program))
+            }
+            WateringServiceMessage::RemoveProgram(name, tx) => {
+                reply(tx, self.state.remove_program(&name))
+            }
+            WateringServiceMessage::EnableSectionFor(section, duration, tx) => {
+                info!("Ad-hoc watering of {section}");
+
+                if let Some(current_section) = &self.state.current_section {
+                    reply(tx, Err(WateringError::SectionBusy(current_section.clone())));
+                } else if let Err(err) = self.state.check_section(&section) {
+                    reply(tx, Err(err));
+                } else if duration.is_zero() {
+                    info!("Nothing to water, ignoring");
+                    reply(tx, Ok(()));
+                } else {
+                    // Sanity call, nothing should be open at this point
+                    self.state.close_all_valves();
+                    self.state.start_section(section, &duration);
+                    reply(tx, Ok(()));
+
+                    return Box::new(AdHocSectionWatering { state: self.state });
+                }
             }
             WateringServiceMessage::CloseAllValves => {
-                self.close_all_valves();
+                self.state.close_all_valves();
+                self.state.disable_section_alarm();
+                if let Some(section) = self.state.current_section.take() {
+                    self.state.record_section_end(&section, EndReason::Manual);
+                }
+
+                // The user stopped it, the rest of the run is not watered
+                self.state.abort_program();
             }
-            WateringServiceMessage::DisableWatering => self.disable_watering_alarm(),
+            WateringServiceMessage::DisableWatering => self.state.disable_watering(),
+            WateringServiceMessage::SetRainDelay(delay) => self.state.set_rain_delay(delay),
+            WateringServiceMessage::RainChanged(wet) => self.state.rain_changed(wet),
             WateringServiceMessage::GetStatus(tx) => {
-                let status = WateringStatus {
-                    section_durations: self.state.section_durations.clone(),
+                let mode = match self.state.current_program {
+                    Some(_) => WateringMode::Scheduled,
+                    None => WateringMode::Idle,
                 };
-                log::info!("Reporting watering status {status:#?}");
-                tx.send(status).unwrap();
+                self.state.report_status(mode, tx)
             }
+            WateringServiceMessage::GetHistory(query, tx) => self.state.report_history(&query, tx),
         }
 
         self
     }
+
+    fn state(&self) -> &WateringState {
+        &self.state
+    }
 }
 
 impl OnScheduleWatering {
-    pub fn new(clock_tx: ClockServiceChannel, sections_tx: SectionsServiceChannel) -> Self {
+    /// `sections` are watered in the given order.
+    /// `config` is usually the one loaded from the `storage`, every change to it gets stored back.
+    /// History of the watering is kept in the `storage` as well
+    pub fn new(
+        clock_tx: ClockServiceChannel,
+        sections_tx: SectionsServiceChannel,
+        sections: Vec<SectionId>,
+        config: WateringConfig,
+        mut storage: Box<dyn Storage>,
+    ) -> Self {
+        let history = History::load(storage.as_mut()).unwrap_or_else(|e| {
+            error!("Failed to load watering history, starting a new one {e:?}");
+            History::default()
+        });
+
         Self {
             state: Box::new(WateringState {
                 clock_tx,
                 sections_tx,
-                current_section: Section::None,
-
-                section_durations: enum_iterator::all::<Section>()
-                    .map(|section| (section, SectionDuration::default()))
-                    .collect::<HashMap<_, _>>(),
+                rain_tx: None,
+                moisture_tx: None,
+                storage,
+                sections,
+                current_section: None,
+                section_started_at: None,
+                section_ends_at: None,
+                section_alarm_id: 0,
+                watchdog_deadline: None,
+                watchdog_grace: WATCHDOG_GRACE,
+                faults: vec![],
+                current_program: None,
+                run_started_at: NaiveDateTime::default(),
+                completed_sections: vec![],
+                recovery_policy: RecoveryPolicy::default(),
+                next_program: None,
+                next_run: None,
+                raining: None,
+                last_skipped: None,
+                config,
+                history,
+                events: Publisher::default(),
             }),
         }
     }
 
+    /// Program run missed or interrupted by the power cut is handled according to the `policy`
+    pub fn with_recovery_policy(mut self, policy: RecoveryPolicy) -> Self {
+        self.state.recovery_policy = policy;
+        self
+    }
+
+    /// Schedule changes and faults are published as the events
+    pub fn with_events(mut self, events_tx: EventsServiceChannel) -> Self {
+        self.state.events = Publisher::new(events_tx);
+        self
+    }
+
+    /// Scheduled runs wait while the rain sensor is wet
+    pub fn with_rain_sensor(mut self, rain_tx: RainServiceChannel) -> Self {
+        self.state.rain_tx = Some(rain_tx);
+        self
+    }
+
+    /// Sections with the moist soil are skipped in the scheduled runs
+    pub fn with_moisture_sensors(mut self, moisture_tx: MoistureServiceChannel) -> Self {
+        self.state.moisture_tx = Some(moisture_tx);
+        self
+    }
+
     /// Starts the Watering Service, returns the WateringServiceChannel to communicate with it
-    pub fn start(self) -> WateringServiceChannel {
+    pub fn start(mut self) -> WateringServiceChannel {
         // Create channel that is used to communicate with this service
         let (tx, rx) = channel();
 
@@ -166,241 +652,907 @@ impl OnScheduleWatering {
             .send(ClockServiceMessage::SubscribeForWateringAlarm(tx.clone()))
             .unwrap();
 
+        if let Some(rain_tx) = &self.state.rain_tx {
+            // Recovered run has to know it right away, the changes come later on
+            let (wet_tx, wet_rx) = channel();
+            rain_tx.send(RainServiceMessage::IsWet(wet_tx)).unwrap();
+            self.state.raining = Some(
+                wet_rx
+                    .recv_timeout(Duration::from_secs(10))
+                    .expect("Rain service does not respond"),
+            );
+            rain_tx
+                .send(RainServiceMessage::Subscribe(tx.clone()))
+                .unwrap();
+        }
+
+        // Alarm got disabled on boot, re-arm it from the stored configuration,
+        // unless the recovered run does it once complete
+        self.state.recover();
+        self.state.arm_next_program();
+
         // Create Watering service
-        std::thread::spawn(move || {
-            log::info!("Hello from Watering service!");
-            let mut boxed: Box<dyn HandleMessage> = Box::new(self);
-
-            while let Ok(msg) = rx.recv() {
-                log::debug!("Handling {msg:?}");
-
-                // Messages that can come in any order. This is pure runtime dispatch.
-                // So I could't use classic TypeState pattern. Classic version assumes
-                // some deterministic, known during compilation time order. If A -> B -> C...
-                // Here messages can come at any time in any order, static dispatch is useless here.
-                // That's why this version utilizes Box and dynamic dispatch
-                // boxed is the current state, handle_message returns next state, depending on the message
-                // handling result. We are in a loop so boxed needs to be of generic enough type: Box<dyn HandleMessage>
-                boxed = boxed.handle_message(msg);
-            }
-        });
+        std::thread::Builder::new()
+            .name("watering service".to_string())
+            .spawn(move || {
+                log::info!("Hello from Watering service!");
+                let mut boxed: Box<dyn HandleMessage> = Box::new(self);
+
+                loop {
+                    let msg = match boxed.state().watchdog_deadline {
+                        Some(deadline) => {
+                            match rx
+                                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                            {
+                                Ok(msg) => msg,
+                                Err(RecvTimeoutError::Timeout) => {
+                                    WateringServiceMessage::WatchdogExpired
+                                }
+                                Err(RecvTimeoutError::Disconnected) => break,
+                            }
+                        }
+                        None => match rx.recv() {
+                            Ok(msg) => msg,
+                            Err(_) => break,
+                        },
+                    };
+
+                    log::debug!(
+                        "Handling {msg:?}, current section {:?}",
+                        boxed.state().current_section
+                    );
+
+                    // Messages that can come in any order. This is pure runtime dispatch.
+                    // So I could't use classic TypeState pattern. Classic version assumes
+                    // some deterministic, known during compilation time order. If A -> B -> C...
+                    // Here messages can come at any time in any order, static dispatch is useless here.
+                    // That's why this version utilizes Box and dynamic dispatch
+                    // boxed is the current state, handle_message returns next state, depending on the message
+                    // handling result. We are in a loop so boxed needs to be of generic enough type: Box<dyn HandleMessage>
+                    boxed = boxed.handle_message(msg);
+                }
+            })
+            .expect("Cannot spawn Watering service");
 
         tx
     }
+}
 
-    fn close_all_valves(&mut self) {
-        info!("Closing all valves...");
-        for section in enum_iterator::all::<Section>() {
-            info!("     {section:?}...");
-            self.state
-                .sections_tx
-                .send(crate::sections::SectionsServiceMessage::Disable(section))
-                .unwrap();
-        }
+impl WateringState {
+    fn start_watering_at(&mut self, when: NaiveTime) -> Result<(), WateringError> {
+        let mut program = self.default_program();
+        program.start_at = Some(when);
+        self.check_overlap(DEFAULT_PROGRAM, &program)?;
+
+        let _ = self
+            .config
+            .programs
+            .insert(DEFAULT_PROGRAM.to_string(), program);
+        self.config.enabled = true;
+        self.store_config();
+
+        self.arm_next_program();
+        Ok(())
     }
 
-    fn water_next_section(&mut self) {
-        debug!("Disabling {:?}", self.state.current_section);
-
-        // disable current section
-        self.disable_section(self.state.current_section);
-        // feed watchdog
+    fn default_program(&self) -> Program {
+        self.config
+            .programs
+            .get(DEFAULT_PROGRAM)
+            .cloned()
+            .unwrap_or_default()
+    }
 
-        self.state.current_section = enum_iterator::next_cycle(&self.state.current_section);
+    /// Program that would never run, because of the other one, is rejected
+    fn check_overlap(&self, name: &str, program: &Program) -> Result<(), WateringError> {
+        match self.config.overlapping_program(name, program) {
+            Some(other) => Err(WateringError::ProgramOverlaps(other.to_string())),
+            None => Ok(()),
+        }
+    }
 
-        if self.state.current_section == Section::None {
-            info!("Watering complete");
-            // disable alarm2
-            self.disable_section_alarm();
-            // disable watchdog
-            return;
+    fn check_section(&self, section: &SectionId) -> Result<(), WateringError> {
+        if self.sections.contains(section) {
+            Ok(())
+        } else {
+            Err(SectionsError::UnknownSection(section.clone()).into())
         }
+    }
 
-        let section_duration = self
-            .state
-            .section_durations
-            .get(&self.state.current_section)
-            .unwrap();
+    fn set_section_duration(
+        &mut self,
+        section: SectionId,
+        duration: SectionDuration,
+    ) -> Result<(), WateringError> {
+        self.check_section(&section)?;
+
+        info!("Setting up section {section} for {duration}");
+        // Longer run might reach the next program
+        let mut program = self.default_program();
+        let _ = program.section_durations.insert(section, duration);
+        self.check_overlap(DEFAULT_PROGRAM, &program)?;
+
+        let _ = self
+            .config
+            .programs
+            .insert(DEFAULT_PROGRAM.to_string(), program);
+        self.store_config();
+        Ok(())
+    }
 
-        if section_duration.is_zero() {
-            info!(
-                "Section {:?} is disabled, moving to another",
-                self.state.current_section
-            );
+    fn set_program(&mut self, name: String, program: Program) -> Result<(), WateringError> {
+        info!("Setting up program {name}: {program:?}");
+        self.check_overlap(&name, &program)?;
 
-            self.water_next_section();
-            return;
-        }
+        let _ = self.config.programs.insert(name, program);
+        self.config.enabled = true;
+        self.store_config();
 
-        self.enable_section(self.state.current_section);
-        self.set_section_alarm(section_duration);
-        // reload watchdog
+        self.arm_next_program();
+        Ok(())
     }
 
-    fn disable_watering_alarm(&self) {
-        info!("Disabling watering alarm");
-        self.state
-            .clock_tx
-            .send(ClockServiceMessage::DisableWateringAlarm)
-            .unwrap();
-    }
+    fn remove_program(&mut self, name: &str) -> Result<(), WateringError> {
+        info!("Removing program {name}");
+        if self.config.programs.remove(name).is_none() {
+            return Err(WateringError::UnknownProgram(name.to_string()));
+        }
+        self.store_config();
 
-    fn disable_section_alarm(&self) {
-        info!("Disabling section alarm");
-        self.state
-            .clock_tx
-            .send(ClockServiceMessage::DisableSectionAlarm)
-            .unwrap();
+        self.arm_next_program();
+        Ok(())
     }
 
-    fn set_section_alarm(&self, section_duration: &SectionDuration) {
-        info!("Arming section alarm {}", section_duration);
-        // Arm alarm2 for that section
-        self.state
-            .clock_tx
-            .send(ClockServiceMessage::SetSectionAlarmAfter(*section_duration))
-            .unwrap();
-    }
+    fn set_rain_delay(&mut self, delay: TimeDelta) {
+        let paused_until = match delay > TimeDelta::zero() {
+            true => match self.now() {
+                Ok(now) => Some(now + delay),
+                Err(e) => {
+                    error!("Cannot tell when the rain delay ends, ignoring it {e:?}");
+                    return;
+                }
+            },
+            false => None,
+        };
 
-    fn disable_section(&self, section: Section) {
-        info!("Disabling {section:?}");
-        self.state
-            .sections_tx
-            .send(crate::sections::SectionsServiceMessage::Disable(section))
-            .unwrap();
+        info!("Rain delay until {paused_until:?}");
+        self.config.paused_until = paused_until;
+        self.store_config();
+
+        // Next run moves past the delay
+        self.arm_next_program();
     }
 
-    fn enable_section(&self, section: Section) {
-        info!("Enabling {section:?}");
-        self.state
-            .sections_tx
-            .send(crate::sections::SectionsServiceMessage::Enable(section))
-            .unwrap();
+    /// Why the scheduled run cannot start at given local time, if it cannot
+    fn skip_reason(&self, now: NaiveDateTime) -> Option<SkipReason> {
+        if self.config.is_paused(now) {
+            Some(SkipReason::RainDelay)
+        } else if self.raining == Some(true) {
+            Some(SkipReason::Rain)
+        } else {
+            None
+        }
     }
-}
 
-#[cfg(test)]
-pub mod tests {
-    use std::{sync::mpsc::Receiver, time::Duration};
+    /// Wet sensor aborts the scheduled run, the ad-hoc watering is left to the user
+    fn rain_changed(&mut self, wet: bool) {
+        info!("Rain sensor is {}", if wet { "wet" } else { "dry" });
+        self.raining = Some(wet);
 
-    use chrono::{NaiveDateTime, TimeDelta};
+        if !wet {
+            return;
+        }
+        let Some(program) = self.current_program.clone() else {
+            return;
+        };
 
-    use crate::sections::SectionsServiceMessage;
+        warn!("Program {program} is aborted, it is raining");
+        self.close_all_valves();
+        if let Some(section) = self.current_section.take() {
+            self.record_section_end(&section, EndReason::Rain);
+        }
+        self.disable_section_alarm();
 
-    use super::*;
+        // The rest of the run is not needed any more
+        self.abort_program();
+    }
 
-    struct ClockMock;
+    fn disable_watering(&mut self) {
+        self.config.enabled = false;
+        self.store_config();
 
-    impl ClockMock {
-        /// rx - is used to handle requests from the service under test
-        /// tx - sends captured messages to the test, for verification
-        fn start(rx: Receiver<ClockServiceMessage>, tx: ClockServiceChannel) {
-            std::thread::spawn(move || {
-                let mut now =
-                    NaiveDateTime::parse_from_str("2015-09-05 23:56:04", "%Y-%m-%d %H:%M:%S")
-                        .unwrap();
+        self.next_program = None;
+        self.next_run = None;
+        self.disable_watering_alarm();
+        self.events.publish(Event::ScheduleChanged {
+            next_program: None,
+            at: None,
+        });
+    }
 
-                while let Ok(msg) = rx.recv() {
-                    tx.send(msg.clone()).unwrap();
+    /// Starts watering of the program, if it is due today
+    fn start_program(&mut self, name: String) {
+        let now = match self.now() {
+            Ok(now) => now,
+            Err(e) => {
+                error!("Cannot tell if program {name} is due, skipping it {e:?}");
+                self.arm_next_program();
+                return;
+            }
+        };
+
+        let due = self
+            .config
+            .programs
+            .get(&name)
+            .is_some_and(|program| program.rule.is_due(now.date()));
+        if !due {
+            info!("Program {name} is not due on {}, skipping", now.date());
+            self.arm_next_program();
+            return;
+        }
 
-                    match msg {
-                        ClockServiceMessage::GetDateTime(tx) => {
-                            tx.send(now).unwrap();
-                        }
-                        ClockServiceMessage::SetSectionAlarmAfter(offset) => {
-                            let future = now.checked_add_signed(offset.into_inner()).unwrap();
-                            now = future;
-                        }
-                        _msg => {}
-                    }
-                }
+        if let Some(reason) = self.skip_reason(now) {
+            info!("Program {name} is skipped, {reason:?}");
+            self.record(HistoryEntry {
+                at: now,
+                event: HistoryEvent::RunSkipped {
+                    program: name.clone(),
+                    reason,
+                },
+            });
+            // Skipped run is as good as finished, it is not caught up on after a reboot
+            let last_run = LastRun {
+                program: name.clone(),
+                started_at: now,
+                completed: vec![],
+                finished: true,
+            };
+            if let Err(e) = last_run.store(self.storage.as_mut()) {
+                error!("Failed to store the skipped run {e:?}");
+            }
+            self.last_skipped = Some(SkippedRun {
+                program: name,
+                at: now,
+                reason,
             });
+            self.arm_next_program();
+            return;
         }
+
+        info!("Starting program {name}");
+        self.current_program = Some(name);
+        self.next_run = None;
+        self.run_started_at = now;
+        self.completed_sections.clear();
+        self.store_last_run(false);
+        self.water_next_section();
     }
 
-    pub fn example_valid_configuration_works() {
-        let (clock_tx, rx) = channel();
-        let (tx, clock_rx) = channel();
-        ClockMock::start(rx, tx);
+    /// Catches up on the program run that got missed or interrupted while the board was off
+    fn recover(&mut self) {
+        if self.recovery_policy == RecoveryPolicy::Skip || !self.config.enabled {
+            return;
+        }
 
-        let (sections_tx, sections_rx) = channel();
+        let last_run = match LastRun::load(self.storage.as_mut()) {
+            Ok(last_run) => last_run,
+            Err(e) => {
+                error!("Cannot load the last run, nothing to recover {e:?}");
+                return;
+            }
+        };
+        let now = match self.now() {
+            Ok(now) => now,
+            Err(e) => {
+                error!("Cannot tell if any run got missed {e:?}");
+                return;
+            }
+        };
 
-        let mut watering = OnScheduleWatering::new(clock_tx, sections_tx);
+        if let Some(reason) = self.skip_reason(now) {
+            info!("Nothing to recover, {reason:?}");
+            return;
+        }
 
-        // Valid clean state
-        assert_eq!(watering.current_section, Section::None);
+        let Some(recovery) = recovery(self.recovery_policy, &self.config, last_run.as_ref(), now)
+        else {
+            info!("Nothing to recover, last run {last_run:?}");
+            return;
+        };
 
-        let vegs_duration = TimeDelta::minutes(5).try_into().unwrap();
-        let flowers_duration = TimeDelta::minutes(10).try_into().unwrap();
-        let grass_duration = TimeDelta::minutes(20).try_into().unwrap();
-        let terrace_duration = TimeDelta::minutes(8).try_into().unwrap();
+        info!(
+            "Recovering program {} due at {}, skipping {:?}",
+            recovery.program, recovery.started_at, recovery.completed
+        );
+        self.current_program = Some(recovery.program);
+        self.run_started_at = recovery.started_at;
+        self.completed_sections = recovery.completed;
+        self.store_last_run(false);
+        self.water_next_section();
+    }
 
-        watering.section_durations = [
-            (Section::Vegs, vegs_duration),
-            (Section::Flowers, flowers_duration),
-            (Section::Grass, grass_duration),
-            (Section::Terrace, terrace_duration),
-        ]
-        .into();
+    /// Arms the watering alarm for the program that is due next
+    fn arm_next_program(&mut self) {
+        if !self.config.enabled {
+            return;
+        }
 
-        // Simulate interrupt from the clock - watering should start
-        watering.handle_msg(WateringServiceMessage::WateringAlarmFired);
+        if self.current_program.is_some() {
+            // Watering alarm must not fire during the run, it gets armed once the run is complete
+            return;
+        }
 
-        // Expect Vegs to be first
-        verify_moved_to_next_section(
-            Section::None,
-            watering.current_section,
-            Section::Vegs,
-            vegs_duration,
-            &sections_rx,
-            &clock_rx,
-        );
+        let now = match self.now() {
+            Ok(now) => now,
+            Err(e) => {
+                error!("Cannot arm the next program {e:?}");
+                return;
+            }
+        };
+
+        if self
+            .config
+            .paused_until
+            .is_some_and(|_| !self.config.is_paused(now))
+        {
+            info!("Rain delay is over");
+            self.config.paused_until = None;
+            self.store_config();
+        }
 
-        // Simulate vegs finished
-        watering.handle_msg(WateringServiceMessage::SectionAlarmFired);
+        match self.config.next_program(now.time()) {
+            Some((name, when)) => {
+                info!("Next program {name} on {when}");
+                self.next_program = Some(name.to_string());
+                // Watering alarm is armed as usual, the runs are skipped until the delay is over
+                self.next_run = self
+                    .config
+                    .next_run(self.config.paused_until.unwrap_or(now));
+                self.clock_tx
+                    .send(ClockServiceMessage::SetWateringAlarmAt(when))
+                    .unwrap();
+                self.events.publish(Event::ScheduleChanged {
+                    next_program: self.next_program.clone(),
+                    at: Some(when),
+                });
+            }
+            None => {
+                info!("None of the programs is scheduled");
+                self.next_program = None;
+                self.next_run = None;
+                self.disable_watering_alarm();
+                self.events.publish(Event::ScheduleChanged {
+                    next_program: None,
+                    at: None,
+                });
+            }
+        }
+    }
 
-        verify_moved_to_next_section(
-            Section::Vegs,
-            watering.current_section,
-            Section::Flowers,
-            flowers_duration,
-            &sections_rx,
-            &clock_rx,
+    fn now(&self) -> Result<NaiveDateTime> {
+        let (tx, rx) = channel();
+        self.clock_tx
+            .send(ClockServiceMessage::GetDateTime(tx))
+            .context("while sending get date time to clock service")?;
+
+        rx.recv_timeout(Duration::from_secs(10))
+            .context("while receiving date time from clock service")
+    }
+
+    /// Progress of the current program, so the run can be recovered after the power cut
+    fn store_last_run(&mut self, finished: bool) {
+        let Some(program) = self.current_program.clone() else {
+            return;
+        };
+
+        let last_run = LastRun {
+            program,
+            started_at: self.run_started_at,
+            completed: self.completed_sections.clone(),
+            finished,
+        };
+        if let Err(e) = last_run.store(self.storage.as_mut()) {
+            error!("Failed to store the last run {e:?}");
+        }
+    }
+
+    fn store_config(&mut self) {
+        // Not fatal, watering goes on with the configuration in memory
+        if let Err(e) = self.config.store(self.storage.as_mut()) {
+            error!("Failed to store watering configuration {e:?}");
+        }
+    }
+
+    fn report_status(&self, mode: WateringMode, tx: Sender<WateringStatus>) {
+        let status = WateringStatus {
+            mode,
+            enabled: self.config.enabled,
+            programs: self.config.programs.clone(),
+            next_program: self.next_program.clone(),
+            next_run: self.next_run.clone(),
+            paused_until: self.config.paused_until,
+            raining: self.raining,
+            last_skipped: self.last_skipped.clone(),
+            soil_moisture: self.soil_moisture(),
+            current_program: self.current_program.clone(),
+            current_section: self.current_section.clone(),
+            section_started_at: self.current_section.as_ref().and(self.section_started_at),
+            section_ends_at: self.current_section.as_ref().and(self.section_ends_at),
+            faults: self.faults.clone(),
+        };
+        log::info!("Reporting watering status {status:#?}");
+        tx.send(status).unwrap();
+    }
+
+    fn close_all_valves(&mut self) {
+        info!("Closing all valves...");
+        for section in &self.sections {
+            info!("     {section}...");
+            self.sections_tx
+                .send(SectionsServiceMessage::Disable(section.clone()))
+                .unwrap();
+        }
+    }
+
+    fn water_next_section(&mut self) {
+        debug!("Disabling {:?}", self.current_section);
+
+        // disable current section
+        if let Some(section) = self.current_section.clone() {
+            self.disable_section(&section);
+            self.record_section_end(&section, EndReason::Alarm);
+            if self.current_program.is_some() {
+                self.completed_sections.push(section);
+            }
+        }
+
+        let next = match &self.current_section {
+            Some(current) => self
+                .sections
+                .iter()
+                .position(|section| section == current)
+                .map_or(self.sections.len(), |i| i + 1),
+            None => 0,
+        };
+        self.current_section = self.sections.get(next).cloned();
+
+        let Some(section) = self.current_section.clone() else {
+            info!("Watering complete");
+            // disable alarm2
+            self.disable_section_alarm();
+
+            self.store_last_run(true);
+            self.current_program = None;
+            self.arm_next_program();
+            return;
+        };
+
+        // Program might got removed during the run, remaining sections are skipped then.
+        // Sections completed before the run got interrupted are skipped as well
+        let section_duration = self
+            .current_program
+            .as_ref()
+            .and_then(|name| self.config.programs.get(name))
+            .filter(|_| !self.completed_sections.contains(&section))
+            .map(|program| program.duration_on(&section, self.run_started_at.date()))
+            .unwrap_or_default();
+
+        if section_duration.is_zero() {
+            info!("Section {section} is disabled or not due, moving to another");
+
+            self.water_next_section();
+            return;
+        }
+
+        if self.skip_moist_section(&section) {
+            self.water_next_section();
+            return;
+        }
+
+        self.store_last_run(false);
+        self.open_section(&section, &section_duration);
+    }
+
+    /// Samples the soil of the section before its turn, the moist one is logged and skipped.
+    /// Section without the probe, or with the one that cannot be read, is watered as usual
+    fn skip_moist_section(&mut self, section: &SectionId) -> bool {
+        let Some(moisture_tx) = &self.moisture_tx else {
+            return false;
+        };
+
+        let (tx, rx) = channel();
+        let reading = moisture_tx
+            .send(MoistureServiceMessage::Sample(section.clone(), tx))
+            .context("Moisture service is not running")
+            .and_then(|_| {
+                rx.recv_timeout(Duration::from_secs(10))
+                    .context("Moisture service does not respond")
+            });
+        let reading = match reading {
+            Ok(Some(reading)) if reading.is_moist() => reading,
+            Ok(_) => return false,
+            Err(e) => {
+                error!("Cannot tell if {section} soil is moist, watering it {e:?}");
+                return false;
+            }
+        };
+
+        info!(
+            "{section} soil moisture is {}%, above {}%, skipping it",
+            reading.moisture, reading.skip_above
+        );
+        match self.now() {
+            Ok(at) => self.record(HistoryEntry {
+                at,
+                event: HistoryEvent::SectionSkipped {
+                    section: section.clone(),
+                    program: self.current_program.clone().unwrap_or_default(),
+                    moisture: reading.moisture,
+                },
+            }),
+            Err(e) => error!("Cannot log skipping {section} {e:?}"),
+        }
+        true
+    }
+
+    /// Latest readings of the probes, for the status
+    fn soil_moisture(&self) -> BTreeMap<SectionId, MoistureReading> {
+        let Some(moisture_tx) = &self.moisture_tx else {
+            return BTreeMap::new();
+        };
+
+        let (tx, rx) = channel();
+        if moisture_tx
+            .send(MoistureServiceMessage::GetReadings(tx))
+            .is_err()
+        {
+            error!("Moisture service is not running");
+            return BTreeMap::new();
+        }
+        rx.recv_timeout(Duration::from_secs(10))
+            .inspect_err(|e| error!("Moisture service does not respond {e:?}"))
+            .unwrap_or_default()
+    }
+
+    /// Opens the section and arms the section alarm to close it after given duration
+    fn start_section(&mut self, section: SectionId, duration: &SectionDuration) {
+        self.open_section(&section, duration);
+        self.current_section = Some(section);
+    }
+
+    fn open_section(&mut self, section: &SectionId, duration: &SectionDuration) {
+        self.enable_section(section);
+        self.section_started_at = self
+            .now()
+            .inspect_err(|e| error!("Cannot tell when {section} got opened {e:?}"))
+            .ok();
+        self.section_ends_at = self
+            .section_started_at
+            .map(|started_at| started_at + duration.into_inner());
+        self.set_section_alarm(duration);
+    }
+
+    /// Closes currently opened section, section alarm is no longer needed
+    fn stop_current_section(&mut self, ended_by: EndReason) {
+        if let Some(section) = self.current_section.take() {
+            self.disable_section(&section);
+            self.record_section_end(&section, ended_by);
+        }
+        self.disable_section_alarm();
+    }
+
+    /// Logs watering of the section that just got closed, unless it was not opened at all
+    fn record_section_end(&mut self, section: &SectionId, ended_by: EndReason) {
+        if self.section_started_at.is_none() {
+            return;
+        }
+
+        match self.now() {
+            Ok(at) => self.record_section_end_at(section, ended_by, at),
+            Err(e) => {
+                self.section_started_at = None;
+                error!("Cannot log watering of {section} {e:?}");
+            }
+        }
+    }
+
+    fn record_section_end_at(
+        &mut self,
+        section: &SectionId,
+        ended_by: EndReason,
+        at: NaiveDateTime,
+    ) {
+        let Some(started_at) = self.section_started_at.take() else {
+            return;
+        };
+
+        let entry = HistoryEntry {
+            at,
+            event: HistoryEvent::SectionWatered {
+                section: section.clone(),
+                program: self.current_program.clone(),
+                started_at,
+                duration_seconds: (at - started_at).max(TimeDelta::zero()).num_seconds(),
+                ended_by,
+            },
+        };
+        self.record(entry);
+    }
+
+    fn record(&mut self, entry: HistoryEntry) {
+        info!("Watering history: {entry:?}");
+        // Not fatal, the entry is kept in memory
+        if let Err(e) = self.history.record(entry, self.storage.as_mut()) {
+            error!("Failed to store watering history {e:?}");
+        }
+    }
+
+    fn report_history(&self, query: &HistoryQuery, tx: Sender<Vec<HistoryEntry>>) {
+        tx.send(self.history.query(query)).unwrap();
+    }
+
+    /// Section alarm did not arrive, nothing that relies on it can be trusted - close everything
+    fn watchdog_expired(&mut self) {
+        error!(
+            "Section alarm for {:?} did not arrive in time, closing all the valves",
+            self.current_section
+        );
+
+        let at = self
+            .now()
+            .inspect_err(|e| error!("Cannot get the time of the fault {e:?}"))
+            .ok();
+        if let Some(section) = self.current_section.take() {
+            self.events.publish(Event::Fault {
+                section: Some(section.clone()),
+                reason: "Section alarm did not arrive in time".to_string(),
+            });
+            match at {
+                Some(at) => self.record_section_end_at(&section, EndReason::Watchdog, at),
+                None => self.section_started_at = None,
+            }
+            if self.faults.len() == MAX_FAULTS {
+                let _ = self.faults.remove(0);
+            }
+            self.faults.push(WatchdogFault { section, at });
+        }
+
+        self.close_all_valves();
+        self.disable_section_alarm();
+
+        // The section alarm cannot be trusted
+        self.abort_program();
+    }
+
+    /// Ends the current program before its time, aborted run is not recovered
+    fn abort_program(&mut self) {
+        self.store_last_run(true);
+        if let Some(program) = self.current_program.take() {
+            warn!("Program {program} is aborted");
+            self.arm_next_program();
+        }
+    }
+
+    fn disable_watering_alarm(&self) {
+        info!("Disabling watering alarm");
+        self.clock_tx
+            .send(ClockServiceMessage::DisableWateringAlarm)
+            .unwrap();
+    }
+
+    fn disable_section_alarm(&mut self) {
+        info!("Disabling section alarm");
+        self.watchdog_deadline = None;
+        self.clock_tx
+            .send(ClockServiceMessage::DisableSectionAlarm)
+            .unwrap();
+    }
+
+    /// Section alarm that fired just before the next one got armed is still in the queue
+    fn section_alarm_replaced(&self, id: u32) -> bool {
+        if id == self.section_alarm_id {
+            return false;
+        }
+
+        warn!(
+            "Section alarm #{id} got replaced by #{}, ignoring",
+            self.section_alarm_id
+        );
+        true
+    }
+
+    fn set_section_alarm(&mut self, section_duration: &SectionDuration) {
+        self.section_alarm_id = self.section_alarm_id.wrapping_add(1);
+        info!(
+            "Arming section alarm #{} {}",
+            self.section_alarm_id, section_duration
+        );
+        self.watchdog_deadline = Some(
+            Instant::now()
+                + section_duration.into_inner().to_std().unwrap_or_default()
+                + self.watchdog_grace,
+        );
+        // Arm alarm2 for that section
+        self.clock_tx
+            .send(ClockServiceMessage::SetSectionAlarmAfter(
+                *section_duration,
+                self.section_alarm_id,
+            ))
+            .unwrap();
+    }
+
+    fn disable_section(&self, section: &SectionId) {
+        info!("Disabling {section}");
+        self.sections_tx
+            .send(SectionsServiceMessage::Disable(section.clone()))
+            .unwrap();
+    }
+
+    fn enable_section(&self, section: &SectionId) {
+        info!("Enabling {section}");
+        self.sections_tx
+            .send(SectionsServiceMessage::Enable(section.clone()))
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc::Receiver, time::Duration};
+
+    use chrono::{NaiveDateTime, TimeDelta, Weekday};
+
+    use crate::{
+        host::{FakeMoistureProbe, MemoryStorage},
+        moisture::MoistureService,
+        sections::{MoistureProbeConfig, SectionsServiceMessage},
+    };
+
+    use super::*;
+
+    /// Sections in the watering order
+    fn test_sections() -> Vec<SectionId> {
+        ["Vegs", "Flowers", "Grass", "Terrace"]
+            .into_iter()
+            .map(SectionId::from)
+            .collect()
+    }
+
+    struct ClockMock;
+
+    impl ClockMock {
+        /// rx - is used to handle requests from the service under test
+        /// tx - sends captured messages to the test, for verification
+        fn start(rx: Receiver<ClockServiceMessage>, tx: ClockServiceChannel) {
+            let now =
+                NaiveDateTime::parse_from_str("2015-09-05 23:56:04", "%Y-%m-%d %H:%M:%S").unwrap();
+            Self::start_at(rx, tx, now);
+        }
+
+        fn start_at(
+            rx: Receiver<ClockServiceMessage>,
+            tx: ClockServiceChannel,
+            now: NaiveDateTime,
+        ) {
+            std::thread::spawn(move || {
+                let mut now = now;
+
+                while let Ok(msg) = rx.recv() {
+                    tx.send(msg.clone()).unwrap();
+
+                    match msg {
+                        ClockServiceMessage::GetDateTime(tx) => {
+                            tx.send(now).unwrap();
+                        }
+                        ClockServiceMessage::SetSectionAlarmAfter(offset, _) => {
+                            let future = now.checked_add_signed(offset.into_inner()).unwrap();
+                            now = future;
+                        }
+                        _msg => {}
+                    }
+                }
+            });
+        }
+    }
+
+    #[test]
+    fn example_valid_configuration_works() {
+        let (clock_tx, rx) = channel();
+        let (tx, clock_rx) = channel();
+        ClockMock::start(rx, tx);
+
+        let (sections_tx, sections_rx) = channel();
+
+        let mut watering = OnScheduleWatering::new(
+            clock_tx,
+            sections_tx,
+            test_sections(),
+            WateringConfig::default(),
+            Box::new(MemoryStorage::default()),
+        );
+
+        // Valid clean state
+        assert_eq!(watering.state.current_section, None);
+
+        let vegs_duration = TimeDelta::minutes(5).try_into().unwrap();
+        let flowers_duration = TimeDelta::minutes(10).try_into().unwrap();
+        let grass_duration = TimeDelta::minutes(20).try_into().unwrap();
+        let terrace_duration = TimeDelta::minutes(8).try_into().unwrap();
+
+        arm_default_program(
+            &mut watering,
+            [
+                (SectionId::from("Vegs"), vegs_duration),
+                (SectionId::from("Flowers"), flowers_duration),
+                (SectionId::from("Grass"), grass_duration),
+                (SectionId::from("Terrace"), terrace_duration),
+            ]
+            .into(),
+        );
+        let mut watering: Box<dyn HandleMessage> = Box::new(watering);
+
+        // Simulate interrupt from the clock - watering should start
+        watering = watering.handle_message(WateringServiceMessage::WateringAlarmFired);
+        verify_date_checked(&clock_rx);
+
+        // Expect Vegs to be first
+        verify_moved_to_next_section(
+            None,
+            &watering.state().current_section,
+            "Vegs",
+            vegs_duration,
+            &sections_rx,
+            &clock_rx,
+        );
+
+        // Simulate vegs finished
+        watering = fire_section_alarm(watering);
+
+        verify_moved_to_next_section(
+            Some("Vegs"),
+            &watering.state().current_section,
+            "Flowers",
+            flowers_duration,
+            &sections_rx,
+            &clock_rx,
         );
 
         // Simulate flowers finished
-        watering.handle_msg(WateringServiceMessage::SectionAlarmFired);
+        watering = fire_section_alarm(watering);
         verify_moved_to_next_section(
-            Section::Flowers,
-            watering.current_section,
-            Section::Grass,
+            Some("Flowers"),
+            &watering.state().current_section,
+            "Grass",
             grass_duration,
             &sections_rx,
             &clock_rx,
         );
 
         // Simulate grass finished
-        watering.handle_msg(WateringServiceMessage::SectionAlarmFired);
+        watering = fire_section_alarm(watering);
         verify_moved_to_next_section(
-            Section::Grass,
-            watering.current_section,
-            Section::Terrace,
+            Some("Grass"),
+            &watering.state().current_section,
+            "Terrace",
             terrace_duration,
             &sections_rx,
             &clock_rx,
         );
 
         // Simulate terrace finished
-        watering.handle_msg(WateringServiceMessage::SectionAlarmFired);
+        watering = fire_section_alarm(watering);
 
         // Expect watering moved to None section
-        assert_eq!(watering.current_section, Section::None);
+        assert_eq!(watering.state().current_section, None);
 
         // Expect Terrace section got disabled
         assert!(matches!(
             sections_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
-            SectionsServiceMessage::Disable(Section::Terrace)
+            SectionsServiceMessage::Disable(section) if section.as_str() == "Terrace"
         ));
+        // Closed section is logged in the history
+        verify_date_checked(&clock_rx);
 
         // Expect section alarm is disabled
         assert!(matches!(
@@ -409,90 +1561,111 @@ pub mod tests {
         ));
     }
 
-    pub fn can_skip_a_section() {
+    #[test]
+    fn can_skip_a_section() {
         let (clock_tx, rx) = channel();
         let (tx, clock_rx) = channel();
         ClockMock::start(rx, tx);
 
         let (sections_tx, sections_rx) = channel();
 
-        let mut watering = OnScheduleWatering::new(clock_tx, sections_tx);
+        let mut watering = OnScheduleWatering::new(
+            clock_tx,
+            sections_tx,
+            test_sections(),
+            WateringConfig::default(),
+            Box::new(MemoryStorage::default()),
+        );
 
         // Valid clean state
-        assert_eq!(watering.current_section, Section::None);
+        assert_eq!(watering.state.current_section, None);
 
         // Skip flowers and terrace
         let vegs_duration = TimeDelta::minutes(5).try_into().unwrap();
         let grass_duration = TimeDelta::minutes(20).try_into().unwrap();
-        watering.section_durations = [
-            (Section::Vegs, vegs_duration),
-            (Section::Flowers, SectionDuration::default()),
-            (Section::Grass, grass_duration),
-            (Section::Terrace, SectionDuration::default()),
-        ]
-        .into();
+        arm_default_program(
+            &mut watering,
+            [
+                (SectionId::from("Vegs"), vegs_duration),
+                (SectionId::from("Flowers"), SectionDuration::default()),
+                (SectionId::from("Grass"), grass_duration),
+                (SectionId::from("Terrace"), SectionDuration::default()),
+            ]
+            .into(),
+        );
+        let mut watering: Box<dyn HandleMessage> = Box::new(watering);
 
         // Simulate interrupt from the clock - watering should start
-        watering.handle_msg(WateringServiceMessage::WateringAlarmFired);
+        watering = watering.handle_message(WateringServiceMessage::WateringAlarmFired);
+        verify_date_checked(&clock_rx);
 
         // Expect Vegs to be first
         verify_moved_to_next_section(
-            Section::None,
-            watering.current_section,
-            Section::Vegs,
+            None,
+            &watering.state().current_section,
+            "Vegs",
             vegs_duration,
             &sections_rx,
             &clock_rx,
         );
 
         // Simulate vegs finished, should skip flowers, go to grass
-        watering.handle_msg(WateringServiceMessage::SectionAlarmFired);
+        watering = fire_section_alarm(watering);
 
         // Expect watering moved to next valid section - grass
-        assert_eq!(watering.current_section, Section::Grass);
+        assert_eq!(
+            watering.state().current_section,
+            Some(SectionId::from("Grass"))
+        );
 
         // Expect vegs section got disabled
         assert!(matches!(
             sections_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
-            SectionsServiceMessage::Disable(Section::Vegs)
+            SectionsServiceMessage::Disable(section) if section.as_str() == "Vegs"
         ));
 
         // Expect skipped flowers section got disabled
         assert!(matches!(
             sections_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
-            SectionsServiceMessage::Disable(Section::Flowers)
+            SectionsServiceMessage::Disable(section) if section.as_str() == "Flowers"
         ));
 
         // Expect grass section got enabled
         assert!(matches!(
             sections_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
-            SectionsServiceMessage::Enable(Section::Grass)
+            SectionsServiceMessage::Enable(section) if section.as_str() == "Grass"
         ));
 
+        // Vegs are logged in the history, and grass opening time is taken
+        verify_date_checked(&clock_rx);
+        verify_date_checked(&clock_rx);
+
         // Expect alarm2 is set
         match clock_rx.recv_timeout(Duration::from_secs(1)).unwrap() {
-            ClockServiceMessage::SetSectionAlarmAfter(offset) => {
+            ClockServiceMessage::SetSectionAlarmAfter(offset, _) => {
                 assert_eq!(offset, grass_duration);
             }
             _ => panic!("Unexpected message"),
         }
 
         // Simulate grass finished, skip terrace, finish watering
-        watering.handle_msg(WateringServiceMessage::SectionAlarmFired);
+        watering = fire_section_alarm(watering);
 
         // Expect watering moved to None section
-        assert_eq!(watering.current_section, Section::None);
+        assert_eq!(watering.state().current_section, None);
 
         // Expect Grass section got disabled
         assert!(matches!(
             sections_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
-            SectionsServiceMessage::Disable(Section::Grass)
+            SectionsServiceMessage::Disable(section) if section.as_str() == "Grass"
         ));
         // Expect skipped Terrace section got disabled
         assert!(matches!(
             sections_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
-            SectionsServiceMessage::Disable(Section::Terrace)
+            SectionsServiceMessage::Disable(section) if section.as_str() == "Terrace"
         ));
+        // Closed section is logged in the history
+        verify_date_checked(&clock_rx);
 
         // Expect section alarm is disabled
         assert!(matches!(
@@ -501,60 +1674,68 @@ pub mod tests {
         ));
     }
 
-    pub fn can_skip_all_sections() {
+    #[test]
+    fn can_skip_all_sections() {
         let (clock_tx, rx) = channel();
         let (tx, clock_rx) = channel();
         ClockMock::start(rx, tx);
 
         let (sections_tx, sections_rx) = channel();
 
-        let mut watering = OnScheduleWatering::new(clock_tx, sections_tx);
+        let mut watering = OnScheduleWatering::new(
+            clock_tx,
+            sections_tx,
+            test_sections(),
+            WateringConfig::default(),
+            Box::new(MemoryStorage::default()),
+        );
 
         // Valid clean state
-        assert_eq!(watering.current_section, Section::None);
+        assert_eq!(watering.state.current_section, None);
 
         // Skip them all!
-        watering.section_durations = [
-            (Section::Vegs, SectionDuration::default()),
-            (Section::Flowers, SectionDuration::default()),
-            (Section::Grass, SectionDuration::default()),
-            (Section::Terrace, SectionDuration::default()),
-        ]
-        .into();
+        arm_default_program(
+            &mut watering,
+            [
+                (SectionId::from("Vegs"), SectionDuration::default()),
+                (SectionId::from("Flowers"), SectionDuration::default()),
+                (SectionId::from("Grass"), SectionDuration::default()),
+                (SectionId::from("Terrace"), SectionDuration::default()),
+            ]
+            .into(),
+        );
+        let mut watering: Box<dyn HandleMessage> = Box::new(watering);
 
         // Simulate interrupt from the clock - watering should start
-        watering.handle_msg(WateringServiceMessage::WateringAlarmFired);
+        watering = watering.handle_message(WateringServiceMessage::WateringAlarmFired);
+        verify_date_checked(&clock_rx);
 
         // Expect none of the sections triggered
         // Expect watering moved to next valid section - None
-        assert_eq!(watering.current_section, Section::None);
+        assert_eq!(watering.state().current_section, None);
 
-        assert!(matches!(
-            sections_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
-            SectionsServiceMessage::Disable(Section::None)
-        ));
         // Expect skipped vegs section got disabled
         assert!(matches!(
             sections_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
-            SectionsServiceMessage::Disable(Section::Vegs)
+            SectionsServiceMessage::Disable(section) if section.as_str() == "Vegs"
         ));
 
         // Expect skipped flowers section got disabled
         assert!(matches!(
             sections_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
-            SectionsServiceMessage::Disable(Section::Flowers)
+            SectionsServiceMessage::Disable(section) if section.as_str() == "Flowers"
         ));
 
         // Expect skipped grass section got disabled
         assert!(matches!(
             sections_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
-            SectionsServiceMessage::Disable(Section::Grass)
+            SectionsServiceMessage::Disable(section) if section.as_str() == "Grass"
         ));
 
         // Expect skipped terrace section got disabled
         assert!(matches!(
             sections_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
-            SectionsServiceMessage::Disable(Section::Terrace)
+            SectionsServiceMessage::Disable(section) if section.as_str() == "Terrace"
         ));
 
         // Expect section alarm is disabled
@@ -564,32 +1745,1546 @@ pub mod tests {
         ));
     }
 
-    fn verify_moved_to_next_section(
-        expected_current_section: Section,
-        next_section: Section,
-        expected_next_section: Section,
-        expected_duration: SectionDuration,
+    /// Makes given durations the default program, as if the watering alarm got armed for it
+    fn arm_default_program(
+        watering: &mut OnScheduleWatering,
+        section_durations: HashMap<SectionId, SectionDuration>,
+    ) {
+        watering.state.config.programs = [(
+            DEFAULT_PROGRAM.to_string(),
+            Program {
+                start_at: None,
+                section_durations,
+                ..Default::default()
+            },
+        )]
+        .into();
+        watering.state.next_program = Some(DEFAULT_PROGRAM.to_string());
+    }
+
+    /// Creates watering service in the initial state, with the clock mock attached
+    fn setup_watering() -> (
+        Box<dyn HandleMessage>,
+        Receiver<SectionsServiceMessage>,
+        Receiver<ClockServiceMessage>,
+    ) {
+        let (clock_tx, rx) = channel();
+        let (tx, clock_rx) = channel();
+        ClockMock::start(rx, tx);
+
+        let (sections_tx, sections_rx) = channel();
+
+        let mut watering = OnScheduleWatering::new(
+            clock_tx,
+            sections_tx,
+            test_sections(),
+            WateringConfig::default(),
+            Box::new(MemoryStorage::default()),
+        );
+
+        arm_default_program(
+            &mut watering,
+            [
+                (
+                    SectionId::from("Vegs"),
+                    TimeDelta::minutes(5).try_into().unwrap(),
+                ),
+                (SectionId::from("Flowers"), SectionDuration::default()),
+                (SectionId::from("Grass"), SectionDuration::default()),
+                (SectionId::from("Terrace"), SectionDuration::default()),
+            ]
+            .into(),
+        );
+
+        (Box::new(watering), sections_rx, clock_rx)
+    }
+
+    /// Enables given section out of schedule, verifies it got opened and the section alarm is armed
+    fn start_ad_hoc(
+        watering: Box<dyn HandleMessage>,
+        section: SectionId,
+        duration: SectionDuration,
         sections_rx: &Receiver<SectionsServiceMessage>,
         clock_rx: &Receiver<ClockServiceMessage>,
-    ) {
-        // Expect watering moved to valid section
-        assert_eq!(next_section, expected_next_section);
+    ) -> Box<dyn HandleMessage> {
+        let watering = watering.handle_message(WateringServiceMessage::EnableSectionFor(
+            section.clone(),
+            duration,
+            channel().0,
+        ));
+
+        assert_eq!(watering.state().current_section.as_ref(), Some(&section));
+
+        // Expect sanity close of all the valves
+        verify_all_sections_disabled(sections_rx);
+
+        // Expect requested section got enabled
+        assert!(matches!(
+            sections_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            SectionsServiceMessage::Enable(enabled) if enabled == section
+        ));
+        verify_date_checked(clock_rx);
+
+        // Expect section alarm is set
+        match clock_rx.recv_timeout(Duration::from_secs(1)).unwrap() {
+            ClockServiceMessage::SetSectionAlarmAfter(offset, _) => assert_eq!(offset, duration),
+            _ => panic!("Unexpected message"),
+        }
+
+        watering
+    }
+
+    /// Fires the section alarm armed last
+    fn fire_section_alarm(watering: Box<dyn HandleMessage>) -> Box<dyn HandleMessage> {
+        let id = watering.state().section_alarm_id;
+        watering.handle_message(WateringServiceMessage::SectionAlarmFired(id))
+    }
+
+    #[test]
+    fn ad_hoc_watering_ends_on_section_alarm() {
+        let (watering, sections_rx, clock_rx) = setup_watering();
+
+        let duration = TimeDelta::minutes(3).try_into().unwrap();
+        let watering = start_ad_hoc(
+            watering,
+            SectionId::from("Grass"),
+            duration,
+            &sections_rx,
+            &clock_rx,
+        );
+
+        // Simulate ad-hoc watering finished
+        let watering = fire_section_alarm(watering);
+
+        assert_eq!(watering.state().current_section, None);
 
-        // Expect current section got disabled
+        // Expect grass section got disabled
         assert!(matches!(
             sections_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
-            SectionsServiceMessage::Disable(current_section) if current_section == expected_current_section
+            SectionsServiceMessage::Disable(section) if section.as_str() == "Grass"
         ));
+        // Closed section is logged in the history
+        verify_date_checked(&clock_rx);
+
+        // Expect section alarm is disabled
+        assert!(matches!(
+            clock_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            ClockServiceMessage::DisableSectionAlarm
+        ));
+
+        // Back on schedule - watering alarm runs the daily schedule
+        verify_back_on_schedule(watering, &sections_rx, &clock_rx);
+    }
+
+    #[test]
+    fn ad_hoc_watering_ends_on_close_all_valves() {
+        let (watering, sections_rx, clock_rx) = setup_watering();
+
+        let duration = TimeDelta::minutes(3).try_into().unwrap();
+        let watering = start_ad_hoc(
+            watering,
+            SectionId::from("Terrace"),
+            duration,
+            &sections_rx,
+            &clock_rx,
+        );
+
+        let watering = watering.handle_message(WateringServiceMessage::CloseAllValves);
+
+        assert_eq!(watering.state().current_section, None);
+        verify_all_sections_disabled(&sections_rx);
+
+        // Expect section alarm is disabled, it has nothing to close anymore
+        assert!(matches!(
+            clock_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            ClockServiceMessage::DisableSectionAlarm
+        ));
+        // Closed section is logged in the history
+        verify_date_checked(&clock_rx);
+
+        verify_back_on_schedule(watering, &sections_rx, &clock_rx);
+    }
+
+    #[test]
+    fn ad_hoc_watering_can_switch_section() {
+        let (watering, sections_rx, clock_rx) = setup_watering();
+
+        let duration = TimeDelta::minutes(3).try_into().unwrap();
+        let watering = start_ad_hoc(
+            watering,
+            SectionId::from("Flowers"),
+            duration,
+            &sections_rx,
+            &clock_rx,
+        );
+
+        let new_duration = TimeDelta::minutes(7).try_into().unwrap();
+        let watering = watering.handle_message(WateringServiceMessage::EnableSectionFor(
+            SectionId::from("Grass"),
+            new_duration,
+            channel().0,
+        ));
+
+        assert_eq!(
+            watering.state().current_section,
+            Some(SectionId::from("Grass"))
+        );
+
+        // Expect flowers got disabled
+        assert!(matches!(
+            sections_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            SectionsServiceMessage::Disable(section) if section.as_str() == "Flowers"
+        ));
+
+        // Expect grass got enabled
+        assert!(matches!(
+            sections_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            SectionsServiceMessage::Enable(section) if section.as_str() == "Grass"
+        ));
+
+        // Flowers are logged in the history, and grass opening time is taken
+        verify_date_checked(&clock_rx);
+        verify_date_checked(&clock_rx);
+
+        // Expect section alarm is re-armed with the new duration
+        match clock_rx.recv_timeout(Duration::from_secs(1)).unwrap() {
+            ClockServiceMessage::SetSectionAlarmAfter(offset, _) => {
+                assert_eq!(offset, new_duration)
+            }
+            _ => panic!("Unexpected message"),
+        }
+
+        // Still ad-hoc, alarm closes grass and goes back on schedule
+        let watering = fire_section_alarm(watering);
+        assert_eq!(watering.state().current_section, None);
+        assert!(matches!(
+            sections_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            SectionsServiceMessage::Disable(section) if section.as_str() == "Grass"
+        ));
+        verify_date_checked(&clock_rx);
+        assert!(matches!(
+            clock_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            ClockServiceMessage::DisableSectionAlarm
+        ));
+
+        verify_back_on_schedule(watering, &sections_rx, &clock_rx);
+    }
+
+    #[test]
+    fn alarm_of_the_switched_section_is_ignored() {
+        let (watering, sections_rx, clock_rx) = setup_watering();
+
+        let duration = TimeDelta::minutes(3).try_into().unwrap();
+        let watering = start_ad_hoc(
+            watering,
+            SectionId::from("Flowers"),
+            duration,
+            &sections_rx,
+            &clock_rx,
+        );
+        let flowers_alarm = watering.state().section_alarm_id;
+
+        // Flowers alarm fires just as the user switches to grass
+        let watering = watering.handle_message(WateringServiceMessage::EnableSectionFor(
+            SectionId::from("Grass"),
+            TimeDelta::minutes(7).try_into().unwrap(),
+            channel().0,
+        ));
+        while sections_rx.recv_timeout(Duration::from_millis(100)).is_ok() {}
+        while clock_rx.recv_timeout(Duration::from_millis(100)).is_ok() {}
+
+        let watering =
+            watering.handle_message(WateringServiceMessage::SectionAlarmFired(flowers_alarm));
+        assert_eq!(
+            watering.state().current_section,
+            Some(SectionId::from("Grass"))
+        );
+        assert!(sections_rx
+            .recv_timeout(Duration::from_millis(100))
+            .is_err());
+        assert!(clock_rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        // Grass alarm ends it
+        let watering = fire_section_alarm(watering);
+        assert_eq!(watering.state().current_section, None);
+        assert!(matches!(
+            sections_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            SectionsServiceMessage::Disable(section) if section.as_str() == "Grass"
+        ));
+    }
+
+    #[test]
+    fn ad_hoc_watering_ends_on_zero_duration() {
+        let (watering, sections_rx, clock_rx) = setup_watering();
+
+        let duration = TimeDelta::minutes(3).try_into().unwrap();
+        let watering = start_ad_hoc(
+            watering,
+            SectionId::from("Vegs"),
+            duration,
+            &sections_rx,
+            &clock_rx,
+        );
+
+        let watering = watering.handle_message(WateringServiceMessage::EnableSectionFor(
+            SectionId::from("Vegs"),
+            SectionDuration::default(),
+            channel().0,
+        ));
+
+        assert_eq!(watering.state().current_section, None);
+        assert!(matches!(
+            sections_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            SectionsServiceMessage::Disable(section) if section.as_str() == "Vegs"
+        ));
+        verify_date_checked(&clock_rx);
+        assert!(matches!(
+            clock_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            ClockServiceMessage::DisableSectionAlarm
+        ));
+
+        verify_back_on_schedule(watering, &sections_rx, &clock_rx);
+    }
+
+    #[test]
+    fn ad_hoc_watering_is_interrupted_by_schedule() {
+        let (watering, sections_rx, clock_rx) = setup_watering();
+
+        let duration = TimeDelta::minutes(3).try_into().unwrap();
+        let watering = start_ad_hoc(
+            watering,
+            SectionId::from("Terrace"),
+            duration,
+            &sections_rx,
+            &clock_rx,
+        );
+
+        let watering = watering.handle_message(WateringServiceMessage::WateringAlarmFired);
+
+        // Expect terrace got closed, logged and section alarm disabled
+        assert!(matches!(
+            sections_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            SectionsServiceMessage::Disable(section) if section.as_str() == "Terrace"
+        ));
+        verify_date_checked(&clock_rx);
+        assert!(matches!(
+            clock_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            ClockServiceMessage::DisableSectionAlarm
+        ));
+        verify_date_checked(&clock_rx);
+
+        // Expect scheduled watering started with the vegs
+        verify_moved_to_next_section(
+            None,
+            &watering.state().current_section,
+            "Vegs",
+            TimeDelta::minutes(5).try_into().unwrap(),
+            &sections_rx,
+            &clock_rx,
+        );
+    }
+
+    #[test]
+    fn ad_hoc_watering_is_rejected_during_schedule() {
+        let (watering, sections_rx, clock_rx) = setup_watering();
+
+        let watering = watering.handle_message(WateringServiceMessage::WateringAlarmFired);
+        verify_date_checked(&clock_rx);
+        verify_moved_to_next_section(
+            None,
+            &watering.state().current_section,
+            "Vegs",
+            TimeDelta::minutes(5).try_into().unwrap(),
+            &sections_rx,
+            &clock_rx,
+        );
+
+        let (tx, rx) = channel();
+        let watering = watering.handle_message(WateringServiceMessage::EnableSectionFor(
+            SectionId::from("Grass"),
+            TimeDelta::minutes(3).try_into().unwrap(),
+            tx,
+        ));
+
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            Err(WateringError::SectionBusy(SectionId::from("Vegs")))
+        );
+        // Expect scheduled watering is not altered
+        assert_eq!(
+            watering.state().current_section,
+            Some(SectionId::from("Vegs"))
+        );
+        assert!(sections_rx
+            .recv_timeout(Duration::from_millis(100))
+            .is_err());
+        assert!(clock_rx.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn ad_hoc_watering_handles_configuration_and_status() {
+        let (watering, sections_rx, clock_rx) = setup_watering();
+
+        let duration = TimeDelta::minutes(3).try_into().unwrap();
+        let watering = start_ad_hoc(
+            watering,
+            SectionId::from("Grass"),
+            duration,
+            &sections_rx,
+            &clock_rx,
+        );
+
+        let grass_duration = TimeDelta::minutes(20).try_into().unwrap();
+        let watering = watering.handle_message(WateringServiceMessage::SetSectionDuration(
+            SectionId::from("Grass"),
+            grass_duration,
+            channel().0,
+        ));
+
+        let when = NaiveTime::from_hms_opt(6, 30, 0).unwrap();
+        let watering =
+            watering.handle_message(WateringServiceMessage::StartWateringAt(when, channel().0));
+        verify_watering_alarm_armed(&clock_rx, when);
+
+        let watering = watering.handle_message(WateringServiceMessage::DisableWatering);
+        assert!(matches!(
+            clock_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            ClockServiceMessage::DisableWateringAlarm
+        ));
+
+        let (tx, rx) = channel();
+        let watering = watering.handle_message(WateringServiceMessage::GetStatus(tx));
+        let status = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(
+            status.programs[DEFAULT_PROGRAM].section_durations[&SectionId::from("Grass")],
+            grass_duration
+        );
+        assert_eq!(status.mode, WateringMode::AdHoc);
+        assert_eq!(status.current_section, Some(SectionId::from("Grass")));
+        assert_eq!(
+            status.section_started_at,
+            "2015-09-05T23:56:04".parse::<NaiveDateTime>().ok()
+        );
+        assert_eq!(
+            status.section_ends_at,
+            "2015-09-05T23:59:04".parse::<NaiveDateTime>().ok()
+        );
+        assert_eq!(status.next_run, None);
+
+        // None of the above affected ad-hoc watering in progress
+        assert_eq!(
+            watering.state().current_section,
+            Some(SectionId::from("Grass"))
+        );
+        assert!(sections_rx
+            .recv_timeout(Duration::from_millis(100))
+            .is_err());
+    }
+
+    #[test]
+    fn configuration_is_stored() {
+        let (clock_tx, rx) = channel();
+        let (tx, clock_rx) = channel();
+        ClockMock::start(rx, tx);
+
+        let (sections_tx, _sections_rx) = channel();
+
+        let mut storage = MemoryStorage::default();
+        let watering: Box<dyn HandleMessage> = Box::new(OnScheduleWatering::new(
+            clock_tx,
+            sections_tx,
+            test_sections(),
+            WateringConfig::default(),
+            Box::new(storage.clone()),
+        ));
+
+        let grass_duration = TimeDelta::minutes(20).try_into().unwrap();
+        let when = NaiveTime::from_hms_opt(6, 30, 0).unwrap();
+        let watering = watering.handle_message(WateringServiceMessage::SetSectionDuration(
+            SectionId::from("Grass"),
+            grass_duration,
+            channel().0,
+        ));
+        let watering =
+            watering.handle_message(WateringServiceMessage::StartWateringAt(when, channel().0));
+
+        let config = WateringConfig::load(&mut storage).unwrap();
+        let program = &config.programs[DEFAULT_PROGRAM];
+        assert_eq!(program.start_at, Some(when));
+        assert!(config.enabled);
+        assert_eq!(program.duration(&SectionId::from("Grass")), grass_duration);
+        assert_eq!(
+            program.duration(&SectionId::from("Vegs")),
+            SectionDuration::default()
+        );
+
+        verify_watering_alarm_armed(&clock_rx, when);
+
+        let _watering = watering.handle_message(WateringServiceMessage::DisableWatering);
+
+        // Time is remembered, so re-enabling does not require setting it again
+        let config = WateringConfig::load(&mut storage).unwrap();
+        assert_eq!(config.programs[DEFAULT_PROGRAM].start_at, Some(when));
+        assert!(!config.enabled);
+    }
+
+    #[test]
+    fn watering_alarm_is_rearmed_from_stored_configuration() {
+        let when = NaiveTime::from_hms_opt(21, 15, 0).unwrap();
+
+        for enabled in [true, false] {
+            let (clock_tx, rx) = channel();
+            let (tx, clock_rx) = channel();
+            ClockMock::start(rx, tx);
+
+            let (sections_tx, _sections_rx) = channel();
+
+            let mut storage = MemoryStorage::default();
+            WateringConfig {
+                enabled,
+                paused_until: None,
+                programs: [(
+                    DEFAULT_PROGRAM.to_string(),
+                    Program {
+                        start_at: Some(when),
+                        ..Default::default()
+                    },
+                )]
+                .into(),
+            }
+            .store(&mut storage)
+            .unwrap();
+
+            let config = WateringConfig::load(&mut storage).unwrap();
+            let _watering_tx = OnScheduleWatering::new(
+                clock_tx,
+                sections_tx,
+                test_sections(),
+                config,
+                Box::new(storage),
+            )
+            .start();
+
+            assert!(matches!(
+                clock_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+                ClockServiceMessage::SubscribeForSectionAlarm(_)
+            ));
+            assert!(matches!(
+                clock_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+                ClockServiceMessage::SubscribeForWateringAlarm(_)
+            ));
+
+            if enabled {
+                verify_watering_alarm_armed(&clock_rx, when);
+            } else {
+                assert!(clock_rx.recv_timeout(Duration::from_millis(100)).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn next_program_is_the_first_one_after_given_time() {
+        let at = |hour| NaiveTime::from_hms_opt(hour, 0, 0).unwrap();
+        let config = WateringConfig {
+            enabled: true,
+            paused_until: None,
+            programs: [
+                ("morning", Some(at(6))),
+                ("evening", Some(at(20))),
+                ("unscheduled", None),
+            ]
+            .into_iter()
+            .map(|(name, start_at)| {
+                let program = Program {
+                    start_at,
+                    ..Default::default()
+                };
+                (name.to_string(), program)
+            })
+            .collect(),
+        };
+
+        assert_eq!(config.next_program(at(5)), Some(("morning", at(6))));
+        // Program that is starting right now is due the next day
+        assert_eq!(config.next_program(at(6)), Some(("evening", at(20))));
+        assert_eq!(config.next_program(at(12)), Some(("evening", at(20))));
+        assert_eq!(config.next_program(at(21)), Some(("morning", at(6))));
+
+        assert_eq!(WateringConfig::default().next_program(at(12)), None);
+    }
+
+    #[test]
+    fn programs_that_would_run_together_are_rejected() {
+        let at = |hour, minute| NaiveTime::from_hms_opt(hour, minute, 0).unwrap();
+        let program = |start_at, minutes| Program {
+            start_at: Some(start_at),
+            section_durations: [(
+                SectionId::from("Vegs"),
+                TimeDelta::minutes(minutes).try_into().unwrap(),
+            )]
+            .into(),
+            ..Default::default()
+        };
+        let (watering, _sections_rx, _clock_rx) = setup_watering();
+
+        let set_program = |watering: Box<dyn HandleMessage>, name: &str, program| {
+            let (tx, rx) = channel();
+            let watering = watering.handle_message(WateringServiceMessage::SetProgram(
+                name.to_string(),
+                program,
+                tx,
+            ));
+            (watering, rx.recv_timeout(Duration::from_secs(1)).unwrap())
+        };
+
+        let (watering, result) = set_program(watering, "evening", program(at(23, 50), 20));
+        assert_eq!(result, Ok(()));
+
+        let overlaps = Err(WateringError::ProgramOverlaps("evening".to_string()));
+        // Same start, even with nothing to water
+        let (watering, result) = set_program(watering, "twin", program(at(23, 50), 0));
+        assert_eq!(result, overlaps);
+        // Starts while the evening one runs, past midnight
+        let (watering, result) = set_program(watering, "night", program(at(0, 5), 5));
+        assert_eq!(result, overlaps);
+        // Runs when the evening one starts
+        let (watering, result) = set_program(watering, "late", program(at(23, 40), 15));
+        assert_eq!(result, overlaps);
+        let (watering, result) = set_program(watering, "night", program(at(0, 10), 5));
+        assert_eq!(result, Ok(()));
+        // Program does not overlap with its previous self
+        let (watering, result) = set_program(watering, "evening", program(at(23, 45), 25));
+        assert_eq!(result, Ok(()));
+
+        // Default program is checked as well
+        let (tx, rx) = channel();
+        let watering =
+            watering.handle_message(WateringServiceMessage::StartWateringAt(at(0, 12), tx));
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            Err(WateringError::ProgramOverlaps("night".to_string()))
+        );
+        assert!(!watering.state().config.programs.contains_key("twin"));
+        assert_eq!(
+            watering.state().config.programs[DEFAULT_PROGRAM].start_at,
+            None
+        );
+    }
+
+    #[test]
+    fn next_run_is_on_the_day_the_program_is_due() {
+        // 2024-05-01 is Wednesday
+        let datetime = |s: &str| s.parse::<NaiveDateTime>().unwrap();
+        let at = |hour| NaiveTime::from_hms_opt(hour, 0, 0).unwrap();
+        let config = WateringConfig {
+            enabled: true,
+            paused_until: None,
+            programs: [
+                ("morning", at(6), ScheduleRule::Weekdays(vec![Weekday::Sat])),
+                ("evening", at(20), ScheduleRule::Daily),
+            ]
+            .into_iter()
+            .map(|(name, start_at, rule)| {
+                let program = Program {
+                    start_at: Some(start_at),
+                    rule,
+                    ..Default::default()
+                };
+                (name.to_string(), program)
+            })
+            .collect(),
+        };
+
+        let run = |program: &str, at: &str| {
+            Some(ScheduledRun {
+                program: program.to_string(),
+                at: datetime(at),
+            })
+        };
+        assert_eq!(
+            config.next_run(datetime("2024-05-01T05:00:00")),
+            run("evening", "2024-05-01T20:00:00")
+        );
+        assert_eq!(
+            config.next_run(datetime("2024-05-03T20:00:00")),
+            run("morning", "2024-05-04T06:00:00")
+        );
+        assert_eq!(
+            WateringConfig::default().next_run(datetime("2024-05-01T05:00:00")),
+            None
+        );
+    }
+
+    #[test]
+    fn programs_water_own_sections_and_arm_the_next_one() {
+        let (clock_tx, rx) = channel();
+        let (tx, clock_rx) = channel();
+        let now =
+            NaiveDateTime::parse_from_str("2024-05-01 05:58:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        ClockMock::start_at(rx, tx, now);
+
+        let (sections_tx, sections_rx) = channel();
+
+        let morning = NaiveTime::from_hms_opt(6, 0, 0).unwrap();
+        let evening = NaiveTime::from_hms_opt(20, 0, 0).unwrap();
+        let vegs_duration = TimeDelta::minutes(5).try_into().unwrap();
+        let grass_duration = TimeDelta::minutes(10).try_into().unwrap();
+        let config = WateringConfig {
+            enabled: true,
+            paused_until: None,
+            programs: [
+                (
+                    "morning".to_string(),
+                    Program {
+                        start_at: Some(morning),
+                        section_durations: [(SectionId::from("Vegs"), vegs_duration)].into(),
+                        ..Default::default()
+                    },
+                ),
+                (
+                    "evening".to_string(),
+                    Program {
+                        start_at: Some(evening),
+                        section_durations: [(SectionId::from("Grass"), grass_duration)].into(),
+                        ..Default::default()
+                    },
+                ),
+            ]
+            .into(),
+        };
+
+        let mut watering = OnScheduleWatering::new(
+            clock_tx,
+            sections_tx,
+            test_sections(),
+            config,
+            Box::new(MemoryStorage::default()),
+        );
+        watering.state.arm_next_program();
+        verify_watering_alarm_armed(&clock_rx, morning);
+        assert_eq!(
+            watering.state.next_run,
+            Some(ScheduledRun {
+                program: "morning".to_string(),
+                at: now.date().and_time(morning),
+            })
+        );
+
+        let watering: Box<dyn HandleMessage> = Box::new(watering);
+        let watering = watering.handle_message(WateringServiceMessage::WateringAlarmFired);
+        verify_date_checked(&clock_rx);
+        assert_eq!(watering.state().current_program.as_deref(), Some("morning"));
+        assert_eq!(watering.state().next_run, None);
+        verify_moved_to_next_section(
+            None,
+            &watering.state().current_section,
+            "Vegs",
+            vegs_duration,
+            &sections_rx,
+            &clock_rx,
+        );
+
+        // Vegs is the only section of the morning program
+        let watering = fire_section_alarm(watering);
+        assert_eq!(watering.state().current_section, None);
+        assert_eq!(watering.state().current_program, None);
+        while let Ok(msg) = sections_rx.recv_timeout(Duration::from_millis(100)) {
+            assert!(matches!(msg, SectionsServiceMessage::Disable(_)));
+        }
+        verify_date_checked(&clock_rx);
+        assert!(matches!(
+            clock_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            ClockServiceMessage::DisableSectionAlarm
+        ));
+
+        // Watering alarm is re-armed for the evening
+        verify_watering_alarm_armed(&clock_rx, evening);
+        assert_eq!(watering.state().next_program.as_deref(), Some("evening"));
+
+        let watering = watering.handle_message(WateringServiceMessage::WateringAlarmFired);
+        verify_date_checked(&clock_rx);
+        assert_eq!(
+            watering.state().current_section,
+            Some(SectionId::from("Grass"))
+        );
+        let mut enabled = vec![];
+        while let Ok(msg) = sections_rx.recv_timeout(Duration::from_millis(100)) {
+            if let SectionsServiceMessage::Enable(section) = msg {
+                enabled.push(section);
+            }
+        }
+        assert_eq!(enabled, vec![SectionId::from("Grass")]);
+        verify_date_checked(&clock_rx);
+        match clock_rx.recv_timeout(Duration::from_secs(1)).unwrap() {
+            ClockServiceMessage::SetSectionAlarmAfter(offset, _) => {
+                assert_eq!(offset, grass_duration)
+            }
+            _ => panic!("Unexpected message"),
+        }
+    }
+
+    #[test]
+    fn program_that_is_not_due_is_skipped() {
+        let (clock_tx, rx) = channel();
+        let (tx, clock_rx) = channel();
+        // 2015-09-05 is Saturday
+        ClockMock::start(rx, tx);
+
+        let (sections_tx, sections_rx) = channel();
+
+        let when = NaiveTime::from_hms_opt(6, 0, 0).unwrap();
+        let config = WateringConfig {
+            enabled: true,
+            paused_until: None,
+            programs: [(
+                "weekdays".to_string(),
+                Program {
+                    start_at: Some(when),
+                    section_durations: [(
+                        SectionId::from("Vegs"),
+                        TimeDelta::minutes(5).try_into().unwrap(),
+                    )]
+                    .into(),
+                    rule: ScheduleRule::Weekdays(vec![chrono::Weekday::Mon]),
+                    ..Default::default()
+                },
+            )]
+            .into(),
+        };
+
+        let mut watering = OnScheduleWatering::new(
+            clock_tx,
+            sections_tx,
+            test_sections(),
+            config,
+            Box::new(MemoryStorage::default()),
+        );
+        watering.state.arm_next_program();
+        verify_watering_alarm_armed(&clock_rx, when);
+
+        let watering: Box<dyn HandleMessage> = Box::new(watering);
+        let watering = watering.handle_message(WateringServiceMessage::WateringAlarmFired);
+        verify_date_checked(&clock_rx);
+
+        // Nothing gets watered, program waits for the next day
+        assert_eq!(watering.state().current_program, None);
+        assert!(sections_rx
+            .recv_timeout(Duration::from_millis(100))
+            .is_err());
+        verify_watering_alarm_armed(&clock_rx, when);
+    }
+
+    #[test]
+    fn rain_delay_skips_runs_until_it_is_lifted() {
+        let (clock_tx, rx) = channel();
+        let (tx, clock_rx) = channel();
+        let now =
+            NaiveDateTime::parse_from_str("2024-05-01 05:58:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        ClockMock::start_at(rx, tx, now);
+
+        let (sections_tx, sections_rx) = channel();
+
+        let when = NaiveTime::from_hms_opt(6, 0, 0).unwrap();
+        let config = WateringConfig {
+            enabled: true,
+            paused_until: None,
+            programs: [(
+                DEFAULT_PROGRAM.to_string(),
+                Program {
+                    start_at: Some(when),
+                    section_durations: [(
+                        SectionId::from("Vegs"),
+                        TimeDelta::minutes(5).try_into().unwrap(),
+                    )]
+                    .into(),
+                    ..Default::default()
+                },
+            )]
+            .into(),
+        };
+        let mut storage = MemoryStorage::default();
+        let mut watering = OnScheduleWatering::new(
+            clock_tx,
+            sections_tx,
+            test_sections(),
+            config,
+            Box::new(storage.clone()),
+        );
+        watering.state.arm_next_program();
+        verify_watering_alarm_armed(&clock_rx, when);
+
+        let watering: Box<dyn HandleMessage> = Box::new(watering);
+        let watering =
+            watering.handle_message(WateringServiceMessage::SetRainDelay(TimeDelta::days(1)));
+        verify_date_checked(&clock_rx);
+        // Schedule stays armed, next run is past the delay
+        verify_watering_alarm_armed(&clock_rx, when);
+        let paused_until = now + TimeDelta::days(1);
+        assert_eq!(
+            WateringConfig::load(&mut storage).unwrap().paused_until,
+            Some(paused_until)
+        );
+        assert_eq!(
+            watering.state().next_run.as_ref().map(|run| run.at),
+            Some(paused_until.date().and_time(when))
+        );
+
+        let watering = watering.handle_message(WateringServiceMessage::WateringAlarmFired);
+        verify_date_checked(&clock_rx);
+        assert_eq!(watering.state().current_program, None);
+        assert!(sections_rx
+            .recv_timeout(Duration::from_millis(100))
+            .is_err());
+        verify_watering_alarm_armed(&clock_rx, when);
+        assert_eq!(
+            watering.state().history.query(&HistoryQuery::default()),
+            vec![HistoryEntry {
+                at: now,
+                event: HistoryEvent::RunSkipped {
+                    program: DEFAULT_PROGRAM.to_string(),
+                    reason: SkipReason::RainDelay,
+                },
+            }]
+        );
+
+        let watering =
+            watering.handle_message(WateringServiceMessage::SetRainDelay(TimeDelta::zero()));
+        verify_watering_alarm_armed(&clock_rx, when);
+        assert_eq!(
+            WateringConfig::load(&mut storage).unwrap().paused_until,
+            None
+        );
+
+        let watering = watering.handle_message(WateringServiceMessage::WateringAlarmFired);
+        assert_eq!(
+            watering.state().current_program.as_deref(),
+            Some(DEFAULT_PROGRAM)
+        );
+    }
+
+    /// Morning run gets skipped for the reason `skip` sets up, the board reboots half an hour later
+    /// with the run now policy. Returns whether the reboot catches up on the run.
+    fn skipped_run_is_recovered(skip: impl FnOnce(&mut OnScheduleWatering)) -> bool {
+        let (clock_tx, rx) = channel();
+        let (tx, _clock_rx) = channel();
+        let due_at =
+            NaiveDateTime::parse_from_str("2024-05-01 06:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        ClockMock::start_at(rx, tx, due_at);
+
+        let (sections_tx, sections_rx) = channel();
+
+        let config = WateringConfig {
+            enabled: true,
+            paused_until: None,
+            programs: [(
+                DEFAULT_PROGRAM.to_string(),
+                Program {
+                    start_at: Some(due_at.time()),
+                    section_durations: [(
+                        SectionId::from("Vegs"),
+                        TimeDelta::minutes(5).try_into().unwrap(),
+                    )]
+                    .into(),
+                    ..Default::default()
+                },
+            )]
+            .into(),
+        };
+
+        // Watered the day before
+        let mut storage = MemoryStorage::default();
+        LastRun {
+            program: DEFAULT_PROGRAM.to_string(),
+            started_at: due_at - TimeDelta::days(1),
+            completed: vec![SectionId::from("Vegs")],
+            finished: true,
+        }
+        .store(&mut storage)
+        .unwrap();
+
+        let mut watering = OnScheduleWatering::new(
+            clock_tx,
+            sections_tx,
+            test_sections(),
+            config.clone(),
+            Box::new(storage.clone()),
+        );
+        watering.state.arm_next_program();
+        skip(&mut watering);
+        let watering: Box<dyn HandleMessage> = Box::new(watering);
+        let watering = watering.handle_message(WateringServiceMessage::WateringAlarmFired);
+        assert_eq!(watering.state().current_program, None);
+        assert!(watering.state().last_skipped.is_some());
+        assert!(sections_rx
+            .recv_timeout(Duration::from_millis(100))
+            .is_err());
+
+        let (clock_tx, rx) = channel();
+        let (tx, _clock_rx) = channel();
+        ClockMock::start_at(rx, tx, due_at + TimeDelta::minutes(30));
+        let (sections_tx, _sections_rx) = channel();
+
+        let mut watering = OnScheduleWatering::new(
+            clock_tx,
+            sections_tx,
+            test_sections(),
+            config,
+            Box::new(storage),
+        )
+        .with_recovery_policy(RecoveryPolicy::RunNow);
+        watering.state.recover();
+        watering.state.current_program.is_some()
+    }
+
+    #[test]
+    fn run_skipped_for_the_rain_delay_is_not_recovered() {
+        let recovered = skipped_run_is_recovered(|watering| {
+            let due_at = watering.state.now().unwrap();
+            watering.state.config.paused_until = Some(due_at + TimeDelta::minutes(10));
+        });
+        assert!(!recovered);
+    }
+
+    #[test]
+    fn run_skipped_for_the_rain_is_not_recovered() {
+        // Dry on reboot, there is no rain sensor then
+        let recovered = skipped_run_is_recovered(|watering| watering.state.raining = Some(true));
+        assert!(!recovered);
+    }
+
+    #[test]
+    fn interrupted_program_resumes_from_the_section_it_did_not_finish() {
+        let (clock_tx, rx) = channel();
+        let (tx, clock_rx) = channel();
+        let started_at =
+            NaiveDateTime::parse_from_str("2024-05-01 06:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        ClockMock::start_at(rx, tx, started_at + TimeDelta::minutes(10));
+
+        let (sections_tx, sections_rx) = channel();
+
+        let when = started_at.time();
+        let flowers_duration = TimeDelta::minutes(10).try_into().unwrap();
+        let config = WateringConfig {
+            enabled: true,
+            paused_until: None,
+            programs: [(
+                "morning".to_string(),
+                Program {
+                    start_at: Some(when),
+                    section_durations: [
+                        (
+                            SectionId::from("Vegs"),
+                            TimeDelta::minutes(5).try_into().unwrap(),
+                        ),
+                        (SectionId::from("Flowers"), flowers_duration),
+                    ]
+                    .into(),
+                    ..Default::default()
+                },
+            )]
+            .into(),
+        };
+
+        // Power got cut while Flowers were watered
+        let mut storage = MemoryStorage::default();
+        LastRun {
+            program: "morning".to_string(),
+            started_at,
+            completed: vec![SectionId::from("Vegs")],
+            finished: false,
+        }
+        .store(&mut storage)
+        .unwrap();
+
+        let mut watering = OnScheduleWatering::new(
+            clock_tx,
+            sections_tx,
+            test_sections(),
+            config,
+            Box::new(storage.clone()),
+        )
+        .with_recovery_policy(RecoveryPolicy::RunRemaining);
+        watering.state.recover();
+        verify_date_checked(&clock_rx);
+
+        // Vegs are done already, they are skipped
+        assert!(matches!(
+            sections_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            SectionsServiceMessage::Disable(section) if section.as_str() == "Vegs"
+        ));
+        verify_moved_to_next_section(
+            None,
+            &watering.state.current_section,
+            "Flowers",
+            flowers_duration,
+            &sections_rx,
+            &clock_rx,
+        );
+        assert_eq!(watering.state.current_program.as_deref(), Some("morning"));
+
+        // Watering alarm is armed once the run is complete
+        let watering: Box<dyn HandleMessage> = Box::new(watering);
+        let _watering = fire_section_alarm(watering);
+        verify_date_checked(&clock_rx);
+        assert!(matches!(
+            clock_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            ClockServiceMessage::DisableSectionAlarm
+        ));
+        verify_watering_alarm_armed(&clock_rx, when);
+
+        let last_run = LastRun::load(&mut storage).unwrap().unwrap();
+        assert_eq!(last_run.started_at, started_at);
+        assert!(last_run.finished);
+    }
+
+    #[test]
+    fn watchdog_follows_section_alarm() {
+        let (watering, sections_rx, clock_rx) = setup_watering();
+        assert!(watering.state().watchdog_deadline.is_none());
+
+        let duration = TimeDelta::minutes(3).try_into().unwrap();
+        let before = Instant::now();
+        let watering = start_ad_hoc(
+            watering,
+            SectionId::from("Grass"),
+            duration,
+            &sections_rx,
+            &clock_rx,
+        );
+
+        let deadline = watering.state().watchdog_deadline.unwrap();
+        let timeout = Duration::from_secs(3 * 60) + WATCHDOG_GRACE;
+        assert!(deadline >= before + timeout && deadline <= Instant::now() + timeout);
+
+        let watering = fire_section_alarm(watering);
+        assert!(watering.state().watchdog_deadline.is_none());
+    }
+
+    #[test]
+    fn watchdog_aborts_scheduled_watering() {
+        let (watering, sections_rx, clock_rx) = setup_watering();
+
+        let watering = watering.handle_message(WateringServiceMessage::WateringAlarmFired);
+        verify_date_checked(&clock_rx);
+        verify_moved_to_next_section(
+            None,
+            &watering.state().current_section,
+            "Vegs",
+            TimeDelta::minutes(5).try_into().unwrap(),
+            &sections_rx,
+            &clock_rx,
+        );
+
+        let watering = watering.handle_message(WateringServiceMessage::WatchdogExpired);
+
+        assert_eq!(watering.state().current_section, None);
+        assert_eq!(watering.state().current_program, None);
+        assert!(watering.state().watchdog_deadline.is_none());
+        verify_date_checked(&clock_rx);
+        verify_all_sections_disabled(&sections_rx);
+        assert!(matches!(
+            clock_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            ClockServiceMessage::DisableSectionAlarm
+        ));
+
+        let faults = &watering.state().faults;
+        assert_eq!(faults.len(), 1);
+        assert_eq!(faults[0].section, SectionId::from("Vegs"));
+        assert!(faults[0].at.is_some());
+    }
+
+    #[test]
+    fn close_all_valves_ends_the_program() {
+        let (watering, sections_rx, clock_rx) = setup_watering();
+
+        let watering = watering.handle_message(WateringServiceMessage::WateringAlarmFired);
+        verify_date_checked(&clock_rx);
+        verify_moved_to_next_section(
+            None,
+            &watering.state().current_section,
+            "Vegs",
+            TimeDelta::minutes(5).try_into().unwrap(),
+            &sections_rx,
+            &clock_rx,
+        );
+
+        let watering = watering.handle_message(WateringServiceMessage::CloseAllValves);
+        assert_eq!(watering.state().current_section, None);
+        assert_eq!(watering.state().current_program, None);
+        assert!(watering.state().watchdog_deadline.is_none());
+        verify_all_sections_disabled(&sections_rx);
+        assert!(matches!(
+            clock_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            ClockServiceMessage::DisableSectionAlarm
+        ));
+        // Closed section is logged in the history, once
+        verify_date_checked(&clock_rx);
+        let history = watering.state().history.query(&HistoryQuery::default());
+        assert!(matches!(
+            &history[..],
+            [HistoryEntry {
+                event: HistoryEvent::SectionWatered {
+                    program: Some(program),
+                    ended_by: EndReason::Manual,
+                    ..
+                },
+                ..
+            }] if program == DEFAULT_PROGRAM
+        ));
+
+        let (tx, rx) = channel();
+        let watering = watering.handle_message(WateringServiceMessage::GetStatus(tx));
+        let status = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(status.mode, WateringMode::Idle);
+        assert_eq!(status.current_section, None);
+
+        // Section alarm that fired meanwhile does not resume the program
+        let watering = fire_section_alarm(watering);
+        assert_eq!(watering.state().current_section, None);
+        assert!(sections_rx
+            .recv_timeout(Duration::from_millis(100))
+            .is_err());
+    }
+
+    #[test]
+    fn late_section_alarm_is_ignored() {
+        let (watering, sections_rx, clock_rx) = setup_watering();
+
+        // Section closed by the watchdog
+        let watering = watering.handle_message(WateringServiceMessage::WateringAlarmFired);
+        let watering = watering.handle_message(WateringServiceMessage::WatchdogExpired);
+        while sections_rx.recv_timeout(Duration::from_millis(100)).is_ok() {}
+        while clock_rx.recv_timeout(Duration::from_millis(100)).is_ok() {}
+
+        let watering = fire_section_alarm(watering);
+        assert_eq!(watering.state().current_section, None);
+        assert_eq!(watering.state().current_program, None);
+        assert!(sections_rx
+            .recv_timeout(Duration::from_millis(100))
+            .is_err());
+
+        // Ad-hoc watering closed by the user
+        let watering = start_ad_hoc(
+            watering,
+            SectionId::from("Grass"),
+            TimeDelta::minutes(3).try_into().unwrap(),
+            &sections_rx,
+            &clock_rx,
+        );
+        let watering = watering.handle_message(WateringServiceMessage::CloseAllValves);
+        while sections_rx.recv_timeout(Duration::from_millis(100)).is_ok() {}
+        while clock_rx.recv_timeout(Duration::from_millis(100)).is_ok() {}
+
+        let watering = fire_section_alarm(watering);
+        assert_eq!(watering.state().current_section, None);
+        assert!(sections_rx
+            .recv_timeout(Duration::from_millis(100))
+            .is_err());
+
+        // Still on schedule
+        verify_back_on_schedule(watering, &sections_rx, &clock_rx);
+    }
+
+    #[test]
+    fn watering_alarm_during_the_run_is_ignored() {
+        let (watering, sections_rx, clock_rx) = setup_watering();
+
+        let watering = watering.handle_message(WateringServiceMessage::WateringAlarmFired);
+        verify_date_checked(&clock_rx);
+        verify_moved_to_next_section(
+            None,
+            &watering.state().current_section,
+            "Vegs",
+            TimeDelta::minutes(5).try_into().unwrap(),
+            &sections_rx,
+            &clock_rx,
+        );
+
+        let watering = watering.handle_message(WateringServiceMessage::WateringAlarmFired);
+        assert_eq!(
+            watering.state().current_section,
+            Some(SectionId::from("Vegs"))
+        );
+        assert!(sections_rx
+            .recv_timeout(Duration::from_millis(100))
+            .is_err());
+        assert!(clock_rx.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn wet_rain_sensor_aborts_the_run_and_skips_the_next_ones() {
+        let (watering, sections_rx, clock_rx) = setup_watering();
+
+        let watering = watering.handle_message(WateringServiceMessage::WateringAlarmFired);
+        verify_date_checked(&clock_rx);
+        verify_moved_to_next_section(
+            None,
+            &watering.state().current_section,
+            "Vegs",
+            TimeDelta::minutes(5).try_into().unwrap(),
+            &sections_rx,
+            &clock_rx,
+        );
+
+        let watering = watering.handle_message(WateringServiceMessage::RainChanged(true));
+        assert_eq!(watering.state().raining, Some(true));
+        assert_eq!(watering.state().current_section, None);
+        assert_eq!(watering.state().current_program, None);
+        verify_all_sections_disabled(&sections_rx);
+        verify_date_checked(&clock_rx);
+        assert!(matches!(
+            clock_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            ClockServiceMessage::DisableSectionAlarm
+        ));
+        let history = watering.state().history.query(&HistoryQuery::default());
+        assert!(matches!(
+            &history[..],
+            [HistoryEntry {
+                event: HistoryEvent::SectionWatered {
+                    ended_by: EndReason::Rain,
+                    ..
+                },
+                ..
+            }]
+        ));
+
+        // Nothing is watered while it is wet
+        let watering = watering.handle_message(WateringServiceMessage::WateringAlarmFired);
+        assert_eq!(watering.state().current_program, None);
+        assert!(sections_rx
+            .recv_timeout(Duration::from_millis(100))
+            .is_err());
+        assert!(matches!(
+            watering.state().last_skipped,
+            Some(SkippedRun {
+                reason: SkipReason::Rain,
+                ..
+            })
+        ));
+
+        let watering = watering.handle_message(WateringServiceMessage::RainChanged(false));
+        let watering = watering.handle_message(WateringServiceMessage::WateringAlarmFired);
+        assert_eq!(
+            watering.state().current_section,
+            Some(SectionId::from("Vegs"))
+        );
+    }
+
+    #[test]
+    fn section_with_moist_soil_is_skipped() {
+        let (clock_tx, rx) = channel();
+        let (tx, _clock_rx) = channel();
+        ClockMock::start(rx, tx);
+
+        let (sections_tx, sections_rx) = channel();
+
+        let probe = |gpio| MoistureProbeConfig {
+            gpio,
+            dry_mv: 2600,
+            wet_mv: 1100,
+            skip_above: 40,
+        };
+        let vegs_probe = FakeMoistureProbe::new(1850);
+        let moisture_tx = MoistureService::new(vec![
+            ("Vegs".into(), probe(36), vegs_probe.clone()),
+            ("Grass".into(), probe(39), FakeMoistureProbe::new(2500)),
+        ])
+        .start();
+
+        let mut watering = OnScheduleWatering::new(
+            clock_tx,
+            sections_tx,
+            test_sections(),
+            WateringConfig::default(),
+            Box::new(MemoryStorage::default()),
+        )
+        .with_moisture_sensors(moisture_tx);
+        let duration: SectionDuration = TimeDelta::minutes(5).try_into().unwrap();
+        arm_default_program(
+            &mut watering,
+            [
+                (SectionId::from("Vegs"), duration),
+                (SectionId::from("Grass"), duration),
+            ]
+            .into(),
+        );
+        let watering: Box<dyn HandleMessage> = Box::new(watering);
+
+        // Vegs is moist enough, Grass is watered
+        let watering = watering.handle_message(WateringServiceMessage::WateringAlarmFired);
+        assert_eq!(
+            watering.state().current_section,
+            Some(SectionId::from("Grass"))
+        );
+        let opened = sections_rx
+            .try_iter()
+            .filter_map(|msg| match msg {
+                SectionsServiceMessage::Enable(section) => Some(section),
+                SectionsServiceMessage::Disable(_) => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(opened, [SectionId::from("Grass")]);
+
+        let history = watering.state().history.query(&HistoryQuery::default());
+        assert_eq!(
+            history
+                .iter()
+                .map(|entry| entry.event.clone())
+                .collect::<Vec<_>>(),
+            [HistoryEvent::SectionSkipped {
+                section: "Vegs".into(),
+                program: DEFAULT_PROGRAM.to_string(),
+                moisture: 50,
+            }]
+        );
+
+        let (tx, rx) = channel();
+        let watering = watering.handle_message(WateringServiceMessage::GetStatus(tx));
+        let status = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(status.soil_moisture.len(), 2);
+        assert_eq!(status.soil_moisture[&SectionId::from("Grass")].moisture, 6);
+
+        // Dried out by the next run
+        vegs_probe.set_mv(2500);
+        let watering = fire_section_alarm(watering);
+        assert_eq!(watering.state().current_section, None);
+        let watering = watering.handle_message(WateringServiceMessage::WateringAlarmFired);
+        assert_eq!(
+            watering.state().current_section,
+            Some(SectionId::from("Vegs"))
+        );
+    }
+
+    #[test]
+    fn watchdog_closes_section_when_section_alarm_is_missed() {
+        let (clock_tx, rx) = channel();
+        let (tx, clock_rx) = channel();
+        ClockMock::start(rx, tx);
+
+        let (sections_tx, sections_rx) = channel();
+
+        let mut watering = OnScheduleWatering::new(
+            clock_tx,
+            sections_tx,
+            test_sections(),
+            WateringConfig::default(),
+            Box::new(MemoryStorage::default()),
+        );
+        watering.state.watchdog_grace = Duration::from_millis(100);
+        let watering_tx = watering.start();
+
+        watering_tx
+            .send(WateringServiceMessage::EnableSectionFor(
+                SectionId::from("Flowers"),
+                TimeDelta::seconds(1).try_into().unwrap(),
+                channel().0,
+            ))
+            .unwrap();
+
+        verify_all_sections_disabled(&sections_rx);
+        assert!(matches!(
+            sections_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            SectionsServiceMessage::Enable(section) if section.as_str() == "Flowers"
+        ));
+
+        // Section alarm never comes, watchdog closes everything once the grace period is over
+        assert!(sections_rx
+            .recv_timeout(Duration::from_millis(500))
+            .is_err());
+        verify_all_sections_disabled(&sections_rx);
+
+        let (tx, rx) = channel();
+        watering_tx
+            .send(WateringServiceMessage::GetStatus(tx))
+            .unwrap();
+        let status = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(status.faults.len(), 1);
+        assert_eq!(status.faults[0].section, SectionId::from("Flowers"));
+
+        // Section alarm is disabled, it has nothing to close anymore
+        assert!(clock_rx
+            .iter()
+            .any(|msg| matches!(msg, ClockServiceMessage::DisableSectionAlarm)));
+    }
+
+    /// Verifies that the service asked for the current time, and armed the watering alarm
+    fn verify_watering_alarm_armed(clock_rx: &Receiver<ClockServiceMessage>, when: NaiveTime) {
+        verify_date_checked(clock_rx);
+        assert!(matches!(
+            clock_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            ClockServiceMessage::SetWateringAlarmAt(at) if at == when
+        ));
+    }
+
+    /// Verifies that the service asked for the current time, to tell what is due
+    fn verify_date_checked(clock_rx: &Receiver<ClockServiceMessage>) {
+        assert!(matches!(
+            clock_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            ClockServiceMessage::GetDateTime(_)
+        ));
+    }
+
+    /// Verifies that service is in the schedule mode - watering alarm starts the watering from the first section
+    fn verify_back_on_schedule(
+        watering: Box<dyn HandleMessage>,
+        sections_rx: &Receiver<SectionsServiceMessage>,
+        clock_rx: &Receiver<ClockServiceMessage>,
+    ) {
+        let watering = watering.handle_message(WateringServiceMessage::WateringAlarmFired);
+        verify_date_checked(clock_rx);
+
+        verify_moved_to_next_section(
+            None,
+            &watering.state().current_section,
+            "Vegs",
+            TimeDelta::minutes(5).try_into().unwrap(),
+            sections_rx,
+            clock_rx,
+        );
+    }
+
+    fn verify_all_sections_disabled(sections_rx: &Receiver<SectionsServiceMessage>) {
+        for section in test_sections() {
+            assert!(matches!(
+                sections_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+                SectionsServiceMessage::Disable(disabled) if disabled == section
+            ));
+        }
+    }
+
+    fn verify_moved_to_next_section(
+        expected_current_section: Option<&str>,
+        next_section: &Option<SectionId>,
+        expected_next_section: &str,
+        expected_duration: SectionDuration,
+        sections_rx: &Receiver<SectionsServiceMessage>,
+        clock_rx: &Receiver<ClockServiceMessage>,
+    ) {
+        // Expect watering moved to valid section
+        assert_eq!(
+            next_section.as_ref().map(SectionId::as_str),
+            Some(expected_next_section)
+        );
+
+        // Expect current section got disabled, and logged in the history
+        if let Some(expected_current_section) = expected_current_section {
+            assert!(matches!(
+                sections_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+                SectionsServiceMessage::Disable(current_section) if current_section.as_str() == expected_current_section
+            ));
+            verify_date_checked(clock_rx);
+        }
 
-        // Expect next section got enabled
+        // Expect next section got enabled, at the time asked for the history
         assert!(matches!(
             sections_rx.recv_timeout(Duration::from_secs(1)).unwrap(),
-            SectionsServiceMessage::Enable(next_section) if next_section == expected_next_section
+            SectionsServiceMessage::Enable(next_section) if next_section.as_str() == expected_next_section
         ));
+        verify_date_checked(clock_rx);
 
         // Expect section alarm is set
         match clock_rx.recv_timeout(Duration::from_secs(1)).unwrap() {
-            ClockServiceMessage::SetSectionAlarmAfter(offset) => {
+            ClockServiceMessage::SetSectionAlarmAfter(offset, _) => {
                 assert_eq!(expected_duration, offset)
             }
             _ => panic!("Unexpected message"),
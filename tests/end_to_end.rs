@@ -0,0 +1,838 @@
+//! Whole watering flow on the host: Sections, ClockService and OnScheduleWatering services
+//! wired together the same way as on the board, with in-memory hardware.
+
+use std::num::NonZeroU32;
+use std::sync::mpsc::channel;
+use std::time::Duration;
+
+use chrono::{NaiveDate, NaiveTime, TimeDelta, Weekday};
+use water_my_garden_rs::{
+    clock::{ClockService, ClockServiceChannel},
+    events::{self, Event, EventsService, EventsServiceChannel},
+    history::{EndReason, HistoryEvent, HistoryQuery, SkipReason},
+    host::{
+        wait_until, FakeBroker, FakeMoistureProbe, FakeRainSensor, FakeRtc, FakeValve,
+        MemoryStorage,
+    },
+    moisture::MoistureService,
+    mqtt::MqttService,
+    rain::RainService,
+    recovery::RecoveryPolicy,
+    schedule::ScheduleRule,
+    sections::{MoistureProbeConfig, SectionId, Sections, SectionsConfig},
+    watering::{
+        OnScheduleWatering, Program, WateringConfig, WateringError, WateringServiceChannel,
+        WateringServiceMessage,
+    },
+};
+
+struct Garden {
+    rtc: FakeRtc,
+    rain: FakeRainSensor,
+    /// Vegs and Grass have the probes, dry at first
+    probes: Vec<(SectionId, FakeMoistureProbe)>,
+    /// In the watering order
+    valves: Vec<(SectionId, FakeValve)>,
+    watering_tx: WateringServiceChannel,
+    clock_tx: ClockServiceChannel,
+    events_tx: EventsServiceChannel,
+}
+
+impl Garden {
+    fn start() -> Self {
+        let now = NaiveDate::from_ymd_opt(2024, 5, 1)
+            .unwrap()
+            .and_hms_opt(20, 0, 0)
+            .unwrap();
+        Self::boot(
+            FakeRtc::new(now),
+            MemoryStorage::default(),
+            RecoveryPolicy::Skip,
+        )
+    }
+
+    /// Starts all the services, the same way the board does on power up
+    fn boot(rtc: FakeRtc, mut storage: MemoryStorage, recovery_policy: RecoveryPolicy) -> Self {
+        let valves = ["Vegs", "Flowers", "Grass", "Terrace"]
+            .map(|section| (SectionId::from(section), FakeValve::default()))
+            .to_vec();
+        let sections = valves.iter().map(|(section, _)| section.clone()).collect();
+
+        let events_tx = EventsService::new().start();
+        let sections_tx = Sections::new(valves.clone())
+            .unwrap()
+            .with_events(events_tx.clone())
+            .start();
+        let clock_tx = ClockService::new(rtc.clone())
+            .unwrap()
+            .with_events(events_tx.clone())
+            .start();
+        let rain = FakeRainSensor::new();
+        let rain_tx = RainService::new(rain.clone())
+            .unwrap()
+            .with_debounce(Duration::ZERO)
+            .with_events(events_tx.clone())
+            .start();
+        let probes = ["Vegs", "Grass"]
+            .map(|section| (SectionId::from(section), FakeMoistureProbe::new(2600)))
+            .to_vec();
+        let moisture_tx = MoistureService::new(
+            probes
+                .iter()
+                .zip([36, 39])
+                .map(|((section, probe), gpio)| {
+                    let config = MoistureProbeConfig {
+                        gpio,
+                        dry_mv: 2600,
+                        wet_mv: 1100,
+                        skip_above: 60,
+                    };
+                    (section.clone(), config, probe.clone())
+                })
+                .collect(),
+        )
+        .start();
+        let config = WateringConfig::load(&mut storage).unwrap();
+        let watering_tx = OnScheduleWatering::new(
+            clock_tx.clone(),
+            sections_tx,
+            sections,
+            config,
+            Box::new(storage),
+        )
+        .with_recovery_policy(recovery_policy)
+        .with_events(events_tx.clone())
+        .with_rain_sensor(rain_tx)
+        .with_moisture_sensors(moisture_tx)
+        .start();
+
+        Self {
+            rtc,
+            rain,
+            probes,
+            valves,
+            watering_tx,
+            clock_tx,
+            events_tx,
+        }
+    }
+
+    fn open_valves(&self) -> Vec<&str> {
+        self.valves
+            .iter()
+            .filter(|(_, valve)| valve.is_open())
+            .map(|(section, _)| section.as_str())
+            .collect()
+    }
+
+    /// Waits until only the `section` valve is open and the section alarm is armed for it
+    fn wait_for_section(&self, section: &str, alarm: NaiveTime) {
+        assert!(
+            wait_until(Duration::from_secs(2), || {
+                self.open_valves() == vec![section] && self.rtc.alarm1() == Some(alarm)
+            }),
+            "expected {section} to be watered until {alarm}, open valves {:?}",
+            self.open_valves()
+        );
+    }
+}
+
+#[test]
+fn scheduled_watering_goes_through_all_enabled_sections() {
+    let garden = Garden::start();
+
+    for (section, minutes) in [
+        (SectionId::from("Vegs"), 5),
+        (SectionId::from("Grass"), 20),
+        (SectionId::from("Terrace"), 8),
+    ] {
+        garden
+            .watering_tx
+            .send(WateringServiceMessage::SetSectionDuration(
+                section,
+                TimeDelta::minutes(minutes).try_into().unwrap(),
+                channel().0,
+            ))
+            .unwrap();
+    }
+
+    let start = NaiveTime::from_hms_opt(20, 30, 0).unwrap();
+    garden
+        .watering_tx
+        .send(WateringServiceMessage::StartWateringAt(start, channel().0))
+        .unwrap();
+    assert!(wait_until(Duration::from_secs(1), || garden.rtc.alarm2()
+        == Some(start)));
+
+    // Nothing happens until the watering time
+    garden.rtc.advance(TimeDelta::minutes(29));
+    assert!(garden.open_valves().is_empty());
+
+    garden.rtc.advance(TimeDelta::minutes(1));
+    garden.wait_for_section("Vegs", NaiveTime::from_hms_opt(20, 35, 0).unwrap());
+
+    // Flowers are skipped, duration is not set
+    garden.rtc.advance(TimeDelta::minutes(5));
+    garden.wait_for_section("Grass", NaiveTime::from_hms_opt(20, 55, 0).unwrap());
+
+    garden.rtc.advance(TimeDelta::minutes(20));
+    garden.wait_for_section("Terrace", NaiveTime::from_hms_opt(21, 3, 0).unwrap());
+
+    garden.rtc.advance(TimeDelta::minutes(8));
+    assert!(wait_until(Duration::from_secs(1), || garden
+        .open_valves()
+        .is_empty()
+        && garden.rtc.alarm1().is_none()));
+
+    // Schedule stays armed for the next day
+    assert_eq!(garden.rtc.alarm2(), Some(start));
+}
+
+#[test]
+fn ad_hoc_watering_closes_section_after_given_duration() {
+    let garden = Garden::start();
+
+    let (tx, rx) = channel();
+    garden
+        .watering_tx
+        .send(WateringServiceMessage::EnableSectionFor(
+            SectionId::from("Flowers"),
+            TimeDelta::minutes(3).try_into().unwrap(),
+            tx,
+        ))
+        .unwrap();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(1)).unwrap(), Ok(()));
+    garden.wait_for_section("Flowers", NaiveTime::from_hms_opt(20, 3, 0).unwrap());
+
+    garden.rtc.advance(TimeDelta::minutes(3));
+    assert!(wait_until(Duration::from_secs(1), || garden
+        .open_valves()
+        .is_empty()
+        && garden.rtc.alarm1().is_none()));
+}
+
+#[test]
+fn events_tell_what_happens_as_it_happens() {
+    let garden = Garden::start();
+    let events = events::subscribe(&garden.events_tx).unwrap();
+
+    garden
+        .watering_tx
+        .send(WateringServiceMessage::StartWateringAt(
+            NaiveTime::from_hms_opt(20, 30, 0).unwrap(),
+            channel().0,
+        ))
+        .unwrap();
+    garden
+        .watering_tx
+        .send(WateringServiceMessage::EnableSectionFor(
+            SectionId::from("Flowers"),
+            TimeDelta::minutes(3).try_into().unwrap(),
+            channel().0,
+        ))
+        .unwrap();
+    garden.wait_for_section("Flowers", NaiveTime::from_hms_opt(20, 3, 0).unwrap());
+    garden.rtc.advance(TimeDelta::minutes(3));
+
+    let mut received = vec![];
+    while let Ok(event) = events.recv_timeout(Duration::from_secs(1)) {
+        let closed = matches!(event, Event::ValveClosed { .. });
+        received.push(event);
+        if closed {
+            break;
+        }
+    }
+
+    assert_eq!(
+        received,
+        vec![
+            Event::ScheduleChanged {
+                next_program: Some("default".to_string()),
+                at: NaiveTime::from_hms_opt(20, 30, 0),
+            },
+            Event::ValveOpened {
+                section: SectionId::from("Flowers")
+            },
+            Event::SectionAlarmFired,
+            Event::ValveClosed {
+                section: SectionId::from("Flowers")
+            },
+        ]
+    );
+}
+
+#[test]
+fn programs_run_at_their_own_time() {
+    let garden = Garden::start();
+
+    for (name, start, section) in [
+        ("early", (20, 10), SectionId::from("Vegs")),
+        ("late", (20, 30), SectionId::from("Grass")),
+    ] {
+        garden
+            .watering_tx
+            .send(WateringServiceMessage::SetProgram(
+                name.to_string(),
+                Program {
+                    start_at: NaiveTime::from_hms_opt(start.0, start.1, 0),
+                    section_durations: [(section, TimeDelta::minutes(5).try_into().unwrap())]
+                        .into(),
+                    ..Default::default()
+                },
+                channel().0,
+            ))
+            .unwrap();
+    }
+
+    let early = NaiveTime::from_hms_opt(20, 10, 0).unwrap();
+    let late = NaiveTime::from_hms_opt(20, 30, 0).unwrap();
+    assert!(wait_until(Duration::from_secs(1), || garden.rtc.alarm2()
+        == Some(early)));
+
+    garden.rtc.advance(TimeDelta::minutes(10));
+    garden.wait_for_section("Vegs", NaiveTime::from_hms_opt(20, 15, 0).unwrap());
+
+    // Alarm is re-armed for the late program, once the early one is complete
+    garden.rtc.advance(TimeDelta::minutes(5));
+    assert!(wait_until(Duration::from_secs(1), || garden
+        .open_valves()
+        .is_empty()
+        && garden.rtc.alarm2() == Some(late)));
+
+    garden.rtc.advance(TimeDelta::minutes(15));
+    garden.wait_for_section("Grass", NaiveTime::from_hms_opt(20, 35, 0).unwrap());
+
+    garden.rtc.advance(TimeDelta::minutes(5));
+    assert!(wait_until(Duration::from_secs(1), || garden
+        .open_valves()
+        .is_empty()
+        && garden.rtc.alarm2() == Some(early)));
+
+    // Early program is gone, late one is the next
+    let remove_early = || {
+        let (tx, rx) = channel();
+        garden
+            .watering_tx
+            .send(WateringServiceMessage::RemoveProgram(
+                "early".to_string(),
+                tx,
+            ))
+            .unwrap();
+        rx.recv_timeout(Duration::from_secs(1)).unwrap()
+    };
+    assert_eq!(remove_early(), Ok(()));
+    assert!(wait_until(Duration::from_secs(1), || garden.rtc.alarm2() == Some(late)));
+    assert_eq!(
+        remove_early(),
+        Err(WateringError::UnknownProgram("early".to_string()))
+    );
+}
+
+#[test]
+fn sections_are_skipped_on_days_they_are_not_due() {
+    // 2024-05-01 is Wednesday
+    let garden = Garden::start();
+
+    let start = NaiveTime::from_hms_opt(20, 30, 0).unwrap();
+    garden
+        .watering_tx
+        .send(WateringServiceMessage::SetProgram(
+            "mixed".to_string(),
+            Program {
+                start_at: Some(start),
+                section_durations: [
+                    (SectionId::from("Vegs"), 5),
+                    (SectionId::from("Grass"), 10),
+                    (SectionId::from("Terrace"), 8),
+                ]
+                .into_iter()
+                .map(|(section, minutes)| {
+                    (section, TimeDelta::minutes(minutes).try_into().unwrap())
+                })
+                .collect(),
+                section_rules: [
+                    (
+                        SectionId::from("Grass"),
+                        ScheduleRule::Weekdays(vec![Weekday::Thu]),
+                    ),
+                    (
+                        SectionId::from("Terrace"),
+                        ScheduleRule::EveryNDays {
+                            days: NonZeroU32::new(3).unwrap(),
+                            anchor: NaiveDate::from_ymd_opt(2024, 4, 28).unwrap(),
+                        },
+                    ),
+                ]
+                .into(),
+                ..Default::default()
+            },
+            channel().0,
+        ))
+        .unwrap();
+    assert!(wait_until(Duration::from_secs(1), || garden.rtc.alarm2()
+        == Some(start)));
+
+    garden.rtc.advance(TimeDelta::minutes(30));
+    garden.wait_for_section("Vegs", NaiveTime::from_hms_opt(20, 35, 0).unwrap());
+
+    // Grass waits for Thursday
+    garden.rtc.advance(TimeDelta::minutes(5));
+    garden.wait_for_section("Terrace", NaiveTime::from_hms_opt(20, 43, 0).unwrap());
+
+    garden.rtc.advance(TimeDelta::minutes(8));
+    assert!(wait_until(Duration::from_secs(1), || garden
+        .open_valves()
+        .is_empty()
+        && garden.rtc.alarm1().is_none()));
+}
+
+#[test]
+fn schedule_survives_power_cut() {
+    let now = NaiveDate::from_ymd_opt(2024, 5, 1)
+        .unwrap()
+        .and_hms_opt(20, 0, 0)
+        .unwrap();
+    let storage = MemoryStorage::default();
+    let garden = Garden::boot(FakeRtc::new(now), storage.clone(), RecoveryPolicy::Skip);
+
+    garden
+        .watering_tx
+        .send(WateringServiceMessage::SetSectionDuration(
+            SectionId::from("Grass"),
+            TimeDelta::minutes(15).try_into().unwrap(),
+            channel().0,
+        ))
+        .unwrap();
+    let start = NaiveTime::from_hms_opt(20, 30, 0).unwrap();
+    garden
+        .watering_tx
+        .send(WateringServiceMessage::StartWateringAt(start, channel().0))
+        .unwrap();
+    assert!(wait_until(Duration::from_secs(1), || garden.rtc.alarm2()
+        == Some(start)));
+
+    // Power cut - all the services are gone, only RTC keeps the time, alarms are disarmed on boot
+    drop(garden);
+    let garden = Garden::boot(FakeRtc::new(now), storage, RecoveryPolicy::Skip);
+    assert!(wait_until(Duration::from_secs(1), || garden.rtc.alarm2()
+        == Some(start)));
+
+    garden.rtc.advance(TimeDelta::minutes(30));
+    garden.wait_for_section("Grass", NaiveTime::from_hms_opt(20, 45, 0).unwrap());
+}
+
+#[test]
+fn run_cut_short_by_power_cut_is_finished_on_boot() {
+    let at = |hour, minute| {
+        NaiveDate::from_ymd_opt(2024, 5, 1)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+    };
+    let storage = MemoryStorage::default();
+    let garden = Garden::boot(
+        FakeRtc::new(at(20, 0)),
+        storage.clone(),
+        RecoveryPolicy::RunRemaining,
+    );
+
+    for (section, minutes) in [("Vegs", 5), ("Grass", 15)] {
+        garden
+            .watering_tx
+            .send(WateringServiceMessage::SetSectionDuration(
+                SectionId::from(section),
+                TimeDelta::minutes(minutes).try_into().unwrap(),
+                channel().0,
+            ))
+            .unwrap();
+    }
+    let start = NaiveTime::from_hms_opt(20, 30, 0).unwrap();
+    garden
+        .watering_tx
+        .send(WateringServiceMessage::StartWateringAt(start, channel().0))
+        .unwrap();
+    assert!(wait_until(Duration::from_secs(1), || garden.rtc.alarm2()
+        == Some(start)));
+
+    garden.rtc.advance(TimeDelta::minutes(30));
+    garden.wait_for_section("Vegs", NaiveTime::from_hms_opt(20, 35, 0).unwrap());
+    garden.rtc.advance(TimeDelta::minutes(5));
+    garden.wait_for_section("Grass", NaiveTime::from_hms_opt(20, 50, 0).unwrap());
+
+    // Power cut in the middle of Grass, Vegs are not watered again
+    drop(garden);
+    let garden = Garden::boot(
+        FakeRtc::new(at(20, 40)),
+        storage,
+        RecoveryPolicy::RunRemaining,
+    );
+    garden.wait_for_section("Grass", NaiveTime::from_hms_opt(20, 55, 0).unwrap());
+
+    garden.rtc.advance(TimeDelta::minutes(15));
+    assert!(wait_until(Duration::from_secs(1), || garden
+        .open_valves()
+        .is_empty()
+        && garden.rtc.alarm2() == Some(start)));
+}
+
+#[test]
+fn history_tells_what_got_watered_and_why_it_ended() {
+    let garden = Garden::start();
+
+    garden
+        .watering_tx
+        .send(WateringServiceMessage::SetSectionDuration(
+            SectionId::from("Vegs"),
+            TimeDelta::minutes(5).try_into().unwrap(),
+            channel().0,
+        ))
+        .unwrap();
+    let start = NaiveTime::from_hms_opt(20, 30, 0).unwrap();
+    garden
+        .watering_tx
+        .send(WateringServiceMessage::StartWateringAt(start, channel().0))
+        .unwrap();
+    assert!(wait_until(Duration::from_secs(1), || garden.rtc.alarm2()
+        == Some(start)));
+
+    garden.rtc.advance(TimeDelta::minutes(30));
+    garden.wait_for_section("Vegs", NaiveTime::from_hms_opt(20, 35, 0).unwrap());
+    garden.rtc.advance(TimeDelta::minutes(5));
+    assert!(wait_until(Duration::from_secs(1), || garden
+        .open_valves()
+        .is_empty()));
+
+    // Flowers are closed by hand before their time
+    garden
+        .watering_tx
+        .send(WateringServiceMessage::EnableSectionFor(
+            SectionId::from("Flowers"),
+            TimeDelta::minutes(10).try_into().unwrap(),
+            channel().0,
+        ))
+        .unwrap();
+    garden.wait_for_section("Flowers", NaiveTime::from_hms_opt(20, 45, 0).unwrap());
+    garden.rtc.advance(TimeDelta::minutes(3));
+    garden
+        .watering_tx
+        .send(WateringServiceMessage::CloseAllValves)
+        .unwrap();
+
+    let history = |query| {
+        let (tx, rx) = std::sync::mpsc::channel();
+        garden
+            .watering_tx
+            .send(WateringServiceMessage::GetHistory(query, tx))
+            .unwrap();
+        rx.recv_timeout(Duration::from_secs(1))
+            .unwrap()
+            .into_iter()
+            .map(|entry| match entry.event {
+                HistoryEvent::SectionWatered {
+                    section,
+                    program,
+                    duration_seconds,
+                    ended_by,
+                    ..
+                } => (section, program, duration_seconds, ended_by),
+                event => panic!("Nothing got skipped, got {event:?}"),
+            })
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        history(HistoryQuery::default()),
+        vec![
+            (
+                SectionId::from("Vegs"),
+                Some("default".to_string()),
+                5 * 60,
+                EndReason::Alarm
+            ),
+            (SectionId::from("Flowers"), None, 3 * 60, EndReason::Manual),
+        ]
+    );
+    assert_eq!(
+        history(HistoryQuery {
+            since: None,
+            section: Some(SectionId::from("Flowers")),
+        })
+        .len(),
+        1
+    );
+}
+
+#[test]
+fn rain_delay_skips_runs_until_it_is_over() {
+    let garden = Garden::start();
+    garden
+        .watering_tx
+        .send(WateringServiceMessage::SetSectionDuration(
+            SectionId::from("Vegs"),
+            TimeDelta::minutes(5).try_into().unwrap(),
+            channel().0,
+        ))
+        .unwrap();
+    let start = NaiveTime::from_hms_opt(20, 30, 0).unwrap();
+    garden
+        .watering_tx
+        .send(WateringServiceMessage::StartWateringAt(start, channel().0))
+        .unwrap();
+    garden
+        .watering_tx
+        .send(WateringServiceMessage::SetRainDelay(TimeDelta::days(1)))
+        .unwrap();
+
+    let skipped = || {
+        let (tx, rx) = channel();
+        garden
+            .watering_tx
+            .send(WateringServiceMessage::GetHistory(
+                HistoryQuery::default(),
+                tx,
+            ))
+            .unwrap();
+        rx.recv_timeout(Duration::from_secs(1))
+            .unwrap()
+            .into_iter()
+            .filter(|entry| {
+                entry.event
+                    == HistoryEvent::RunSkipped {
+                        program: "default".to_string(),
+                        reason: SkipReason::RainDelay,
+                    }
+            })
+            .count()
+    };
+
+    assert!(wait_until(Duration::from_secs(1), || garden.rtc.alarm2()
+        == Some(start)));
+
+    // Run is skipped, the schedule stays armed
+    garden.rtc.advance(TimeDelta::minutes(30));
+    assert!(wait_until(Duration::from_secs(1), || skipped() == 1));
+    assert!(garden.open_valves().is_empty());
+    assert_eq!(garden.rtc.alarm2(), Some(start));
+
+    // Delay is over before the next day's run
+    garden.rtc.advance(TimeDelta::days(1));
+    garden.wait_for_section("Vegs", NaiveTime::from_hms_opt(20, 35, 0).unwrap());
+    assert_eq!(skipped(), 1);
+}
+
+#[test]
+fn rain_sensor_aborts_the_run_and_skips_runs_while_wet() {
+    let garden = Garden::start();
+    for section in ["Vegs", "Grass"] {
+        garden
+            .watering_tx
+            .send(WateringServiceMessage::SetSectionDuration(
+                SectionId::from(section),
+                TimeDelta::minutes(5).try_into().unwrap(),
+                channel().0,
+            ))
+            .unwrap();
+    }
+    let start = NaiveTime::from_hms_opt(20, 30, 0).unwrap();
+    garden
+        .watering_tx
+        .send(WateringServiceMessage::StartWateringAt(start, channel().0))
+        .unwrap();
+    assert!(wait_until(Duration::from_secs(1), || garden.rtc.alarm2()
+        == Some(start)));
+
+    garden.rtc.advance(TimeDelta::minutes(30));
+    garden.wait_for_section("Vegs", NaiveTime::from_hms_opt(20, 35, 0).unwrap());
+
+    // Grass is not watered either
+    garden.rain.set_wet(true);
+    assert!(wait_until(Duration::from_secs(1), || garden
+        .open_valves()
+        .is_empty()
+        && garden.rtc.alarm1().is_none()));
+
+    let (tx, rx) = channel();
+    garden
+        .watering_tx
+        .send(WateringServiceMessage::GetStatus(tx))
+        .unwrap();
+    let status = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(status.raining, Some(true));
+    assert_eq!(status.current_program, None);
+
+    // Next day it is still wet
+    garden.rtc.advance(TimeDelta::days(1));
+    let history = || {
+        let (tx, rx) = channel();
+        garden
+            .watering_tx
+            .send(WateringServiceMessage::GetHistory(
+                HistoryQuery::default(),
+                tx,
+            ))
+            .unwrap();
+        rx.recv_timeout(Duration::from_secs(1))
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.event)
+            .collect::<Vec<_>>()
+    };
+    assert!(wait_until(Duration::from_secs(1), || history().len() == 2));
+    let history = history();
+    assert!(matches!(
+        history[0],
+        HistoryEvent::SectionWatered {
+            ended_by: EndReason::Rain,
+            ..
+        }
+    ));
+    assert_eq!(
+        history[1],
+        HistoryEvent::RunSkipped {
+            program: "default".to_string(),
+            reason: SkipReason::Rain,
+        }
+    );
+    assert!(garden.open_valves().is_empty());
+
+    // Dried out by the day after
+    garden.rain.set_wet(false);
+    garden.rtc.advance(TimeDelta::days(1));
+    garden.wait_for_section("Vegs", NaiveTime::from_hms_opt(20, 35, 0).unwrap());
+}
+
+#[test]
+fn section_with_moist_soil_is_skipped_by_the_schedule() {
+    let garden = Garden::start();
+    for section in ["Vegs", "Grass"] {
+        garden
+            .watering_tx
+            .send(WateringServiceMessage::SetSectionDuration(
+                SectionId::from(section),
+                TimeDelta::minutes(5).try_into().unwrap(),
+                channel().0,
+            ))
+            .unwrap();
+    }
+    let start = NaiveTime::from_hms_opt(20, 30, 0).unwrap();
+    garden
+        .watering_tx
+        .send(WateringServiceMessage::StartWateringAt(start, channel().0))
+        .unwrap();
+    assert!(wait_until(Duration::from_secs(1), || garden.rtc.alarm2()
+        == Some(start)));
+
+    // Vegs got watered by hand
+    let (_, vegs) = &garden.probes[0];
+    vegs.set_mv(1250);
+    garden.rtc.advance(TimeDelta::minutes(30));
+    garden.wait_for_section("Grass", NaiveTime::from_hms_opt(20, 35, 0).unwrap());
+
+    let (tx, rx) = channel();
+    garden
+        .watering_tx
+        .send(WateringServiceMessage::GetHistory(
+            HistoryQuery::default(),
+            tx,
+        ))
+        .unwrap();
+    let history = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(
+        history[0].event,
+        HistoryEvent::SectionSkipped {
+            section: SectionId::from("Vegs"),
+            program: "default".to_string(),
+            moisture: 90,
+        }
+    );
+
+    let (tx, rx) = channel();
+    garden
+        .watering_tx
+        .send(WateringServiceMessage::GetStatus(tx))
+        .unwrap();
+    let status = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(status.soil_moisture[&SectionId::from("Vegs")].moisture, 90);
+    assert_eq!(status.soil_moisture[&SectionId::from("Grass")].moisture, 0);
+}
+
+#[test]
+fn home_assistant_controls_sections_over_mqtt() {
+    let garden = Garden::start();
+    let broker = FakeBroker::new();
+    let sections = SectionsConfig::from_json(include_str!("../sections.json")).unwrap();
+    MqttService::new(
+        broker.client(),
+        "garden",
+        sections,
+        garden.watering_tx.clone(),
+        garden.clock_tx.clone(),
+    )
+    .with_events(garden.events_tx.clone())
+    .start();
+
+    let retained = |topic: &str, payload: &str| {
+        wait_until(Duration::from_secs(1), || {
+            broker.retained(topic).as_deref() == Some(payload)
+        })
+    };
+
+    // Every section is discovered as a switch
+    assert!(wait_until(Duration::from_secs(1), || broker
+        .retained_topics("homeassistant/switch/garden/+/config")
+        .len()
+        == 5));
+    let config: serde_json::Value = serde_json::from_str(
+        &broker
+            .retained("homeassistant/switch/garden/section_Vegs/config")
+            .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(config["name"], "Vegetables");
+    assert_eq!(config["command_topic"], "garden/section/Vegs/set");
+    assert_eq!(config["availability_topic"], "garden/availability");
+    assert!(broker
+        .retained("homeassistant/sensor/garden/temperature/config")
+        .is_some());
+    assert!(retained("garden/availability", "online"));
+    assert!(retained("garden/temperature", "21.5"));
+    assert!(retained("garden/next_run", "None"));
+
+    broker.publish("garden/section/Flowers/set", b"ON");
+    garden.wait_for_section("Flowers", NaiveTime::from_hms_opt(20, 10, 0).unwrap());
+    assert!(retained("garden/section/Flowers/state", "ON"));
+    assert!(retained("garden/section/Vegs/state", "OFF"));
+
+    broker.publish("garden/section/Flowers/set", b"OFF");
+    assert!(wait_until(Duration::from_secs(1), || garden
+        .open_valves()
+        .is_empty()));
+    assert!(retained("garden/section/Flowers/state", "OFF"));
+
+    garden
+        .watering_tx
+        .send(WateringServiceMessage::StartWateringAt(
+            NaiveTime::from_hms_opt(20, 30, 0).unwrap(),
+            channel().0,
+        ))
+        .unwrap();
+    assert!(retained("garden/watering/state", "ON"));
+    assert!(retained("garden/next_run", "2024-05-01 20:30 default"));
+
+    broker.publish("garden/watering/set", b"OFF");
+    assert!(retained("garden/watering/state", "OFF"));
+    assert!(wait_until(Duration::from_secs(1), || garden
+        .rtc
+        .alarm2()
+        .is_none()));
+
+    // Broker forgot everything, the entities are announced again
+    broker.restart();
+    assert!(wait_until(Duration::from_secs(1), || broker
+        .retained_topics("homeassistant/#")
+        .len()
+        == 8));
+    broker.publish("garden/watering/set", b"ON");
+    assert!(retained("garden/watering/state", "ON"));
+}